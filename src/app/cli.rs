@@ -0,0 +1,608 @@
+use super::super::otter;
+use super::super::util;
+use clap::{App, Arg, ArgMatches};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+
+// instructions in the profile's table of the hottest, and branches in the
+// predictor's table of the most mispredicted
+const PROFILE_TOP: usize = 20;
+
+// arguments for running without the GUI
+pub fn run_command<'a>() -> App<'a> {
+    App::new("run")
+        .about("Run a binary headless, printing program output to stdout")
+        .arg(
+            Arg::new("binary")
+                .about("RV32I binary or ELF to run")
+                .required_unless_present("restore")
+                .index(1),
+        )
+        .arg(
+            Arg::new("restore")
+                .long("restore")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("binary")
+                .about("Resume from a snapshot instead of loading a binary"),
+        )
+        .arg(
+            Arg::new("snapshot")
+                .long("snapshot")
+                .takes_value(true)
+                .value_name("FILE")
+                .about("Save a snapshot of the machine to FILE when it stops"),
+        )
+        .arg(
+            Arg::new("semihosting")
+                .long("semihosting")
+                .short('s')
+                .about("Service newlib-style ecalls (read, write, exit, brk)"),
+        )
+        .arg(
+            Arg::new("input")
+                .long("input")
+                .short('i')
+                .takes_value(true)
+                .value_name("FILE")
+                .about("Feed semihosted reads from FILE instead of stdin"),
+        )
+        .arg(
+            Arg::new("halt-addr")
+                .long("halt-addr")
+                .takes_value(true)
+                .value_name("ADDR")
+                .about("Stop when the PC reaches ADDR"),
+        )
+        .arg(
+            Arg::new("exit-addr")
+                .long("exit-addr")
+                .takes_value(true)
+                .value_name("ADDR")
+                .about("Stop when the program writes to the MMIO register at ADDR"),
+        )
+        .arg(
+            Arg::new("max-instrs")
+                .long("max-instrs")
+                .takes_value(true)
+                .value_name("N")
+                .about("Stop after executing N instructions"),
+        )
+        .arg(
+            Arg::new("no-self-loop")
+                .long("no-self-loop")
+                .about("Keep running on a jump to self"),
+        )
+        .arg(
+            Arg::new("on-trap")
+                .long("on-trap")
+                .takes_value(true)
+                .value_name("ACTION")
+                .possible_values(&["halt", "skip"])
+                .default_value("halt")
+                .about("Without a trap handler, halt on an exception or skip the instruction"),
+        )
+        .arg(
+            Arg::new("break")
+                .long("break")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("SPEC")
+                .about("Add a breakpoint: LOCATION [ignore N] [if COND] [log MSG]"),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("ADDR|BEGIN:END")
+                .about(
+                    "Stop when the program reads or writes the word at ADDR, or bytes in BEGIN:END",
+                ),
+        )
+        .arg(
+            Arg::new("watch-read")
+                .long("watch-read")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("ADDR|BEGIN:END")
+                .about("Stop when the program reads these addresses"),
+        )
+        .arg(
+            Arg::new("watch-write")
+                .long("watch-write")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .value_name("ADDR|BEGIN:END")
+                .about("Stop when the program writes these addresses"),
+        )
+        .arg(
+            Arg::new("stack-check")
+                .long("stack-check")
+                .takes_value(true)
+                .value_name("ACTION")
+                .possible_values(&["warn", "stop"])
+                .about("Warn of, or stop on, sp leaving the stack and stores to the text section"),
+        )
+        .arg(
+            Arg::new("stack")
+                .long("stack")
+                .takes_value(true)
+                .value_name("BOTTOM:TOP")
+                .requires("stack-check")
+                .about("The stack's bounds, instead of the ELF's or the memory above the program"),
+        )
+        .arg(
+            Arg::new("text")
+                .long("text")
+                .takes_value(true)
+                .value_name("BEGIN:END")
+                .about("Write-protect the text section, and check the stack against it"),
+        )
+        .arg(
+            Arg::new("track-undefined")
+                .long("track-undefined")
+                .about("Report undefined values used in branches, addresses and IO writes"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .about("Report the hottest instructions, functions, operations and branches"),
+        )
+        .arg(
+            Arg::new("profile-stacks")
+                .long("profile-stacks")
+                .takes_value(true)
+                .value_name("FILE")
+                .about("Write the profile's call stacks to FILE for flamegraph tools"),
+        )
+        .arg(
+            Arg::new("icache")
+                .long("icache")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .about("Model an instruction cache, e.g. size=1024,line=16,ways=2,replace=lru,penalty=10"),
+        )
+        .arg(
+            Arg::new("dcache")
+                .long("dcache")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .about("Model a data cache, configured as --icache with write=back|through"),
+        )
+        .arg(
+            Arg::new("predict")
+                .long("predict")
+                .takes_value(true)
+                .value_name("CONFIG")
+                .about("Model a branch predictor: not-taken, 1bit, 2bit or gshare, then e.g. table=1024,btb=64,penalty=2"),
+        )
+        .arg(
+            Arg::new("uart-addr")
+                .long("uart-addr")
+                .takes_value(true)
+                .value_name("ADDR")
+                .about("Move the UART from 0x11100000"),
+        )
+        .arg(
+            Arg::new("timer-addr")
+                .long("timer-addr")
+                .takes_value(true)
+                .value_name("ADDR")
+                .about("Move the timer from 0x11140000"),
+        )
+        .arg(
+            Arg::new("no-protect")
+                .long("no-protect")
+                .about("Let the program write its code and execute data"),
+        )
+        .arg(
+            Arg::new("trace")
+                .long("trace")
+                .takes_value(true)
+                .value_name("FILE")
+                .about("Write a trace of every executed instruction to FILE"),
+        )
+        .arg(
+            Arg::new("trace-format")
+                .long("trace-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "json", "spike"])
+                .default_value("text")
+                .about("Trace as readable text, JSON lines or a Spike commit log"),
+        )
+        .arg(
+            Arg::new("vcd")
+                .long("vcd")
+                .takes_value(true)
+                .value_name("FILE")
+                .about("Write a waveform of the PC, IR, registers and IO to FILE"),
+        )
+        .arg(
+            Arg::new("vcd-bus")
+                .long("vcd-bus")
+                .requires("vcd")
+                .about("Include the memory bus in the waveform"),
+        )
+        .arg(
+            Arg::new("trace-range")
+                .long("trace-range")
+                .takes_value(true)
+                .value_name("BEGIN:END")
+                .about("Only trace instructions with BEGIN <= PC < END"),
+        )
+        .arg(
+            Arg::new("trace-class")
+                .long("trace-class")
+                .takes_value(true)
+                .value_name("CLASSES")
+                .about("Only trace these classes: alu, load, store, branch, jump, system"),
+        )
+}
+
+// arguments for running riscv-tests programs
+pub fn riscv_tests_command<'a>() -> App<'a> {
+    App::new("riscv-tests")
+        .about("Run riscv-tests ELFs, reporting the first failing test of each")
+        .arg(
+            Arg::new("tests")
+                .about("Test ELFs, or directories of them")
+                .multiple(true)
+                .default_value(otter::riscv_tests::RV32UI_DIR)
+                .index(1),
+        )
+        .arg(
+            Arg::new("tohost")
+                .long("tohost")
+                .takes_value(true)
+                .value_name("SYMBOL|ADDR")
+                .default_value("tohost")
+                .about("Symbol or address the tests report their result to"),
+        )
+}
+
+// arguments for checking against an RTL trace
+pub fn cosim_command<'a>() -> App<'a> {
+    App::new("cosim")
+        .about(
+            "Run a binary in lockstep with an RTL simulation trace, stopping at the first mismatch",
+        )
+        .arg(
+            Arg::new("binary")
+                .about("RV32I binary the RTL ran")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::new("trace")
+                .about("Trace of PCs, register writes and memory writes, one instruction per line")
+                .required(true)
+                .index(2),
+        )
+}
+
+// exit on a bad argument
+fn fail_arg(name: &str, why: &str) -> ! {
+    eprintln!("Error: Invalid value for --{}: {}.", name, why);
+    process::exit(1);
+}
+
+// parse an optional numeric argument, exiting on bad input
+fn int_arg(matches: &ArgMatches, name: &str) -> Option<u32> {
+    let s = matches.value_of(name)?;
+    match util::parse::parse_int(s) {
+        Ok(n) => Some(n),
+        Err(why) => fail_arg(name, &format!("{}: {}", s, why)),
+    }
+}
+
+// build a tracer from the --trace arguments, if tracing was asked for
+fn tracer(matches: &ArgMatches) -> Option<otter::trace::Tracer> {
+    let path = matches.value_of("trace")?;
+    let format =
+        otter::trace::Format::from_name(matches.value_of("trace-format").unwrap()).unwrap();
+    let mut filter = otter::trace::Filter::default();
+    if let Some(s) = matches.value_of("trace-range") {
+        filter.range = Some(
+            otter::trace::Filter::parse_range(s).unwrap_or_else(|e| fail_arg("trace-range", &e)),
+        );
+    }
+    if let Some(s) = matches.value_of("trace-class") {
+        filter.classes =
+            otter::trace::Filter::parse_classes(s).unwrap_or_else(|e| fail_arg("trace-class", &e));
+    }
+    match otter::trace::Tracer::create(path, format, filter) {
+        Ok(t) => Some(t),
+        Err(why) => {
+            eprintln!("Error: Could not open file {}: {}.", path, why);
+            process::exit(1);
+        }
+    }
+}
+
+// print anything the program wrote to the console
+fn flush_console(mcu: &mut otter::MCU) {
+    let out = mcu.take_console();
+    if !out.is_empty() {
+        let mut stdout = io::stdout();
+        stdout.write_all(&out).unwrap();
+        stdout.flush().unwrap();
+    }
+}
+
+// read stdin on another thread, so the program keeps running while it
+// waits for input
+fn stdin_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 256];
+        while let Ok(n) = io::stdin().read(&mut buf) {
+            if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// load an ELF, with its symbols, or else a raw binary
+fn load_program(mcu: &mut otter::MCU, path: &str) {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(why) => {
+            eprintln!("Error: Could not open file {}: {}.", path, why);
+            process::exit(1);
+        }
+    };
+    if !util::elf::is_elf(&bytes) {
+        mcu.load_bin(path);
+    } else if let Err(why) = util::elf::parse(&bytes).and_then(|elf| mcu.load_elf(&elf)) {
+        eprintln!("Error: Could not load {}: {}.", path, why);
+        process::exit(1);
+    }
+}
+
+// run until the MCU stops, then exit with the program's status
+pub fn run(matches: &ArgMatches) {
+    let mut mcu = otter::MCU::new();
+    match matches.value_of("restore") {
+        Some(path) => {
+            if let Err(why) = otter::snapshot::load(path).and_then(|snap| mcu.restore(&snap)) {
+                eprintln!("Error: Could not restore {}.", why);
+                process::exit(1);
+            }
+        }
+        None => load_program(&mut mcu, matches.value_of("binary").unwrap()),
+    }
+
+    if let Some(addr) = int_arg(matches, "uart-addr") {
+        mcu.set_uart_addr(addr);
+    }
+    if let Some(addr) = int_arg(matches, "timer-addr") {
+        mcu.set_timer_addr(addr);
+    }
+    // the UART receives stdin, unless semihosted reads take it
+    let semihosted_stdin = matches.is_present("semihosting") && !matches.is_present("input");
+    let uart_rx = if semihosted_stdin {
+        None
+    } else {
+        Some(stdin_reader())
+    };
+    if matches.is_present("semihosting") {
+        let input: Box<dyn Read + Send> = match matches.value_of("input") {
+            None => Box::new(io::stdin()),
+            Some(path) => match File::open(path) {
+                Err(why) => {
+                    eprintln!("Error: Could not open file {}: {}.", path, why);
+                    process::exit(1);
+                }
+                Ok(f) => Box::new(f),
+            },
+        };
+        mcu.enable_semihosting(Some(input));
+    }
+
+    mcu.set_halt_config(otter::HaltConfig {
+        self_loop: !matches.is_present("no-self-loop"),
+        addr: int_arg(matches, "halt-addr"),
+        exit_addr: int_arg(matches, "exit-addr"),
+        budget: int_arg(matches, "max-instrs").map(|n| n as u64),
+        tohost: None,
+    });
+    if matches.value_of("on-trap") == Some("skip") {
+        mcu.set_trap_fallback(otter::trap::TrapFallback::Skip);
+    }
+    mcu.set_tracer(tracer(matches));
+    for (name, kind) in [
+        ("watch", otter::WatchKind::Access),
+        ("watch-read", otter::WatchKind::Read),
+        ("watch-write", otter::WatchKind::Write),
+    ]
+    .iter()
+    {
+        for spec in matches.values_of(name).into_iter().flatten() {
+            match otter::Watchpoint::parse(*kind, spec) {
+                Ok(w) => mcu.add_watchpoint(w),
+                Err(why) => fail_arg(name, &why),
+            }
+        }
+    }
+    let text = matches
+        .value_of("text")
+        .map(|s| otter::trace::Filter::parse_range(s).unwrap_or_else(|e| fail_arg("text", &e)));
+    if let Some((begin, end)) = text {
+        mcu.protect_text(begin, end);
+    }
+    if matches.is_present("no-protect") {
+        mcu.set_protection(false);
+    }
+    if matches.is_present("track-undefined") {
+        mcu.set_definedness(true);
+    }
+    let cache = |name| {
+        matches
+            .value_of(name)
+            .map(|s| otter::CacheConfig::parse(s).unwrap_or_else(|e| fail_arg(name, &e)))
+    };
+    mcu.set_caches(cache("icache"), cache("dcache"));
+    let predictor = matches
+        .value_of("predict")
+        .map(|s| otter::PredictorConfig::parse(s).unwrap_or_else(|e| fail_arg("predict", &e)));
+    mcu.set_predictor(predictor);
+    if matches.is_present("profile") || matches.is_present("profile-stacks") {
+        mcu.set_profiling(true);
+    }
+    if let Some(action) = matches.value_of("stack-check") {
+        let mut guard = mcu.stack_layout();
+        guard.stop = action == "stop";
+        if let Some(s) = matches.value_of("stack") {
+            let (bottom, top) =
+                otter::trace::Filter::parse_range(s).unwrap_or_else(|e| fail_arg("stack", &e));
+            guard.stack_bottom = bottom;
+            guard.stack_top = top;
+        }
+        if let Some((begin, end)) = text {
+            guard.text_begin = begin;
+            guard.text_end = end;
+        }
+        mcu.set_stack_guard(Some(guard));
+    }
+    for spec in matches.values_of("break").into_iter().flatten() {
+        if let Err(why) = mcu.add_breakpoint(spec) {
+            fail_arg("break", &why);
+        }
+    }
+    if let Some(path) = matches.value_of("vcd") {
+        match otter::vcd::Vcd::create(path, matches.is_present("vcd-bus")) {
+            Ok(vcd) => mcu.set_vcd(Some(vcd)),
+            Err(why) => {
+                eprintln!("Error: Could not open file {}: {}.", path, why);
+                process::exit(1);
+            }
+        }
+    }
+
+    let stop = loop {
+        for data in uart_rx.iter().flat_map(|rx| rx.try_iter()) {
+            mcu.uart_receive(&data);
+        }
+        let stop = mcu.run_until(Some(1), |s| eprintln!("{}", s));
+        flush_console(&mut mcu);
+        if let Some(reason) = stop {
+            break reason;
+        }
+    };
+
+    eprintln!(
+        "Stopped: {} after {} instructions.",
+        stop,
+        mcu.instr_count()
+    );
+    if let otter::StopReason::Trap(..) = stop {
+        eprintln!("Backtrace:");
+        for frame in mcu.backtrace() {
+            eprintln!("    {}", frame);
+        }
+    }
+    let cache_report = mcu.cache_report();
+    let predictor_report = mcu.predictor_report(PROFILE_TOP);
+    for report in cache_report.iter().chain(predictor_report.iter()) {
+        eprint!("{}", report);
+    }
+    if cache_report.is_some() || predictor_report.is_some() {
+        eprintln!("{} cycles.", mcu.cycle_count());
+    }
+    if matches.is_present("profile") {
+        eprint!("{}", mcu.profile_report(PROFILE_TOP).unwrap());
+    }
+    if let Some(path) = matches.value_of("profile-stacks") {
+        if let Err(why) = fs::write(path, mcu.profile_stacks().unwrap()) {
+            eprintln!("Error: Could not write file {}: {}.", path, why);
+        }
+    }
+    if let Some(path) = matches.value_of("snapshot") {
+        match otter::snapshot::save(path, &mcu.snapshot()) {
+            Ok(()) => eprintln!("Saved a snapshot to {}.", path),
+            Err(why) => eprintln!("Error: Could not save snapshot {}.", why),
+        }
+    }
+    // flushes the trace and waveform; exiting skips destructors
+    mcu.set_tracer(None);
+    mcu.set_vcd(None);
+    process::exit(match stop {
+        otter::StopReason::Exit(code) | otter::StopReason::ExitWrite(code) => code as i32,
+        otter::StopReason::SelfLoop(_)
+        | otter::StopReason::HaltAddress(_)
+        | otter::StopReason::Breakpoint(..) => 0,
+        _ => 1,
+    });
+}
+
+// run each test, then exit with 1 if any did not pass
+pub fn riscv_tests(matches: &ArgMatches) {
+    let paths: Vec<&str> = matches.values_of("tests").unwrap().collect();
+    let tohost = matches.value_of("tohost").unwrap();
+    let tests = match otter::riscv_tests::collect(&paths) {
+        Err(why) => {
+            eprintln!("Error: {}.", why);
+            process::exit(1);
+        }
+        Ok(tests) => tests,
+    };
+
+    let mut failed = 0;
+    for test in tests.iter() {
+        let name = test.file_name().unwrap().to_string_lossy();
+        match otter::riscv_tests::run(test.to_str().unwrap(), tohost) {
+            Ok(stop) if otter::riscv_tests::passed(&stop) => println!("PASS {}", name),
+            Ok(stop) => {
+                println!("FAIL {}: {}", name, stop);
+                failed += 1;
+            }
+            Err(why) => {
+                println!("FAIL {}: {}", name, why);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} of {} passed.", tests.len() - failed, tests.len());
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// check a binary against a trace, then exit with 1 on a mismatch
+pub fn cosim(matches: &ArgMatches) {
+    let path = matches.value_of("trace").unwrap();
+    let trace = match otter::cosim::read(path) {
+        Ok(trace) => trace,
+        Err(why) => {
+            eprintln!("Error: Could not read trace {}.", why);
+            process::exit(1);
+        }
+    };
+
+    let mut mcu = otter::MCU::new();
+    mcu.load_bin(matches.value_of("binary").unwrap());
+    // the hardware keeps running where the emulator would stop, but not
+    // past two instructions for each in the trace, as check() skips at most
+    // one that traps for each
+    mcu.set_halt_config(otter::HaltConfig {
+        self_loop: false,
+        budget: Some(2 * trace.len() as u64),
+        ..otter::HaltConfig::default()
+    });
+
+    match otter::cosim::check(&mut mcu, &trace) {
+        Ok(n) => println!("Matched all {} instructions.", n),
+        Err(report) => {
+            print!("{}", report);
+            process::exit(1);
+        }
+    }
+}
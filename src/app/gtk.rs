@@ -3,17 +3,24 @@ extern crate glib;
 extern crate gtk;
 extern crate webbrowser;
 use super::super::otter;
+use super::super::otter::trap::TrapFallback;
 use super::super::util;
 use gtk::prelude::*;
+use std::io;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 const GUI_REFRESH_PERIOD: usize = 1;
 const IR_PERIOD_US: u64 = 2000;
+// instructions that can be stepped back over
+const HISTORY_DEPTH: usize = 100_000;
+// instructions in the profile's table of the hottest
+const PROFILE_TOP: usize = 20;
 
 struct GUIMessage {
     console_msg: String,
+    console_out: String,
     update_leds: bool,
     update_pc: bool,
     new_leds: Vec<bool>,
@@ -24,6 +31,8 @@ struct GUIMessage {
     new_pc: u32,
     update_ir: bool,
     new_ir: otter::Instruction,
+    // the call stack, one frame per line
+    new_stack: Option<String>,
 }
 
 // contains all the data needed to update the GUI
@@ -38,6 +47,7 @@ impl GUIMessage {
     ) -> GUIMessage {
         let mut msg = GUIMessage {
             console_msg: String::from(""),
+            console_out: String::from(""),
             new_sseg: 0,
             new_pc: 0,
             new_leds: vec![false; 16],
@@ -54,6 +64,7 @@ impl GUIMessage {
             update_sseg: false,
             update_rf: false,
             update_ir: false,
+            new_stack: None,
         };
 
         if let Some(s) = print {
@@ -99,6 +110,140 @@ impl GUIMessage {
         ))
         .unwrap();
     }
+
+    // forward program output to the console as-is, without a line break
+    fn print_console(tx: &glib::Sender<GUIMessage>, mcu: &mut otter::MCU) {
+        let out = mcu.take_console();
+        if out.is_empty() {
+            return;
+        }
+        let mut msg = GUIMessage::gui_update(None, None, None, None, None, None);
+        msg.console_out = String::from_utf8_lossy(&out).into_owned();
+        tx.send(msg).unwrap();
+    }
+
+    fn show_stack(tx: &glib::Sender<GUIMessage>, mcu: &otter::MCU) {
+        let mut msg = GUIMessage::gui_update(None, None, None, None, None, None);
+        msg.new_stack = Some(mcu.backtrace().join("\n"));
+        tx.send(msg).unwrap();
+    }
+}
+
+// why the MCU stopped, with a backtrace for an unhandled exception
+fn stop_message(reason: &otter::StopReason, mcu: &otter::MCU) -> String {
+    let mut msg = format!("Stopped: {}.", reason);
+    if let otter::StopReason::Trap(..) = reason {
+        msg.push_str("\nBacktrace:");
+        for frame in mcu.backtrace() {
+            msg.push_str(&format!("\n    {}", frame));
+        }
+    }
+    msg
+}
+
+// show a watchpoint in the breakpoints window
+fn add_bp_row(list: &gtk::ListBox, text: &str) {
+    let row = gtk::ListBoxRow::new();
+    let label = gtk::Label::new(Some(text));
+    let container = gtk::Box::new(gtk::Orientation::Horizontal, 20);
+    container.add(&label);
+    container.pack_start(&label, true, true, 10);
+    row.add(&container);
+    list.add(&row);
+    list.show_all();
+}
+
+// list the MCU's breakpoints and watchpoints in the breakpoints window;
+// each breakpoint can be enabled, disabled and removed from its row
+fn show_breakpoints(list: &gtk::ListBox, mcu: &otter::MCU, mcu_mutex: &Arc<Mutex<otter::MCU>>) {
+    list.foreach(|w| list.remove(w));
+    for e in mcu.breakpoints() {
+        let id = e.id;
+        let row = gtk::ListBoxRow::new();
+        let container = gtk::Box::new(gtk::Orientation::Horizontal, 20);
+        let enabled = gtk::CheckButton::with_label(&format!("{}: {}", id, e.bp));
+        enabled.set_active(e.enabled);
+        let mcu = mcu_mutex.clone();
+        enabled.connect_toggled(move |b| {
+            mcu.lock().unwrap().enable_breakpoint(id, b.get_active());
+        });
+        let remove = gtk::Button::with_label("Remove");
+        let mcu = mcu_mutex.clone();
+        // weak, as the row owns the button
+        let (list_weak, row_weak) = (list.downgrade(), row.downgrade());
+        remove.connect_clicked(move |_| {
+            mcu.lock().unwrap().remove_breakpoint(id);
+            if let (Some(list), Some(row)) = (list_weak.upgrade(), row_weak.upgrade()) {
+                list.remove(&row);
+            }
+        });
+        container.pack_start(&enabled, true, true, 10);
+        container.pack_start(&remove, false, false, 10);
+        row.add(&container);
+        list.add(&row);
+    }
+    for w in mcu.watchpoints() {
+        add_bp_row(list, &w.to_string());
+    }
+    list.show_all();
+}
+
+// run the MCU in a new thread until it stops, reaches 'goal' or is paused
+fn run_in_background(
+    mcu: Arc<Mutex<otter::MCU>>,
+    running: Arc<Mutex<bool>>,
+    tx: glib::Sender<GUIMessage>,
+    mut goal: Option<otter::Goal>,
+) {
+    thread::spawn(move || {
+        let mut c: usize = 0;
+        *running.lock().unwrap() = true;
+        let mut local_running = true;
+        // do while still running (wait for pause btn)
+        while local_running {
+            let mut mcu = mcu.lock().unwrap();
+            let tx_logger = tx.clone();
+            // breakpoints are checked before each instruction
+            let logger = move |s: &str| GUIMessage::log_console(&tx_logger, s);
+            let stop = match &mut goal {
+                None => mcu.run_until(Some(1), logger),
+                Some(goal) => mcu.run_goal(goal, Some(1), logger),
+            };
+            GUIMessage::print_console(&tx, &mut mcu);
+            if let Some(reason) = stop {
+                *running.lock().unwrap() = false;
+                local_running = false;
+                tx.send(GUIMessage::gui_update(
+                    Some(&stop_message(&reason, &mcu)),
+                    Some(mcu.leds()),
+                    Some(mcu.sseg()),
+                    Some(mcu.rf()),
+                    Some(mcu.pc),
+                    Some(mcu.fetch(|_s| {}).0),
+                ))
+                .unwrap();
+                GUIMessage::show_stack(&tx, &mcu);
+            } else {
+                local_running = *running.lock().unwrap();
+            }
+            // refresh GUI
+            thread::sleep(Duration::from_micros(IR_PERIOD_US));
+            c += 1;
+            if c == GUI_REFRESH_PERIOD {
+                // reset count, lock mcu, read, send message
+                c = 0;
+                tx.send(GUIMessage::gui_update(
+                    None,
+                    Some(mcu.leds()),
+                    Some(mcu.sseg()),
+                    Some(mcu.rf()),
+                    Some(mcu.pc),
+                    None,
+                ))
+                .unwrap();
+            }
+        }
+    });
 }
 
 pub fn build_gui(application: &gtk::Application) {
@@ -108,10 +253,12 @@ pub fn build_gui(application: &gtk::Application) {
     }
 
     // mutexs for shared memory
-    let mcu_mutex = Arc::from(Mutex::from(otter::MCU::new()));
+    let mut mcu = otter::MCU::new();
+    mcu.set_history_depth(HISTORY_DEPTH);
+    mcu.set_profiling(true);
+    let mcu_mutex = Arc::from(Mutex::from(mcu));
     let running_mutex = Arc::from(Mutex::from(false));
     let programmed_mutex = Arc::from(Mutex::from(false));
-    let bps_mutex: Arc<Mutex<Vec<u32>>> = Arc::from(Mutex::from(Vec::new()));
 
     // load in glade source
     let glade_src = include_str!("../../res/gui/gtk.ui");
@@ -125,9 +272,16 @@ pub fn build_gui(application: &gtk::Application) {
     // control buttons
     let run_btn: gtk::Button = builder.get_object("run_btn").unwrap();
     let step_btn: gtk::Button = builder.get_object("step_btn").unwrap();
+    let step_back_btn: gtk::Button = builder.get_object("step_back_btn").unwrap();
+    let reverse_btn: gtk::Button = builder.get_object("reverse_btn").unwrap();
     let pause_btn: gtk::Button = builder.get_object("pause_btn").unwrap();
     let reset_btn: gtk::Button = builder.get_object("reset_btn").unwrap();
     let mem_rd_btn: gtk::Button = builder.get_object("read_mem_btn").unwrap();
+    let last_write_btn: gtk::Button = builder.get_object("last_write_btn").unwrap();
+    let step_over_btn: gtk::Button = builder.get_object("step_over_btn").unwrap();
+    let step_out_btn: gtk::Button = builder.get_object("step_out_btn").unwrap();
+    let run_to_btn: gtk::Button = builder.get_object("run_to_btn").unwrap();
+    let mem_addr_entry: gtk::Entry = builder.get_object("mem_addr_entry").unwrap();
 
     // menu items
     let load_bin_btn: gtk::Button = builder.get_object("load_binary_btn").unwrap();
@@ -154,6 +308,9 @@ pub fn build_gui(application: &gtk::Application) {
     // pc
     let pc_buffer: gtk::TextBuffer = builder.get_object("pc_buffer").unwrap();
 
+    // call stack
+    let stack_buffer: gtk::TextBuffer = builder.get_object("stack_buffer").unwrap();
+
     // ir buffers
     let ir_type_buffer: gtk::TextBuffer = builder.get_object("ir_type_buffer").unwrap();
     let ir_rd_buffer: gtk::TextBuffer = builder.get_object("ir_rd_buffer").unwrap();
@@ -174,6 +331,12 @@ pub fn build_gui(application: &gtk::Application) {
             let adj = console_container.get_vadjustment().unwrap();
             adj.set_value(adj.get_upper() - adj.get_page_size());
         }
+        // program output
+        if !message.console_out.is_empty() {
+            console_buffer.insert_at_cursor(&message.console_out);
+            let adj = console_container.get_vadjustment().unwrap();
+            adj.set_value(adj.get_upper() - adj.get_page_size());
+        }
         // udpate the SSEG
         if message.update_sseg {
             sseg.set_text(&format!("{:#06X}", message.new_sseg));
@@ -213,6 +376,10 @@ pub fn build_gui(application: &gtk::Application) {
             ir_rs2_buffer.set_text(&format!(" x{} ({}) ", rs2, otter::reg_name(rs2)));
             ir_imm_buffer.set_text(&format!(" {:#010X} ", message.new_ir.imm));
         };
+        // update the call stack
+        if let Some(stack) = &message.new_stack {
+            stack_buffer.set_text(stack);
+        }
         // continue
         glib::Continue(true)
     });
@@ -248,6 +415,7 @@ pub fn build_gui(application: &gtk::Application) {
         mcu.load_bin(&path);
         *programmed.lock().unwrap() = true;
         GUIMessage::log_console(&tx, &format!("Programmed with {}.", path));
+        GUIMessage::show_stack(&tx, &mcu);
     });
 
     // CONSOLE BUTTON
@@ -260,6 +428,144 @@ pub fn build_gui(application: &gtk::Application) {
         console.grab_focus();
     });
 
+    // UART INPUT
+    let uart_entry: gtk::Entry = builder.get_object("uart_entry").unwrap();
+    let mcu = mcu_mutex.clone();
+    uart_entry.connect_activate(move |entry| {
+        let line = format!("{}\n", entry.get_text());
+        mcu.lock().unwrap().uart_receive(line.as_bytes());
+        entry.set_text("");
+    });
+
+    // CALL STACK BUTTON
+    let stack_btn: gtk::Button = builder.get_object("stack_btn").unwrap();
+    let builder_clone = builder.clone();
+    stack_btn.connect_clicked(move |_| {
+        let stack: gtk::Window = builder_clone.get_object("stack_window").unwrap();
+        stack.set_default_size(400, 300);
+        stack.show_all();
+        stack.grab_focus();
+    });
+
+    // PROFILE BUTTON
+    let profile_btn: gtk::Button = builder.get_object("profile_btn").unwrap();
+    let builder_clone = builder.clone();
+    let mcu = mcu_mutex.clone();
+    profile_btn.connect_clicked(move |_| {
+        let buffer: gtk::TextBuffer = builder_clone.get_object("profile_buffer").unwrap();
+        let profile = mcu.lock().unwrap().profile_report(PROFILE_TOP).unwrap();
+        buffer.set_text(&profile);
+        let window: gtk::Window = builder_clone.get_object("profile_window").unwrap();
+        window.set_default_size(600, 600);
+        window.show_all();
+        window.grab_focus();
+    });
+
+    // SEMIHOSTING TOGGLE
+    let semihosting_check: gtk::CheckButton = builder.get_object("semihosting_check").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    semihosting_check.connect_toggled(move |check| {
+        let mut mcu = mcu.lock().unwrap();
+        if check.get_active() {
+            mcu.enable_semihosting(Some(Box::new(io::stdin())));
+            GUIMessage::log_console(&tx, "Semihosting enabled, reading from stdin.");
+        } else {
+            mcu.disable_semihosting();
+            GUIMessage::log_console(&tx, "Semihosting disabled.");
+        }
+    });
+
+    // TRACE TOGGLE
+    let trace_check: gtk::CheckButton = builder.get_object("trace_check").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    trace_check.connect_toggled(move |check| {
+        let mut mcu = mcu.lock().unwrap();
+        if !check.get_active() {
+            mcu.set_tracer(None);
+            GUIMessage::log_console(&tx, "Tracing stopped.");
+            return;
+        }
+        match otter::trace::Tracer::create(
+            "oemu.trace",
+            otter::trace::Format::Text,
+            otter::trace::Filter::default(),
+        ) {
+            Ok(t) => {
+                mcu.set_tracer(Some(t));
+                GUIMessage::log_console(&tx, "Tracing to oemu.trace.");
+            }
+            Err(why) => GUIMessage::log_console(
+                &tx,
+                &format!("Error: Could not open file oemu.trace: {}.", why),
+            ),
+        }
+    });
+
+    // STACK CHECK TOGGLE
+    let stack_check: gtk::CheckButton = builder.get_object("stack_check").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    stack_check.connect_toggled(move |check| {
+        let mut mcu = mcu.lock().unwrap();
+        if !check.get_active() {
+            mcu.set_stack_guard(None);
+            GUIMessage::log_console(&tx, "Stack check disabled.");
+            return;
+        }
+        let mut guard = mcu.stack_layout();
+        guard.stop = true;
+        mcu.set_stack_guard(Some(guard));
+        GUIMessage::log_console(
+            &tx,
+            &format!(
+                "Stack check enabled, with the stack at {:#010X}:{:#010X} and text at {:#010X}:{:#010X}.",
+                guard.stack_bottom, guard.stack_top, guard.text_begin, guard.text_end
+            ),
+        );
+    });
+
+    // UNDEFINED VALUES TOGGLE
+    let undefined_check: gtk::CheckButton = builder.get_object("undefined_check").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    undefined_check.connect_toggled(move |check| {
+        mcu.lock().unwrap().set_definedness(check.get_active());
+        if check.get_active() {
+            GUIMessage::log_console(&tx, "Tracking undefined values.");
+        } else {
+            GUIMessage::log_console(&tx, "Stopped tracking undefined values.");
+        }
+    });
+
+    // MEMORY PROTECTION TOGGLE
+    let protect_check: gtk::CheckButton = builder.get_object("protect_check").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    protect_check.connect_toggled(move |check| {
+        mcu.lock().unwrap().set_protection(check.get_active());
+        if check.get_active() {
+            GUIMessage::log_console(&tx, "Memory protection enabled.");
+        } else {
+            GUIMessage::log_console(&tx, "Memory protection disabled.");
+        }
+    });
+
+    // UNHANDLED TRAPS TOGGLE
+    let skip_trap_check: gtk::CheckButton = builder.get_object("skip_trap_check").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    skip_trap_check.connect_toggled(move |check| {
+        if check.get_active() {
+            mcu.lock().unwrap().set_trap_fallback(TrapFallback::Skip);
+            GUIMessage::log_console(&tx, "Skipping instructions that trap without a handler.");
+        } else {
+            mcu.lock().unwrap().set_trap_fallback(TrapFallback::Halt);
+            GUIMessage::log_console(&tx, "Halting on traps without a handler.");
+        }
+    });
+
     // BREAKPOINTS BTNs
     let bp_btn: gtk::Button = builder.get_object("bp_btn").unwrap();
     let builder_clone = builder.clone();
@@ -274,32 +580,91 @@ pub fn build_gui(application: &gtk::Application) {
     let builder_clone = builder.clone();
     let add_bp_btn: gtk::Button = builder.get_object("add_bp_btn").unwrap();
     let clear_bp_btn: gtk::Button = builder.get_object("clear_bp_btn").unwrap();
-    let bps_clone = bps_mutex.clone();
+    let mcu_clone = mcu_mutex.clone();
+    let tx = tx_main.clone();
     add_bp_btn.connect_clicked(move |_| {
         let input: gtk::Entry = builder_clone.get_object("bp_entry").unwrap();
         let list: gtk::ListBox = builder_clone.get_object("bp_list").unwrap();
-        let addr = match util::parse::parse_int(&input.get_text()) {
-            Ok(n) => n,
-            _ => return,
+        let kind: gtk::ComboBoxText = builder_clone.get_object("bp_kind").unwrap();
+        let kind = match kind.get_active_id().as_deref() {
+            Some("write") => otter::WatchKind::Write,
+            Some("read") => otter::WatchKind::Read,
+            Some("access") => otter::WatchKind::Access,
+            _ => {
+                // LOCATION [ignore N] [if CONDITION] [log MESSAGE]
+                let mut mcu = mcu_clone.lock().unwrap();
+                match mcu.add_breakpoint(&input.get_text()) {
+                    Ok(_) => show_breakpoints(&list, &mcu, &mcu_clone),
+                    Err(why) => GUIMessage::log_console(
+                        &tx,
+                        &format!("Error: Invalid breakpoint: {}.", why),
+                    ),
+                }
+                return;
+            }
         };
-        bps_clone.lock().unwrap().push(addr);
-        let row = gtk::ListBoxRow::new();
-        let label = gtk::Label::new(Some(&format!("{:#10X}", addr)));
-        let container = gtk::Box::new(gtk::Orientation::Horizontal, 20);
-        container.add(&label);
-        container.pack_start(&label, true, true, 10);
-        row.add(&container);
-        list.add(&row);
-        list.show_all();
+        // an address watches a word, BEGIN:END a range of bytes
+        match otter::Watchpoint::parse(kind, &input.get_text()) {
+            Ok(w) => {
+                mcu_clone.lock().unwrap().add_watchpoint(w);
+                add_bp_row(&list, &w.to_string());
+            }
+            Err(why) => {
+                GUIMessage::log_console(&tx, &format!("Error: Invalid watchpoint: {}.", why))
+            }
+        }
     });
-    let bps_clone = bps_mutex.clone();
+    let mcu = mcu_mutex.clone();
     let builder_clone = builder.clone();
     clear_bp_btn.connect_clicked(move |_| {
-        bps_clone.lock().unwrap().clear();
+        let mut mcu = mcu.lock().unwrap();
+        mcu.clear_breakpoints();
+        mcu.clear_watchpoints();
         let list: gtk::ListBox = builder_clone.get_object("bp_list").unwrap();
         list.foreach(|w| list.remove(w));
     });
 
+    // SAVE STATE BUTTON
+    let save_btn: gtk::Button = builder.get_object("save_btn").unwrap();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    save_btn.connect_clicked(move |_| {
+        let snap = mcu.lock().unwrap().snapshot();
+        match otter::snapshot::save("oemu.snap", &snap) {
+            Ok(()) => GUIMessage::log_console(&tx, "Saved state to oemu.snap."),
+            Err(why) => {
+                GUIMessage::log_console(&tx, &format!("Error: Could not save state {}.", why))
+            }
+        }
+    });
+
+    // RESTORE STATE BUTTON
+    let restore_btn: gtk::Button = builder.get_object("restore_btn").unwrap();
+    let mcu_clone = mcu_mutex.clone();
+    let programmed = programmed_mutex.clone();
+    let builder_clone = builder.clone();
+    let tx = tx_main.clone();
+    restore_btn.connect_clicked(move |_| {
+        let mut mcu = mcu_clone.lock().unwrap();
+        if let Err(why) = otter::snapshot::load("oemu.snap").and_then(|snap| mcu.restore(&snap)) {
+            GUIMessage::log_console(&tx, &format!("Error: Could not restore state {}.", why));
+            return;
+        }
+        let list: gtk::ListBox = builder_clone.get_object("bp_list").unwrap();
+        show_breakpoints(&list, &mcu, &mcu_clone);
+        *programmed.lock().unwrap() = true;
+        tx.send(GUIMessage::gui_update(
+            Some("Restored state from oemu.snap."),
+            Some(mcu.leds()),
+            Some(mcu.sseg()),
+            Some(mcu.rf()),
+            Some(mcu.pc),
+            Some(mcu.fetch(|_s| {}).0),
+        ))
+        .unwrap();
+        GUIMessage::show_stack(&tx, &mcu);
+    });
+
     // ABOUT BUTTON
     about_btn.connect_clicked(move |_| {
         webbrowser::open("https://github.com/trmckay/otter-emu").unwrap();
@@ -314,6 +679,39 @@ pub fn build_gui(application: &gtk::Application) {
             .dump("oemu.dump", |s| GUIMessage::log_console(&tx_logger, s));
     });
 
+    // LAST WRITE
+    // which instruction last wrote the register or address in the entry
+    let builder_clone = builder.clone();
+    let mcu = mcu_mutex.clone();
+    let tx = tx_main.clone();
+    last_write_btn.connect_clicked(move |_| {
+        let input: gtk::Entry = builder_clone.get_object("mem_addr_entry").unwrap();
+        let text = input.get_text();
+        let mcu = mcu.lock().unwrap();
+        let (what, write) = if let Some(reg) = otter::reg_index(&text) {
+            (
+                format!("x{} ({})", reg, otter::reg_name(reg)),
+                mcu.last_reg_write(reg),
+            )
+        } else if let Ok(addr) = util::parse::parse_int(&text) {
+            (format!("{:#010X}", addr), mcu.last_mem_write(addr))
+        } else {
+            return;
+        };
+        let msg = match write {
+            Some(u) => format!(
+                "{} was last written by instruction {} at {:#010X}.",
+                what, u.count, u.pc
+            ),
+            None => format!(
+                "{} was not written in the last {} instructions.",
+                what,
+                mcu.history_len()
+            ),
+        };
+        GUIMessage::log_console(&tx, &msg);
+    });
+
     // READ MEMORY
     let builder_clone = builder.clone();
     let mcu = mcu_mutex.clone();
@@ -345,6 +743,60 @@ pub fn build_gui(application: &gtk::Application) {
             Some(mcu.fetch(|_s| {}).0),
         ))
         .unwrap();
+        GUIMessage::show_stack(&tx, &mcu);
+    });
+
+    // STEP BACK
+    let tx = tx_main.clone();
+    let mcu = mcu_mutex.clone();
+    let running = running_mutex.clone();
+    step_back_btn.connect_clicked(move |_| {
+        if *running.lock().unwrap() {
+            GUIMessage::log_console(&tx, "Error: Cannot step back while running.");
+            return;
+        }
+        let mut mcu = mcu.lock().unwrap();
+        let msg = if mcu.step_back() {
+            None
+        } else {
+            Some("Error: No history to step back through.")
+        };
+        tx.send(GUIMessage::gui_update(
+            msg,
+            Some(mcu.leds()),
+            Some(mcu.sseg()),
+            Some(mcu.rf()),
+            Some(mcu.pc),
+            Some(mcu.fetch(|_s| {}).0),
+        ))
+        .unwrap();
+        GUIMessage::show_stack(&tx, &mcu);
+    });
+
+    // REVERSE CONTINUE
+    let tx = tx_main.clone();
+    let mcu = mcu_mutex.clone();
+    let running = running_mutex.clone();
+    reverse_btn.connect_clicked(move |_| {
+        if *running.lock().unwrap() {
+            GUIMessage::log_console(&tx, "Error: Cannot reverse while running.");
+            return;
+        }
+        let mut mcu = mcu.lock().unwrap();
+        let msg = match mcu.reverse_continue() {
+            Some(id) => format!("Reversed to breakpoint {} at {:#010X}.", id, mcu.pc),
+            None => String::from("Reversed to the start of the history."),
+        };
+        tx.send(GUIMessage::gui_update(
+            Some(&msg),
+            Some(mcu.leds()),
+            Some(mcu.sseg()),
+            Some(mcu.rf()),
+            Some(mcu.pc),
+            Some(mcu.fetch(|_s| {}).0),
+        ))
+        .unwrap();
+        GUIMessage::show_stack(&tx, &mcu);
     });
 
     // RUN
@@ -361,54 +813,7 @@ pub fn build_gui(application: &gtk::Application) {
             GUIMessage::log_console(&tx, "Error: MCU must be programmed first.");
             return;
         }
-        // clone mutexs
-        let mcu = mcu.clone();
-        let running = running.clone();
-        let tx = tx.clone();
-        let bps = bps_mutex.clone();
-        // create a new thread so the CPU runs in the background
-        thread::spawn(move || {
-            let mut c: usize = 0;
-            *running.lock().unwrap() = true;
-            let mut local_running = true;
-            // do while still running (wait for pause btn)
-            while local_running {
-                let mut mcu = mcu.lock().unwrap();
-                let tx_logger = tx.clone();
-                mcu.step(move |s| GUIMessage::log_console(&tx_logger, s));
-                if bps.lock().unwrap().contains(&mcu.pc) {
-                    *running.lock().unwrap() = false;
-                    local_running = false;
-                    tx.send(GUIMessage::gui_update(
-                        Some(&format!("Encountered breakpoint at {:#010X}.", mcu.pc)),
-                        Some(mcu.leds()),
-                        Some(mcu.sseg()),
-                        Some(mcu.rf()),
-                        Some(mcu.pc),
-                        Some(mcu.fetch(|_s| {}).0),
-                    ))
-                    .unwrap();
-                } else {
-                    local_running = *running.lock().unwrap();
-                }
-                // refresh GUI
-                thread::sleep(Duration::from_micros(IR_PERIOD_US));
-                c += 1;
-                if c == GUI_REFRESH_PERIOD {
-                    // reset count, lock mcu, read, send message
-                    c = 0;
-                    tx.send(GUIMessage::gui_update(
-                        None,
-                        Some(mcu.leds()),
-                        Some(mcu.sseg()),
-                        Some(mcu.rf()),
-                        Some(mcu.pc),
-                        None,
-                    ))
-                    .unwrap();
-                }
-            }
-        });
+        run_in_background(mcu.clone(), running.clone(), tx.clone(), None);
     });
 
     // STEP
@@ -427,9 +832,10 @@ pub fn build_gui(application: &gtk::Application) {
         }
         let mut mcu = mcu.lock().unwrap();
         let tx_logger = tx.clone();
-        mcu.step(move |s| GUIMessage::log_console(&tx_logger, s));
+        let stop = mcu.step(move |s| GUIMessage::log_console(&tx_logger, s));
+        GUIMessage::print_console(&tx, &mut mcu);
         tx.send(GUIMessage::gui_update(
-            None,
+            stop.map(|r| stop_message(&r, &mcu)).as_deref(),
             Some(mcu.leds()),
             Some(mcu.sseg()),
             Some(mcu.rf()),
@@ -437,8 +843,52 @@ pub fn build_gui(application: &gtk::Application) {
             Some(mcu.fetch(|_s| {}).0),
         ))
         .unwrap();
+        GUIMessage::show_stack(&tx, &mcu);
     });
 
+    // STEP OVER, STEP OUT, RUN TO
+    // run in the background like RUN, until the goal is reached
+    for (btn, name) in [
+        (&step_over_btn, "step over"),
+        (&step_out_btn, "step out"),
+        (&run_to_btn, "run to"),
+    ]
+    .iter()
+    {
+        let tx = tx_main.clone();
+        let mcu = mcu_mutex.clone();
+        let running = running_mutex.clone();
+        let programmed = programmed_mutex.clone();
+        let entry = mem_addr_entry.clone();
+        let name = *name;
+        btn.connect_clicked(move |_| {
+            if !*programmed.lock().unwrap() {
+                GUIMessage::log_console(&tx, "Error: MCU must be programmed first.");
+                return;
+            }
+            if *running.lock().unwrap() {
+                GUIMessage::log_console(&tx, &format!("Error: Cannot {} while running.", name));
+                return;
+            }
+            let goal = {
+                let mcu = mcu.lock().unwrap();
+                match name {
+                    "step over" => mcu.step_over_goal(),
+                    "step out" => otter::Goal::Out { depth: 0 },
+                    // an address or symbol in the memory entry
+                    _ => match mcu.locate(&entry.get_text()) {
+                        Ok(addr) => otter::Goal::Address(addr),
+                        Err(why) => {
+                            GUIMessage::log_console(&tx, &format!("Error: Cannot run to {}.", why));
+                            return;
+                        }
+                    },
+                }
+            };
+            run_in_background(mcu.clone(), running.clone(), tx.clone(), Some(goal));
+        });
+    }
+
     // PAUSE
     let tx = tx_main.clone();
     let mcu = mcu_mutex.clone();
@@ -460,6 +910,7 @@ pub fn build_gui(application: &gtk::Application) {
             Some(mcu.fetch(|_s| {}).0),
         ))
         .unwrap();
+        GUIMessage::show_stack(&tx, &mcu);
     });
 
     window.show_all();
@@ -10,12 +10,29 @@ mod otter;
 mod util;
 
 fn main() {
-    App::new("oemu")
+    let matches = App::new("oemu")
         .version("0.1.0")
         .author("Trevor McKay <trmckay@calpoly.edu>")
         .about("Emulator for the RV32I multi-cycle Otter")
+        .subcommand(app::cli::run_command())
+        .subcommand(app::cli::riscv_tests_command())
+        .subcommand(app::cli::cosim_command())
         .get_matches();
 
+    // headless
+    if let Some(m) = matches.subcommand_matches("run") {
+        app::cli::run(m);
+        return;
+    }
+    if let Some(m) = matches.subcommand_matches("riscv-tests") {
+        app::cli::riscv_tests(m);
+        return;
+    }
+    if let Some(m) = matches.subcommand_matches("cosim") {
+        app::cli::cosim(m);
+        return;
+    }
+
     let application = gtk::Application::new(Some("com.trmckay.oemu"), Default::default())
         .expect("Initialization failed...");
 
@@ -0,0 +1,384 @@
+// Breakpoints with conditions, ignore counts and log-only tracepoints,
+// written as
+//
+//     LOCATION [ignore N] [if CONDITION] [log MESSAGE]
+//
+// e.g. '0x40 ignore 2 if a0 == 5 && mem32[sp+4] != 0'. LOCATION is an
+// address or a symbol from the loaded ELF, with an optional offset, e.g.
+// 'main' or 'main+0x8'. CONDITION is an
+// expression (see expr.rs) and the breakpoint only counts a hit when it is
+// non-zero. The first N hits are ignored. With 'log', the breakpoint prints
+// MESSAGE instead of stopping; '{EXPR}' in MESSAGE is replaced by the value
+// of EXPR in hex, and '{{' and '}}' are literal braces.
+
+use super::super::util::parse::parse_int;
+use super::expr::{self, Expr};
+use super::mcu::MCU;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Value(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u32,
+    // as written, and parsed
+    condition: Option<(String, Expr)>,
+    pub ignore: u64,
+    // times the condition held
+    pub hits: u64,
+    message: Option<(String, Vec<Piece>)>,
+}
+
+// a breakpoint in a Breakpoints list
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub id: u32,
+    pub enabled: bool,
+    pub bp: Breakpoint,
+}
+
+// the MCU's breakpoints, numbered from 1 in the order they were added
+#[derive(Default)]
+pub struct Breakpoints {
+    entries: Vec<Entry>,
+    next_id: u32,
+}
+
+// what to do on reaching a breakpoint
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Continue,
+    Stop,
+    Log(String),
+}
+
+fn parse_message(msg: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut rest = msg;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            text.push(c);
+            rest = &rest[2..];
+        } else if c == '{' {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| String::from("unclosed '{' in message"))?;
+            if !text.is_empty() {
+                pieces.push(Piece::Text(text.split_off(0)));
+            }
+            pieces.push(Piece::Value(expr::parse(&rest[1..end])?));
+            rest = &rest[end + 1..];
+        } else if c == '}' {
+            return Err(String::from("unmatched '}' in message"));
+        } else {
+            text.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+// the address of an address or 'SYMBOL[+OFFSET]'
+pub fn locate(location: &str, symbols: &HashMap<String, u32>) -> Result<u32, String> {
+    if let Ok(addr) = parse_int(location) {
+        return Ok(addr);
+    }
+    let (name, offset) = match location.find('+') {
+        Some(i) => {
+            let offset = &location[i + 1..];
+            let offset = parse_int(offset).map_err(|e| format!("bad offset {}: {}", offset, e))?;
+            (&location[..i], offset)
+        }
+        None => (location, 0),
+    };
+    match symbols.get(name) {
+        Some(addr) => Ok(addr.wrapping_add(offset)),
+        None => Err(format!("no symbol or address {}", name)),
+    }
+}
+
+// the text after the first ' KEYWORD ' in 's', and 's' up to it
+fn split_keyword<'a>(s: &'a str, keyword: &str) -> (&'a str, Option<&'a str>) {
+    let pat = format!(" {} ", keyword);
+    match s.find(&pat) {
+        Some(i) => (&s[..i], Some(s[i + pat.len()..].trim())),
+        None => (s, None),
+    }
+}
+
+impl Breakpoint {
+    pub fn new(addr: u32) -> Breakpoint {
+        Breakpoint {
+            addr,
+            condition: None,
+            ignore: 0,
+            hits: 0,
+            message: None,
+        }
+    }
+
+    // 'symbols' resolves a symbolic location
+    pub fn parse(spec: &str, symbols: &HashMap<String, u32>) -> Result<Breakpoint, String> {
+        // pad so a keyword at either end still has spaces around it
+        let spec = format!(" {} ", spec.trim());
+        let (spec, message) = split_keyword(&spec, "log");
+        let (spec, condition) = split_keyword(spec, "if");
+        let tokens: Vec<&str> = spec.split_whitespace().collect();
+        let addr = match tokens.first() {
+            Some(t) => locate(t, symbols)?,
+            None => return Err(String::from("missing location")),
+        };
+        let mut bp = Breakpoint::new(addr);
+        match tokens[1..] {
+            [] => {}
+            ["ignore", n] => {
+                bp.ignore =
+                    parse_int(n).map_err(|e| format!("bad ignore count {}: {}", n, e))? as u64;
+            }
+            _ => return Err(format!("unexpected '{}'", tokens[1..].join(" "))),
+        }
+        if let Some(c) = condition {
+            bp.condition = Some((String::from(c), expr::parse(c)?));
+        }
+        if let Some(m) = message {
+            // quotes are optional
+            let m = m
+                .strip_prefix('"')
+                .and_then(|m| m.strip_suffix('"'))
+                .unwrap_or(m);
+            bp.message = Some((String::from(m), parse_message(m)?));
+        }
+        Ok(bp)
+    }
+
+    // whether this only logs
+    pub fn is_tracepoint(&self) -> bool {
+        self.message.is_some()
+    }
+
+    // whether the MCU is at the breakpoint with its condition holding
+    pub fn matches(&self, mcu: &MCU) -> bool {
+        if mcu.pc != self.addr {
+            return false;
+        }
+        match &self.condition {
+            None => true,
+            Some((_, c)) => c.eval(mcu) != 0,
+        }
+    }
+
+    // reached the breakpoint, with the MCU about to execute it
+    pub fn check(&mut self, mcu: &MCU) -> Action {
+        if !self.matches(mcu) {
+            return Action::Continue;
+        }
+        self.hits += 1;
+        if self.hits <= self.ignore {
+            return Action::Continue;
+        }
+        match &self.message {
+            None => Action::Stop,
+            Some((_, pieces)) => {
+                let mut msg = String::new();
+                for p in pieces.iter() {
+                    match p {
+                        Piece::Text(t) => msg.push_str(t),
+                        Piece::Value(e) => msg.push_str(&format!("{:#010X}", e.eval(mcu))),
+                    }
+                }
+                Action::Log(msg)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#010X}", self.addr)?;
+        if self.ignore > 0 {
+            write!(f, " ignore {}", self.ignore)?;
+        }
+        if let Some((c, _)) = &self.condition {
+            write!(f, " if {}", c)?;
+        }
+        if let Some((m, _)) = &self.message {
+            write!(f, " log \"{}\"", m)?;
+        }
+        Ok(())
+    }
+}
+
+impl Breakpoints {
+    // returns the new breakpoint's id
+    pub fn add(&mut self, bp: Breakpoint) -> u32 {
+        self.next_id += 1;
+        self.entries.push(Entry {
+            id: self.next_id,
+            enabled: true,
+            bp,
+        });
+        self.next_id
+    }
+
+    // returns false if there is no breakpoint 'id'
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.id != id);
+        self.entries.len() != len
+    }
+
+    // returns false if there is no breakpoint 'id'
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        match self.entries.iter_mut().find(|e| e.id == id) {
+            Some(e) => {
+                e.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // check the enabled breakpoints at the MCU's PC, logging tracepoints;
+    // returns the id of the first that stops
+    pub fn check<L>(&mut self, mcu: &MCU, logger: L) -> Option<u32>
+    where
+        L: Fn(&str),
+    {
+        let mut stop = None;
+        for e in self.entries.iter_mut().filter(|e| e.enabled) {
+            match e.bp.check(mcu) {
+                Action::Continue => {}
+                Action::Stop => stop = stop.or(Some(e.id)),
+                Action::Log(msg) => logger(&msg),
+            }
+        }
+        stop
+    }
+
+    // the first enabled breakpoint that would stop the MCU here, without
+    // counting a hit
+    pub fn matching(&self, mcu: &MCU) -> Option<u32> {
+        self.entries
+            .iter()
+            .find(|e| e.enabled && !e.bp.is_tracepoint() && e.bp.matches(mcu))
+            .map(|e| e.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::load_words;
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn parses() {
+        let mut symbols = HashMap::new();
+        symbols.insert(String::from("main"), 0x40);
+        let bp = Breakpoint::parse("0x40", &symbols).unwrap();
+        assert_eq!(Breakpoint::new(0x40), bp);
+        assert_eq!("0x00000040", bp.to_string());
+        assert_eq!(bp, Breakpoint::parse("main", &symbols).unwrap());
+        assert_eq!(Ok(0x48), locate("main+0x8", &symbols));
+
+        let spec = "main ignore 2 if a0 == 5 && mem32[sp+4] != 0 log \"a0={a0} {{sp}}\"";
+        let bp = Breakpoint::parse(spec, &symbols).unwrap();
+        assert_eq!(2, bp.ignore);
+        assert!(bp.is_tracepoint());
+        assert_eq!(
+            "0x00000040 ignore 2 if a0 == 5 && mem32[sp+4] != 0 log \"a0={a0} {{sp}}\"",
+            bp.to_string()
+        );
+        assert_eq!(bp, Breakpoint::parse(&bp.to_string(), &symbols).unwrap());
+
+        let parse = |s| Breakpoint::parse(s, &symbols);
+        assert!(parse("").is_err());
+        assert_eq!(Err(String::from("no symbol or address foo")), parse("foo"));
+        assert!(parse("main+x").is_err());
+        assert!(parse("0x40 if").is_err());
+        assert!(parse("0x40 if a0 ==").is_err());
+        assert_eq!(Err(String::from("unexpected 'twice'")), parse("0x40 twice"));
+        assert_eq!(
+            Err(String::from("unclosed '{' in message")),
+            parse("0x40 log {a0")
+        );
+    }
+
+    #[test]
+    fn hits() {
+        let mut mcu = MCU::new();
+        // loop: addi a0, a0, 1; j loop
+        let prog: [u32; 2] = [0x00150513, 0xffdff06f];
+        load_words(&mut mcu, &prog);
+        let symbols = HashMap::new();
+        let mut bps = Breakpoints::default();
+        let first = bps.add(Breakpoint::parse("0 ignore 1 if a0 % 2 == 0", &symbols).unwrap());
+        bps.add(Breakpoint::parse("4 log a0 = {a0}", &symbols).unwrap());
+        let log = RefCell::new(Vec::new());
+        let mut stops = Vec::new();
+        for _ in 0..12 {
+            if let Some(id) = bps.check(&mcu, |s| log.borrow_mut().push(String::from(s))) {
+                stops.push((id, mcu.rf_rd(10)));
+            }
+            mcu.step(|_s| {});
+        }
+        // a0 is 0, 2 and 4 at the first, the first is ignored
+        assert_eq!(vec![(first, 2), (first, 4)], stops);
+        assert_eq!(3, bps.list()[0].bp.hits);
+        assert_eq!(
+            vec!["a0 = 0x00000001", "a0 = 0x00000002", "a0 = 0x00000003"],
+            log.borrow()[..3].to_vec()
+        );
+        assert_eq!(6, log.borrow().len());
+    }
+
+    #[test]
+    fn list() {
+        let mut bps = Breakpoints::default();
+        assert_eq!(1, bps.add(Breakpoint::new(0x10)));
+        assert_eq!(2, bps.add(Breakpoint::new(0x20)));
+        assert!(bps.set_enabled(1, false));
+        assert!(!bps.set_enabled(3, false));
+
+        let mut mcu = MCU::new();
+        mcu.pc = 0x10;
+        assert_eq!(None, bps.check(&mcu, |_s| {}));
+        assert_eq!(None, bps.matching(&mcu));
+        mcu.pc = 0x20;
+        assert_eq!(Some(2), bps.matching(&mcu));
+        assert_eq!(0, bps.list()[1].bp.hits);
+
+        assert!(bps.remove(2));
+        assert!(!bps.remove(2));
+        assert_eq!(None, bps.check(&mcu, |_s| {}));
+        // ids are not reused
+        assert_eq!(3, bps.add(Breakpoint::new(0x20)));
+        assert_eq!(
+            vec![1, 3],
+            bps.list().iter().map(|e| e.id).collect::<Vec<u32>>()
+        );
+        bps.clear();
+        assert!(bps.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+// A shadow call stack, kept by watching calls (jal or jalr saving a return
+// address in ra or t0) and returns (jalr x0 through ra or t0), for
+// backtraces and to catch a return to somewhere no call would come back to.
+
+use std::collections::HashMap;
+
+// one call that has not returned yet
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frame {
+    // PC of the call instruction
+    pub call: u32,
+    // the function called
+    pub target: u32,
+    // where it should return to
+    pub ret: u32,
+    // the stack pointer at the call
+    pub sp: u32,
+}
+
+// a return to an address not on the call stack
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mismatch {
+    // PC of the return instruction
+    pub pc: u32,
+    pub target: u32,
+    // where the innermost call should have returned to
+    pub expected: u32,
+}
+
+#[derive(Default)]
+pub struct CallStack {
+    // outermost first
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn call(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    // a return from 'pc' to 'target'; returns the frames it popped
+    // returning past several frames, as longjmp does, pops them all; an
+    // empty stack, as after restoring a snapshot, returns anywhere
+    pub fn ret(&mut self, pc: u32, target: u32) -> Result<Vec<Frame>, Mismatch> {
+        match self.frames.iter().rposition(|f| f.ret == target) {
+            Some(i) => Ok(self.frames.split_off(i)),
+            None => match self.frames.last() {
+                None => Ok(Vec::new()),
+                Some(f) => Err(Mismatch {
+                    pc,
+                    target,
+                    expected: f.ret,
+                }),
+            },
+        }
+    }
+
+    // outermost first
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // undo changes, going back to 'depth' frames and pushing 'popped'
+    pub fn rewind(&mut self, depth: usize, popped: &[Frame]) {
+        self.frames.truncate(depth);
+        self.frames.extend_from_slice(popped);
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+// the symbol 'addr' is in, as the nearest one at or below it, with the
+// offset from it; names like 'file.c' lose ties to others
+pub fn symbolize(addr: u32, symbols: &HashMap<String, u32>) -> Option<(&str, u32)> {
+    symbols
+        .iter()
+        .filter(|(_, a)| **a <= addr)
+        .max_by_key(|(name, a)| (**a, !name.contains('.'), std::cmp::Reverse(*name)))
+        .map(|(name, a)| (name.as_str(), addr - a))
+}
+
+// 'addr', and its symbol if there is one, e.g. '0x00000048 in main+0x8'
+pub fn describe(addr: u32, symbols: &HashMap<String, u32>) -> String {
+    match symbolize(addr, symbols) {
+        None => format!("{:#010X}", addr),
+        Some((name, 0)) => format!("{:#010X} in {}", addr, name),
+        Some((name, offset)) => format!("{:#010X} in {}+{:#X}", addr, name, offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(call: u32) -> Frame {
+        Frame {
+            call,
+            target: 0x100,
+            ret: call + 4,
+            sp: 0,
+        }
+    }
+
+    #[test]
+    fn calls_and_returns() {
+        let mut stack = CallStack::default();
+        // returns with nothing to match are let through
+        assert_eq!(Ok(Vec::new()), stack.ret(0x10, 0x20));
+
+        stack.call(frame(0x0));
+        stack.call(frame(0x100));
+        stack.call(frame(0x200));
+        assert_eq!(Ok(vec![frame(0x200)]), stack.ret(0x300, 0x204));
+        assert_eq!(
+            Err(Mismatch {
+                pc: 0x108,
+                target: 0x50,
+                expected: 0x104
+            }),
+            stack.ret(0x108, 0x50)
+        );
+        assert_eq!(2, stack.frames().len());
+
+        // back out of both at once
+        assert_eq!(Ok(vec![frame(0x0), frame(0x100)]), stack.ret(0x108, 0x4));
+        assert!(stack.frames().is_empty());
+        stack.rewind(0, &[frame(0x0), frame(0x100)]);
+        assert_eq!(2, stack.frames().len());
+        stack.clear();
+        assert!(stack.frames().is_empty());
+    }
+
+    #[test]
+    fn symbols() {
+        let mut symbols = HashMap::new();
+        symbols.insert(String::from("crt0.o"), 0x0);
+        symbols.insert(String::from("_start"), 0x0);
+        symbols.insert(String::from("main"), 0x40);
+        assert_eq!(None, symbolize(0x10, &HashMap::new()));
+        assert_eq!(Some(("_start", 0x10)), symbolize(0x10, &symbols));
+        assert_eq!("0x00000040 in main", describe(0x40, &symbols));
+        assert_eq!("0x00000048 in main+0x8", describe(0x48, &symbols));
+        assert_eq!("0x00000048", describe(0x48, &HashMap::new()));
+    }
+}
@@ -0,0 +1,369 @@
+// Checks the MCU against a trace from an RTL simulation, one instruction at
+// a time, stopping at the first instruction that disagrees.
+//
+// A trace has one line per retired instruction:
+//
+//     PC [REG VALUE] [mem ADDR [DATA]]
+//
+// e.g. '0x0000000C x5 0x00000003' or '0x00000010 mem 0x11080000 0x0003'.
+// REG is 'xN' or an ABI name and is written with VALUE; 'mem ADDR DATA' is a
+// store and 'mem ADDR' a load. Numbers are decimal or 0x-prefixed hex.
+// Writes to x0 are ignored, as are blank lines and '#' comments.
+//
+// Lines from Spike's commit log, as written with '--trace-format spike',
+// are accepted too: the leading 'core 0: 3' is skipped and the encoding in
+// parentheses is checked. Spike leaves out instructions that trap, so the
+// MCU may execute one the trace does not have, but not two in a row, as when
+// a handler's first instruction traps again.
+
+use super::super::util::parse::parse_int;
+use super::mcu::MCU;
+use super::rv32i::decode::{reg_index, reg_name};
+use super::rv32i::disasm;
+use super::trace::Record;
+use std::fs;
+
+// what the trace says one instruction did
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Commit {
+    // line in the trace, from 1
+    pub line: usize,
+    pub pc: u32,
+    pub raw: Option<u32>,
+    // register written and its new value
+    pub rd: Option<(u32, u32)>,
+    // address accessed, and the data if it was a store
+    pub mem: Option<(u32, Option<u32>)>,
+}
+
+impl Commit {
+    // what the MCU did, in the same terms
+    fn from_record(r: &Record) -> Commit {
+        Commit {
+            line: 0,
+            pc: r.pc,
+            raw: Some(r.raw),
+            rd: r.rd,
+            mem: r
+                .access
+                .map(|a| (a.addr, if a.write { Some(a.data) } else { None })),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut line = format!("{:#010X}", self.pc);
+        if let Some(raw) = self.raw {
+            line.push_str(&format!(" ({:#010X})", raw));
+        }
+        if let Some((rd, value)) = self.rd {
+            line.push_str(&format!(" x{} {:#010X}", rd, value));
+        }
+        if let Some((addr, data)) = self.mem {
+            line.push_str(&format!(" mem {:#010X}", addr));
+            if let Some(data) = data {
+                line.push_str(&format!(" {:#010X}", data));
+            }
+        }
+        line
+    }
+
+    // whether the MCU's instruction matches this one
+    fn matches(&self, emulated: &Commit) -> bool {
+        if self.pc != emulated.pc || self.rd != emulated.rd {
+            return false;
+        }
+        if self.raw.is_some() && self.raw != emulated.raw {
+            return false;
+        }
+        match (self.mem, emulated.mem) {
+            // a trace need not list loads
+            (None, None) | (None, Some((_, None))) => true,
+            (Some(expected), Some(got)) => expected == got,
+            _ => false,
+        }
+    }
+}
+
+fn number(tokens: &[&str], i: usize, what: &str) -> Result<u32, String> {
+    let token = tokens.get(i).ok_or(format!("missing {}", what))?;
+    parse_int(token).map_err(|e| format!("bad {} {}: {}", what, token, e))
+}
+
+fn parse_line(line: &str) -> Result<Option<Commit>, String> {
+    let line = match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+    let mut tokens: Vec<&str> = line.split_whitespace().collect();
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    // Spike's hart and privilege level
+    if tokens[0] == "core" {
+        if tokens.len() < 3 {
+            return Err(String::from("missing hart or privilege after 'core'"));
+        }
+        tokens.drain(..3);
+    }
+
+    let mut commit = Commit {
+        line: 0,
+        pc: number(&tokens, 0, "PC")?,
+        raw: None,
+        rd: None,
+        mem: None,
+    };
+    let mut i = 1;
+    if let Some(raw) = tokens.get(i).and_then(|t| t.strip_prefix('(')) {
+        let raw = raw.trim_end_matches(')');
+        commit.raw = Some(parse_int(raw).map_err(|e| format!("bad encoding {}: {}", raw, e))?);
+        i += 1;
+    }
+    while i < tokens.len() {
+        if tokens[i] == "mem" {
+            let addr = number(&tokens, i + 1, "address")?;
+            // data follows a store's address
+            let data = match tokens.get(i + 2) {
+                Some(t) if parse_int(t).is_ok() => Some(number(&tokens, i + 2, "data")?),
+                _ => None,
+            };
+            commit.mem = Some((addr, data));
+            i += if data.is_some() { 3 } else { 2 };
+        } else if let Some(rd) = reg_index(tokens[i]) {
+            let value = number(&tokens, i + 1, "register value")?;
+            if rd != 0 {
+                commit.rd = Some((rd, value));
+            }
+            i += 2;
+        } else {
+            return Err(format!("unexpected {}", tokens[i]));
+        }
+    }
+    Ok(Some(commit))
+}
+
+pub fn parse(text: &str) -> Result<Vec<Commit>, String> {
+    let mut commits = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(mut commit)) => {
+                commit.line = i + 1;
+                commits.push(commit);
+            }
+            Ok(None) => {}
+            Err(why) => return Err(format!("line {}: {}", i + 1, why)),
+        }
+    }
+    Ok(commits)
+}
+
+pub fn read(path: &str) -> Result<Vec<Commit>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+// registers whose values in the trace differ from the MCU's
+fn register_diff(expected: &[u32], mcu: &MCU) -> String {
+    let mut diff = String::new();
+    for (i, value) in expected.iter().enumerate() {
+        let got = mcu.rf_rd(i as u32);
+        if got != *value {
+            diff.push_str(&format!(
+                "  x{} ({}): expected {:#010X}, emulated {:#010X}\n",
+                i,
+                reg_name(i as u32),
+                value,
+                got
+            ));
+        }
+    }
+    diff
+}
+
+// step the MCU through 'trace'; returns how many instructions matched, or
+// a report of the first mismatch
+pub fn check(mcu: &mut MCU, trace: &[Commit]) -> Result<usize, String> {
+    // the hardware does not check permissions, so neither may the MCU
+    mcu.set_protection(false);
+    // the register file as the trace has it
+    let mut rf = vec![0; 32];
+    let mut matched = 0;
+    // whether a trap the trace left out was skipped for this line
+    let mut skipped = false;
+    while matched < trace.len() {
+        let expected = &trace[matched];
+        let count = mcu.instr_count();
+        let stop = mcu.step(|_s| {});
+        // stopped before executing anything
+        if let (Some(stop), true) = (stop, mcu.instr_count() == count) {
+            return Err(format!(
+                "Mismatch at line {} (instruction {}):\n  expected: {}\n  emulated: stopped: {}\n",
+                expected.line,
+                matched,
+                expected.text(),
+                stop
+            ));
+        }
+        let record = *mcu.retired().unwrap();
+        let emulated = Commit::from_record(&record);
+        if record.trap.is_some() && record.pc != expected.pc && !skipped {
+            skipped = true;
+            continue;
+        }
+
+        if let Some((rd, value)) = expected.rd {
+            rf[rd as usize] = value;
+        }
+        if !expected.matches(&emulated) {
+            let mut report = format!(
+                "Mismatch at line {} (instruction {}):\n  expected: {}\n  emulated: {}  {}\n",
+                expected.line,
+                matched,
+                expected.text(),
+                emulated.text(),
+                disasm::disassemble(&record.ir, record.pc)
+            );
+            if let Some(e) = record.trap {
+                report.push_str(&format!("  trapped: {}\n", e));
+            }
+            let diff = register_diff(&rf, mcu);
+            if !diff.is_empty() {
+                report.push_str("Registers that differ:\n");
+                report.push_str(&diff);
+            }
+            return Err(report);
+        }
+        matched += 1;
+        skipped = false;
+    }
+    Ok(matched)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::mcu::HaltConfig;
+    use super::super::test_util::load_words;
+    use super::*;
+
+    fn mcu() -> MCU {
+        let prog: [u32; 5] = [
+            0x110802b7, // lui t0, 0x11080
+            0x00300313, // addi t1, zero, 3
+            0x00629023, // sh t1, 0(t0)
+            0x0002a383, // lw t2, 0(t0)
+            0x0000006f, // j 0
+        ];
+        let mut mcu = MCU::new();
+        load_words(&mut mcu, &prog);
+        mcu.set_halt_config(HaltConfig {
+            self_loop: false,
+            ..HaltConfig::default()
+        });
+        mcu
+    }
+
+    #[test]
+    fn parses() {
+        let trace = parse(
+            "# from the testbench\n\
+             0x0 x5 0x11080000\n\
+             \n\
+             4 t1 3 # addi\n\
+             core   0: 3 0x00000008 (0x00629023) mem 0x11080000 0x0003\n\
+             0xC x7 3 mem 0x11080000\n\
+             0x10 zero 0x14\n",
+        )
+        .unwrap();
+        assert_eq!(5, trace.len());
+        assert_eq!(2, trace[0].line);
+        assert_eq!(Some((5, 0x11080000)), trace[0].rd);
+        assert_eq!(Some((6, 3)), trace[1].rd);
+        assert_eq!(Some(0x00629023), trace[2].raw);
+        assert_eq!(Some((0x11080000, Some(3))), trace[2].mem);
+        assert_eq!(Some((0x11080000, None)), trace[3].mem);
+        assert_eq!(None, trace[4].rd);
+
+        assert_eq!("line 1: unexpected y5", parse("0 y5 1").unwrap_err());
+        assert!(parse("0 x5").is_err());
+        assert!(parse("pc").is_err());
+    }
+
+    #[test]
+    fn lockstep() {
+        let trace = "0x0 x5 0x11080000\n\
+                     0x4 x6 3\n\
+                     0x8 mem 0x11080000 3\n\
+                     0xC x7 3\n\
+                     0x10\n\
+                     0x10\n";
+        assert_eq!(Ok(6), check(&mut mcu(), &parse(trace).unwrap()));
+
+        // the hardware stored the wrong half
+        let trace = "0x0 x5 0x11080000\n\
+                     0x4 x6 3\n\
+                     0x8 mem 0x11080002 3\n";
+        let report = check(&mut mcu(), &parse(trace).unwrap()).unwrap_err();
+        assert!(report.starts_with("Mismatch at line 3 (instruction 2):"));
+        assert!(report.contains("emulated: 0x00000008 (0x00629023) mem 0x11080000 0x00000003"));
+
+        // and wrote the wrong value
+        let trace = "0x0 x5 0x11080000\n\
+                     0x4 x6 4\n";
+        let report = check(&mut mcu(), &parse(trace).unwrap()).unwrap_err();
+        assert!(report.contains("x6 (t1): expected 0x00000004, emulated 0x00000003"));
+    }
+
+    #[test]
+    fn unprotected() {
+        // a store to write-protected code
+        let mut mcu = MCU::new();
+        load_words(&mut mcu, &[0x00002023, 0x0000006f]); // sw zero, 0(zero); j 0
+        mcu.protect_text(0, 0x8);
+        let trace = "0x0 mem 0x0 0\n\
+                     0x4\n";
+        assert_eq!(Ok(2), check(&mut mcu, &parse(trace).unwrap()));
+    }
+
+    #[test]
+    fn spike_log() {
+        // the MCU's own Spike log checks against it
+        let path = std::env::temp_dir().join(format!("oemu_cosim_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut m = mcu();
+        m.set_halt_config(HaltConfig::default());
+        m.set_tracer(Some(
+            super::super::trace::Tracer::create(
+                path,
+                super::super::trace::Format::Spike,
+                Default::default(),
+            )
+            .unwrap(),
+        ));
+        m.run(|_s| {});
+        m.set_tracer(None);
+
+        let trace = read(path).unwrap();
+        assert_eq!(5, trace.len());
+        assert_eq!(Ok(5), check(&mut mcu(), &trace));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn trap_loop() {
+        // a handler that traps to itself, forever
+        let prog: [u32; 5] = [
+            0x01000293, // addi t0, zero, 0x10
+            0x30529073, // csrw mtvec, t0
+            0x00000073, // ecall
+            0x00000000, 0xFFFFFFFF, // handler: illegal
+        ];
+        let mut mcu = MCU::new();
+        load_words(&mut mcu, &prog);
+        let trace = "0x0 x5 0x10\n\
+                     0x4\n\
+                     0x14\n";
+        let report = check(&mut mcu, &parse(trace).unwrap()).unwrap_err();
+        assert!(report.starts_with("Mismatch at line 3 (instruction 2):"));
+        assert!(report.contains("trapped: illegal instruction"));
+    }
+}
@@ -0,0 +1,155 @@
+// Tracks undefined values, as Valgrind's memcheck does: registers not
+// written since reset and unset memory are undefined, and so is anything
+// computed from them. Copying an undefined value around is harmless, so
+// it is only reported when it decides a branch, forms an address or is
+// written to IO, along with where it came from.
+
+use super::rv32i::decode::reg_name;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+// where an undefined value came from
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Origin {
+    // a register not written since reset
+    Reset(u32),
+    // the instruction at 'pc' loaded unset memory at 'addr'
+    Load { pc: u32, addr: u32 },
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Reset(reg) => write!(
+                f,
+                "x{} ({}) has not been written since reset",
+                reg,
+                reg_name(*reg)
+            ),
+            Origin::Load { pc, addr } => write!(
+                f,
+                "loaded from unset memory at {:#010X} by the instruction at {:#010X}",
+                addr, pc
+            ),
+        }
+    }
+}
+
+// the uses of an undefined value that are reported
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Use {
+    Branch,
+    Address,
+    Io,
+}
+
+impl fmt::Display for Use {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Use::Branch => write!(f, "in a branch condition"),
+            Use::Address => write!(f, "as an address"),
+            Use::Io => write!(f, "as data written to IO"),
+        }
+    }
+}
+
+// the undefined state outside the register file
+#[derive(Default)]
+pub struct Shadow {
+    // bytes of memory holding undefined values stored from registers;
+    // unset bytes are undefined without being here
+    mem: HashMap<u32, Origin>,
+    // instructions already reported, as each is reported once
+    reported: HashSet<u32>,
+}
+
+impl Shadow {
+    // where the value loaded by the instruction at 'pc' came from, if it is
+    // undefined; 'set' tells whether a byte of memory has been written
+    pub fn load<F>(&self, pc: u32, addr: u32, bytes: u32, set: F) -> Option<Origin>
+    where
+        F: Fn(u32) -> bool,
+    {
+        (0..bytes)
+            .map(|i| addr.wrapping_add(i))
+            .find_map(|a| match self.mem.get(&a) {
+                Some(origin) => Some(*origin),
+                None if !set(a) => Some(Origin::Load { pc, addr: a }),
+                None => None,
+            })
+    }
+
+    // a store of a value, undefined if it has an origin
+    pub fn store(&mut self, addr: u32, bytes: u32, origin: Option<Origin>) {
+        for a in (0..bytes).map(|i| addr.wrapping_add(i)) {
+            match origin {
+                Some(origin) => self.mem.insert(a, origin),
+                None => self.mem.remove(&a),
+            };
+        }
+    }
+
+    // a warning for a use of an undefined value by the instruction at 'pc',
+    // unless it was already reported
+    pub fn report(&mut self, pc: u32, used: Use, origin: Origin) -> Option<String> {
+        if !self.reported.insert(pc) {
+            return None;
+        }
+        Some(format!(
+            "[{:#010X}] Warning: Undefined value used {}: {}.",
+            pc, used, origin
+        ))
+    }
+
+    pub fn clear(&mut self) {
+        self.mem.clear();
+        self.reported.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory() {
+        let mut shadow = Shadow::default();
+        let set = |a: u32| a < 0x100;
+        assert_eq!(None, shadow.load(0x8, 0x10, 4, set));
+        assert_eq!(
+            Some(Origin::Load {
+                pc: 0x8,
+                addr: 0x100
+            }),
+            shadow.load(0x8, 0xFE, 4, set)
+        );
+
+        // storing an undefined value makes set memory undefined, with the
+        // value's origin; storing a defined one over it makes it defined
+        let origin = Origin::Reset(5);
+        shadow.store(0x20, 4, Some(origin));
+        assert_eq!(Some(origin), shadow.load(0xC, 0x22, 1, set));
+        shadow.store(0x20, 2, None);
+        assert_eq!(None, shadow.load(0xC, 0x20, 2, set));
+        assert_eq!(Some(origin), shadow.load(0xC, 0x20, 4, set));
+        shadow.clear();
+        assert_eq!(None, shadow.load(0xC, 0x20, 4, set));
+    }
+
+    #[test]
+    fn reports() {
+        let mut shadow = Shadow::default();
+        assert_eq!(
+            Some(String::from(
+                "[0x00000010] Warning: Undefined value used in a branch condition: x2 (sp) has not been written since reset."
+            )),
+            shadow.report(0x10, Use::Branch, Origin::Reset(2))
+        );
+        // once for each instruction
+        assert_eq!(None, shadow.report(0x10, Use::Address, Origin::Reset(3)));
+        assert_eq!(
+            "loaded from unset memory at 0x00000100 by the instruction at 0x00000000",
+            Origin::Load { pc: 0, addr: 0x100 }.to_string()
+        );
+    }
+}
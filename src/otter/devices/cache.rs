@@ -0,0 +1,371 @@
+// A model of a set-associative cache in front of main memory. It keeps
+// only tags, not data, so it counts hits and misses and the cycles spent
+// waiting on memory but never changes what a program reads.
+//
+// A miss costs the miss penalty to fetch the line, and replacing a dirty
+// line costs it again to write that line back. A write-back cache fills
+// lines on write misses and marks lines dirty. A write-through cache writes
+// every store to memory, through a write buffer that costs nothing, so its
+// lines are never dirty and write misses leave the cache as it was.
+
+use super::super::super::util::parse::parse_int;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Replacement {
+    // the line used longest ago
+    Lru,
+    // the line filled longest ago
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CacheConfig {
+    // in bytes
+    pub size: u32,
+    pub line: u32,
+    pub ways: u32,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    // cycles to fetch or write back a line
+    pub penalty: u64,
+}
+
+impl Default for CacheConfig {
+    // 1 kB, direct-mapped, with 16-byte lines
+    fn default() -> CacheConfig {
+        CacheConfig {
+            size: 1024,
+            line: 16,
+            ways: 1,
+            replacement: Replacement::Lru,
+            write: WritePolicy::WriteBack,
+            penalty: 0,
+        }
+    }
+}
+
+impl CacheConfig {
+    // comma-separated settings over the default, any of
+    // 'size=N,line=N,ways=N,replace=lru|fifo|random,write=back|through,penalty=N',
+    // e.g. 'size=4096,ways=2,penalty=10'
+    pub fn parse(s: &str) -> Result<CacheConfig, String> {
+        let mut c = CacheConfig::default();
+        for setting in s.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            let (key, value) = match setting.find('=') {
+                Some(i) => (&setting[..i], &setting[i + 1..]),
+                None => return Err(format!("expected KEY=VALUE, found '{}'", setting)),
+            };
+            let int = || parse_int(value).map_err(|e| format!("{}: {}", setting, e));
+            match key {
+                "size" => c.size = int()?,
+                "line" => c.line = int()?,
+                "ways" => c.ways = int()?,
+                "penalty" => c.penalty = int()? as u64,
+                "replace" => {
+                    c.replacement = match value {
+                        "lru" => Replacement::Lru,
+                        "fifo" => Replacement::Fifo,
+                        "random" => Replacement::Random,
+                        _ => return Err(format!("unknown replacement policy '{}'", value)),
+                    }
+                }
+                "write" => {
+                    c.write = match value {
+                        "back" => WritePolicy::WriteBack,
+                        "through" => WritePolicy::WriteThrough,
+                        _ => return Err(format!("unknown write policy '{}'", value)),
+                    }
+                }
+                _ => return Err(format!("unknown setting '{}'", key)),
+            }
+        }
+        for (name, n) in [("size", c.size), ("line", c.line), ("ways", c.ways)].iter() {
+            if !n.is_power_of_two() {
+                return Err(format!("{} must be a power of two, not {}", name, n));
+            }
+        }
+        if c.line as u64 * c.ways as u64 > c.size as u64 {
+            return Err(format!(
+                "{} ways of {}-byte lines do not fit in {} bytes",
+                c.ways, c.line, c.size
+            ));
+        }
+        Ok(c)
+    }
+
+    fn sets(&self) -> u32 {
+        self.size / (self.line * self.ways)
+    }
+}
+
+impl fmt::Display for CacheConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes, {}-byte lines, {}-way, {}, {}, {}-cycle miss penalty",
+            self.size,
+            self.line,
+            self.ways,
+            match self.replacement {
+                Replacement::Lru => "LRU",
+                Replacement::Fifo => "FIFO",
+                Replacement::Random => "random",
+            },
+            match self.write {
+                WritePolicy::WriteBack => "write-back",
+                WritePolicy::WriteThrough => "write-through",
+            },
+            self.penalty
+        )
+    }
+}
+
+// accesses, counted by line, so one that spans two lines counts twice
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Stats {
+    pub reads: u64,
+    pub read_misses: u64,
+    pub writes: u64,
+    pub write_misses: u64,
+    // dirty lines written back to memory
+    pub writebacks: u64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    // when it was last used, for LRU, or filled, for FIFO
+    time: u64,
+}
+
+pub struct Cache {
+    config: CacheConfig,
+    // 'ways' lines for each set
+    lines: Vec<Line>,
+    stats: Stats,
+    // accesses so far
+    time: u64,
+    // xorshift state for random replacement, the same every run
+    seed: u32,
+}
+
+impl Cache {
+    pub fn new(config: CacheConfig) -> Cache {
+        Cache {
+            config,
+            lines: vec![Line::default(); (config.sets() * config.ways) as usize],
+            stats: Stats::default(),
+            time: 0,
+            seed: 1,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    // empty the cache and clear its counts
+    pub fn reset(&mut self) {
+        *self = Cache::new(self.config);
+    }
+
+    // read or write 'bytes' bytes at 'addr'; returns the cycles of miss
+    // penalty it costs
+    pub fn access(&mut self, addr: u32, bytes: u32, write: bool) -> u64 {
+        let first = addr / self.config.line;
+        let last = addr.wrapping_add(bytes - 1) / self.config.line;
+        let mut cycles = self.access_line(first, write);
+        if last != first {
+            cycles += self.access_line(last, write);
+        }
+        cycles
+    }
+
+    fn access_line(&mut self, block: u32, write: bool) -> u64 {
+        self.time += 1;
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+        let sets = self.config.sets();
+        let ways = self.config.ways as usize;
+        let (set, tag) = ((block % sets) as usize, block / sets);
+        let lines = &mut self.lines[set * ways..(set + 1) * ways];
+        let write_back = self.config.write == WritePolicy::WriteBack;
+
+        if let Some(line) = lines.iter_mut().find(|l| l.valid && l.tag == tag) {
+            if self.config.replacement == Replacement::Lru {
+                line.time = self.time;
+            }
+            line.dirty |= write && write_back;
+            return 0;
+        }
+
+        if write {
+            self.stats.write_misses += 1;
+            if !write_back {
+                return 0;
+            }
+        } else {
+            self.stats.read_misses += 1;
+        }
+        let victim = match lines.iter().position(|l| !l.valid) {
+            Some(i) => i,
+            None => match self.config.replacement {
+                Replacement::Lru | Replacement::Fifo => {
+                    (0..ways).min_by_key(|i| lines[*i].time).unwrap()
+                }
+                Replacement::Random => {
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 17;
+                    self.seed ^= self.seed << 5;
+                    self.seed as usize % ways
+                }
+            },
+        };
+        let mut cycles = self.config.penalty;
+        if lines[victim].dirty {
+            self.stats.writebacks += 1;
+            cycles += self.config.penalty;
+        }
+        lines[victim] = Line {
+            valid: true,
+            dirty: write,
+            tag,
+            time: self.time,
+        };
+        cycles
+    }
+
+    // the configuration and counts, e.g.
+    //     Data cache: 1024 bytes, 16-byte lines, 1-way, LRU, write-back, ...
+    //         reads          40  hits         30  misses         10   75.0% hit rate
+    pub fn report(&self, name: &str) -> String {
+        let s = self.stats;
+        let line = |what: &str, n: u64, misses: u64| {
+            format!(
+                "    {:<6} {:>10}  hits {:>10}  misses {:>10}  {:>5.1}% hit rate\n",
+                what,
+                n,
+                n - misses,
+                misses,
+                100.0 * (n - misses) as f64 / n.max(1) as f64
+            )
+        };
+        let mut out = format!("{}: {}\n", name, self.config);
+        out += &line("reads", s.reads, s.read_misses);
+        if s.writes > 0 {
+            out += &line("writes", s.writes, s.write_misses);
+        }
+        if s.writebacks > 0 {
+            out += &format!("    {} lines written back\n", s.writebacks);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(s: &str) -> CacheConfig {
+        CacheConfig::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(CacheConfig::default(), config(""));
+        let c = config("size=0x1000, ways=4, replace=fifo, write=through, penalty=10");
+        assert_eq!(0x1000, c.size);
+        assert_eq!(4, c.ways);
+        assert_eq!(Replacement::Fifo, c.replacement);
+        assert_eq!(WritePolicy::WriteThrough, c.write);
+        assert_eq!(10, c.penalty);
+        assert_eq!(
+            "4096 bytes, 16-byte lines, 4-way, FIFO, write-through, 10-cycle miss penalty",
+            c.to_string()
+        );
+        assert_eq!(
+            Err(String::from("line must be a power of two, not 12")),
+            CacheConfig::parse("line=12")
+        );
+        assert_eq!(
+            Err(String::from(
+                "4 ways of 16-byte lines do not fit in 32 bytes"
+            )),
+            CacheConfig::parse("size=32,ways=4")
+        );
+        assert!(CacheConfig::parse("size").is_err());
+        assert!(CacheConfig::parse("replace=mru").is_err());
+        assert!(CacheConfig::parse("colour=blue").is_err());
+    }
+
+    #[test]
+    fn direct_mapped() {
+        // 4 lines of 16 bytes
+        let mut c = Cache::new(config("size=64,penalty=10"));
+        assert_eq!(10, c.access(0x0, 4, false));
+        assert_eq!(0, c.access(0xC, 4, false));
+        // a different tag in the same set
+        assert_eq!(10, c.access(0x40, 4, false));
+        assert_eq!(10, c.access(0x0, 4, false));
+        // spanning two lines
+        assert_eq!(10, c.access(0xE, 4, false));
+        let s = c.stats();
+        assert_eq!((6, 4), (s.reads, s.read_misses));
+    }
+
+    #[test]
+    fn replacement() {
+        // one set of two ways
+        let run = |replace: &str| {
+            let mut c = Cache::new(config(&format!("size=32,ways=2,replace={}", replace)));
+            for addr in [0x0, 0x100, 0x0, 0x200, 0x0].iter() {
+                c.access(*addr, 4, false);
+            }
+            c.stats().read_misses
+        };
+        // LRU keeps 0x0, as it was used since 0x100 was filled
+        assert_eq!(3, run("lru"));
+        // FIFO replaces it, as it was filled first
+        assert_eq!(4, run("fifo"));
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut c = Cache::new(config("size=16,penalty=5"));
+        // write-back fills on a miss, and writes the dirty line back later
+        assert_eq!(5, c.access(0x0, 4, true));
+        assert_eq!(10, c.access(0x10, 4, false));
+        assert_eq!(1, c.stats().writebacks);
+        c.reset();
+        assert_eq!(Stats::default(), c.stats());
+
+        let mut c = Cache::new(config("size=16,write=through,penalty=5"));
+        assert_eq!(0, c.access(0x0, 4, true));
+        assert_eq!(5, c.access(0x0, 4, false));
+        assert_eq!(0, c.access(0x0, 4, true));
+        assert_eq!(5, c.access(0x10, 4, false));
+        let s = c.stats();
+        assert_eq!((2, 1, 0), (s.writes, s.write_misses, s.writebacks));
+        assert_eq!(
+            "I-cache: 16 bytes, 16-byte lines, 1-way, LRU, write-through, 5-cycle miss penalty
+    reads           2  hits          0  misses          2    0.0% hit rate
+    writes          2  hits          1  misses          1   50.0% hit rate
+",
+            c.report("I-cache")
+        );
+    }
+}
@@ -0,0 +1,152 @@
+use super::super::snapshot;
+use std::collections::HashMap;
+
+// machine-mode CSR addresses
+pub const MSTATUS: u32 = 0x300;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const MHARTID: u32 = 0xF14;
+
+// user-mode counters, read-only
+pub const CYCLE: u32 = 0xC00;
+pub const INSTRET: u32 = 0xC02;
+pub const CYCLEH: u32 = 0xC80;
+pub const INSTRETH: u32 = 0xC82;
+
+// mstatus fields
+pub const MSTATUS_MIE: u32 = 0b1 << 3;
+pub const MSTATUS_MPIE: u32 = 0b1 << 7;
+
+// mtvec mode: interrupts jump to BASE + 4 * code
+pub const MTVEC_VECTORED: u32 = 0b01;
+
+const IMPLEMENTED: [u32; 13] = [
+    MSTATUS, MIE, MTVEC, MSCRATCH, MEPC, MCAUSE, MTVAL, MIP, MHARTID, CYCLE, INSTRET, CYCLEH,
+    INSTRETH,
+];
+
+// name of an implemented CSR, for disassembly
+pub fn name(addr: u32) -> Option<&'static str> {
+    Some(match addr {
+        MSTATUS => "mstatus",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MHARTID => "mhartid",
+        CYCLE => "cycle",
+        INSTRET => "instret",
+        CYCLEH => "cycleh",
+        INSTRETH => "instreth",
+        _ => return None,
+    })
+}
+
+pub struct CSRFile {
+    mem: HashMap<u32, u32>,
+}
+
+impl CSRFile {
+    pub fn init() -> CSRFile {
+        let mut mem = HashMap::new();
+        for addr in IMPLEMENTED.iter() {
+            mem.insert(*addr, 0);
+        }
+        CSRFile { mem }
+    }
+
+    pub fn reset(&mut self) {
+        for i in self.mem.values_mut() {
+            *i = 0;
+        }
+    }
+
+    // read a CSR, None if it is not implemented
+    pub fn rd(&self, addr: u32) -> Option<u32> {
+        self.mem.get(&addr).copied()
+    }
+
+    // write a CSR, returns false if it is not implemented or read-only
+    // CSRs with address bits [11:10] set are read-only
+    pub fn wr(&mut self, addr: u32, data: u32) -> bool {
+        if addr >> 10 == 0b11 {
+            return false;
+        }
+        match self.mem.get_mut(&addr) {
+            None => false,
+            Some(d) => {
+                *d = data;
+                true
+            }
+        }
+    }
+
+    // update the counters, which the program can only read
+    pub fn set_counters(&mut self, cycles: u64, instret: u64) {
+        self.mem.insert(CYCLE, cycles as u32);
+        self.mem.insert(CYCLEH, (cycles >> 32) as u32);
+        self.mem.insert(INSTRET, instret as u32);
+        self.mem.insert(INSTRETH, (instret >> 32) as u32);
+    }
+
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        let mut addrs: Vec<&u32> = self.mem.keys().collect();
+        addrs.sort();
+        w.u32(addrs.len() as u32);
+        for addr in addrs {
+            w.u32(*addr);
+            w.u32(self.mem[addr]);
+        }
+    }
+
+    // read-only CSRs are restored too; unimplemented ones are an error
+    pub fn restore(r: &mut snapshot::Reader) -> Result<CSRFile, String> {
+        let mut csr = CSRFile::init();
+        for _ in 0..r.u32()? {
+            let addr = r.u32()?;
+            let data = r.u32()?;
+            match csr.mem.get_mut(&addr) {
+                None => return Err(format!("snapshot has unknown CSR {:#05X}", addr)),
+                Some(d) => *d = data,
+            }
+        }
+        Ok(csr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rd_wr() {
+        let mut csr = CSRFile::init();
+        assert!(csr.wr(MTVEC, 0x100));
+        assert_eq!(Some(0x100), csr.rd(MTVEC));
+        csr.reset();
+        assert_eq!(Some(0), csr.rd(MTVEC));
+    }
+
+    #[test]
+    fn unimplemented() {
+        let mut csr = CSRFile::init();
+        assert_eq!(None, csr.rd(0x7C0));
+        assert!(!csr.wr(0x7C0, 1));
+        // mhartid is read-only
+        assert!(!csr.wr(MHARTID, 1));
+        assert_eq!(Some(0), csr.rd(MHARTID));
+        assert!(!csr.wr(CYCLE, 1));
+        csr.set_counters(0x1_0000_0002, 3);
+        assert_eq!(Some(2), csr.rd(CYCLE));
+        assert_eq!(Some(1), csr.rd(CYCLEH));
+        assert_eq!(Some(3), csr.rd(INSTRET));
+    }
+}
@@ -1,15 +1,269 @@
+use super::super::super::util::parse::parse_int;
+use super::super::snapshot;
+use super::cache::Cache;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fmt;
 
 // The size of the normal memory is 64 kB for the text and data sections.
 // Of course, the address space extends far beyond this.
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Size {
     Byte,
     HalfWord,
     Word,
 }
 
+impl Size {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Size::Byte => 1,
+            Size::HalfWord => 2,
+            Size::Word => 4,
+        }
+    }
+
+    // the bits a value of this size has
+    pub fn mask(self) -> u32 {
+        match self {
+            Size::Byte => 0xFF,
+            Size::HalfWord => 0xFFFF,
+            Size::Word => 0xFFFFFFFF,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // reads and writes
+    Access,
+}
+
+// stop when the program accesses any byte from 'begin' up to 'end'
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub begin: u32,
+    pub end: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    // parse 'ADDR' for the word at ADDR, or 'BEGIN:END' for a range of bytes
+    pub fn parse(kind: WatchKind, s: &str) -> Result<Watchpoint, String> {
+        let int = |s: &str| parse_int(s.trim()).map_err(|e| format!("{}: {}", s, e));
+        let (begin, end) = match s.find(':') {
+            None => {
+                let addr = int(s)?;
+                (addr, addr.wrapping_add(4))
+            }
+            Some(i) => (int(&s[..i])?, int(&s[i + 1..])?),
+        };
+        if end <= begin {
+            return Err(format!("empty range {}", s));
+        }
+        Ok(Watchpoint { begin, end, kind })
+    }
+
+    fn matches(&self, addr: u32, bytes: u32, write: bool) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        kind && addr < self.end && addr.wrapping_add(bytes) > self.begin
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        write!(f, "{} {:#010X}:{:#010X}", kind, self.begin, self.end)
+    }
+}
+
+// an access that triggered a watchpoint
+// 'old' and 'new' are the same for reads
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WatchHit {
+    pub addr: u32,
+    pub write: bool,
+    pub old: u32,
+    pub new: u32,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.write {
+            write!(
+                f,
+                "wrote {:#010X} over {:#010X} at {:#010X}",
+                self.new, self.old, self.addr
+            )
+        } else {
+            write!(f, "read {:#010X} from {:#010X}", self.new, self.addr)
+        }
+    }
+}
+
+// what the program may do with a region of memory
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Perms {
+    pub read: bool,
+    pub write: bool,
+    pub exec: bool,
+}
+
+impl Perms {
+    pub const RW: Perms = Perms {
+        read: true,
+        write: true,
+        exec: false,
+    };
+    pub const RX: Perms = Perms {
+        read: true,
+        write: false,
+        exec: true,
+    };
+    pub const RWX: Perms = Perms {
+        read: true,
+        write: true,
+        exec: true,
+    };
+
+    fn allows(self, kind: AccessKind) -> bool {
+        match kind {
+            AccessKind::Read => self.read,
+            AccessKind::Write => self.write,
+            AccessKind::Exec => self.exec,
+        }
+    }
+
+    // as bits 2 (r), 1 (w) and 0 (x), like ELF segment flags
+    pub fn bits(self) -> u32 {
+        (self.read as u32) << 2 | (self.write as u32) << 1 | self.exec as u32
+    }
+
+    pub fn from_bits(bits: u32) -> Perms {
+        Perms {
+            read: bits & 0b100 != 0,
+            write: bits & 0b010 != 0,
+            exec: bits & 0b001 != 0,
+        }
+    }
+}
+
+// e.g. 'r-x'
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |on: bool, c: char| if on { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.exec, 'x')
+        )
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    // instruction fetch
+    Exec,
+}
+
+// main memory from 'begin' up to 'end' with its own permissions
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Region {
+    pub begin: u32,
+    pub end: u32,
+    pub perms: Perms,
+}
+
+// an access the permissions do not allow
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Fault {
+    pub addr: u32,
+    pub kind: AccessKind,
+    pub perms: Perms,
+    // the region the access hit, None for MMIO or memory outside every region
+    pub region: Option<Region>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            AccessKind::Read => "read from",
+            AccessKind::Write => "write to",
+            AccessKind::Exec => "execute",
+        };
+        write!(f, "cannot {} {:#010X}, ", kind, self.addr)?;
+        match self.region {
+            Some(r) => write!(
+                f,
+                "in {} memory at {:#010X}:{:#010X}",
+                self.perms, r.begin, r.end
+            ),
+            None => write!(f, "in {} memory", self.perms),
+        }
+    }
+}
+
+// where the stack and the program's code are, to catch a stack pointer
+// that runs out of the stack and stores that overwrite the program
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StackGuard {
+    // the stack grows down from 'stack_top' to 'stack_bottom'
+    pub stack_bottom: u32,
+    pub stack_top: u32,
+    // the code, from 'text_begin' up to 'text_end'
+    pub text_begin: u32,
+    pub text_end: u32,
+    // stop on a violation, rather than only warning
+    pub stop: bool,
+}
+
+impl StackGuard {
+    fn in_stack(&self, sp: u32) -> bool {
+        sp >= self.stack_bottom && sp <= self.stack_top
+    }
+
+    fn in_text(&self, addr: u32, bytes: u32) -> bool {
+        addr < self.text_end && addr.wrapping_add(bytes) > self.text_begin
+    }
+}
+
+// something the stack guard caught
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Violation {
+    // the stack pointer was set to this, outside the stack
+    StackPointer(u32),
+    // a store to this address, in the text section
+    TextStore(u32),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::StackPointer(sp) => {
+                write!(f, "stack pointer {:#010X} is outside the stack", sp)
+            }
+            Violation::TextStore(addr) => {
+                write!(f, "store to {:#010X} in the text section", addr)
+            }
+        }
+    }
+}
+
 // MMIO device
 struct IODevice {
     size: u32,
@@ -135,6 +389,19 @@ impl MMIO {
     }
 }
 
+impl MMIO {
+    // every device in address order
+    fn save(&self, w: &mut snapshot::Writer) {
+        w.u32(self.addrs.len() as u32);
+        for addr in self.addrs.iter() {
+            let device = &self.devices[addr];
+            w.u32(*addr);
+            w.u32(device.size);
+            w.bytes(&device.contents);
+        }
+    }
+}
+
 // main memory
 pub struct RAM {
     mem: Vec<Option<u8>>,
@@ -196,6 +463,33 @@ impl RAM {
         data
     }
 
+    // which bytes are initialized as a bitmap, then every byte
+    fn save(&self, w: &mut snapshot::Writer) {
+        w.u32(self.size);
+        let mut init = vec![0; (self.size as usize).div_ceil(8)];
+        for (i, b) in self.mem.iter().enumerate() {
+            if b.is_some() {
+                init[i / 8] |= 1 << (i % 8);
+            }
+        }
+        w.bytes(&init);
+        let data: Vec<u8> = self.mem.iter().map(|b| b.unwrap_or(0)).collect();
+        w.bytes(&data);
+    }
+
+    fn restore(r: &mut snapshot::Reader) -> Result<RAM, String> {
+        let size = r.u32()?;
+        let init = r.bytes((size as usize).div_ceil(8))?;
+        let data = r.bytes(size as usize)?;
+        let mut ram = RAM::new(size);
+        for (i, b) in ram.mem.iter_mut().enumerate() {
+            if init[i / 8] & (1 << (i % 8)) != 0 {
+                *b = Some(data[i]);
+            }
+        }
+        Ok(ram)
+    }
+
     // write some data
     fn wr(&mut self, addr: u32, data: u32, size: Size) {
         if addr >= self.size {
@@ -225,6 +519,23 @@ pub struct Memory {
     main: RAM,
     mmio: MMIO,
     mmio_begin: u32,
+    watchpoints: Vec<Watchpoint>,
+    // the last access to hit a watchpoint; set by rd, which does not take &mut
+    hit: Cell<Option<WatchHit>>,
+    guard: Option<StackGuard>,
+    // the last violation of the guard, if it stops the program
+    violation: Option<Violation>,
+    // main memory with its own permissions; later regions override earlier ones
+    regions: Vec<Region>,
+    // false to allow any access, as if there were no regions
+    protect: bool,
+    // warn on reads of unset main memory
+    warn_unset: bool,
+    // models of the caches in front of main memory, if any
+    icache: Option<Cache>,
+    dcache: Option<Cache>,
+    // cycles of miss penalty since the last call to take_stall
+    stall: u64,
 }
 
 impl Memory {
@@ -236,10 +547,22 @@ impl Memory {
             main: RAM::new(main_size),
             mmio: MMIO::new(),
             mmio_begin: 0xFFFFFFFF,
+            watchpoints: Vec::new(),
+            hit: Cell::new(None),
+            guard: None,
+            violation: None,
+            regions: Vec::new(),
+            protect: true,
+            warn_unset: true,
+            icache: None,
+            dcache: None,
+            stall: 0,
         }
     }
 
     // program the memory with a binary
+    // a raw binary mixes code and data, so all of it stays writable and
+    // executable, and only the rest of memory is read-write
     pub fn prog(&mut self, binary: Vec<Vec<u8>>) {
         let binary_size = binary.len() * 4;
         if binary_size >= self.main.size as usize {
@@ -256,7 +579,7 @@ impl Memory {
             for (byte_offset, &byte) in word.iter().enumerate() {
                 // combine the word address and byte offset as {word_addr[31:2], byte_offset[1:0]}
                 // write the byte to this address at byte granularity
-                self.wr(
+                self.poke(
                     ((word_addr << 2) + byte_offset) as u32,
                     byte as u32,
                     Size::Byte,
@@ -264,6 +587,11 @@ impl Memory {
                 )
             }
         }
+        self.set_regions(vec![Region {
+            begin: 0,
+            end: binary_size as u32,
+            perms: Perms::RWX,
+        }]);
     }
 
     // map an IO device to 'addr' that contains 'size' bytes
@@ -282,12 +610,341 @@ impl Memory {
         self.mmio_begin = self.mmio.addrs[0];
     }
 
-    // read from the correct region of memory
+    // unmap the IO device at 'addr'
+    pub fn remove_io(&mut self, addr: u32) {
+        if self.mmio.devices.remove(&addr).is_some() {
+            self.mmio.addrs.retain(|a| *a != addr);
+            self.mmio_begin = self.mmio.addrs.first().copied().unwrap_or(0xFFFFFFFF);
+        }
+    }
+
+    // the byte at 'addr' as stored, None if it is uninitialized or unmapped
+    pub fn byte(&self, addr: u32) -> Option<u8> {
+        if addr < self.main.size {
+            self.main.mem[addr as usize]
+        } else if self.mmio.match_addr_to_key(addr).is_some() {
+            Some(self.mmio.rd(addr, Size::Byte) as u8)
+        } else {
+            None
+        }
+    }
+
+    // put back a byte as returned by byte()
+    pub fn set_byte(&mut self, addr: u32, byte: Option<u8>) {
+        if addr < self.main.size {
+            self.main.mem[addr as usize] = byte;
+        } else if let Some(b) = byte {
+            self.mmio.wr(addr, b as u32, Size::Byte);
+        }
+    }
+
+    // the size of main memory
+    pub fn size(&self) -> u32 {
+        self.main.size
+    }
+
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        self.main.save(w);
+        self.mmio.save(w);
+        w.u32(self.regions.len() as u32);
+        for r in self.regions.iter() {
+            w.u32(r.begin);
+            w.u32(r.end);
+            w.u32(r.perms.bits());
+        }
+    }
+
+    pub fn restore(r: &mut snapshot::Reader) -> Result<Memory, String> {
+        let mut mem = Memory {
+            main: RAM::restore(r)?,
+            mmio: MMIO::new(),
+            mmio_begin: 0xFFFFFFFF,
+            watchpoints: Vec::new(),
+            hit: Cell::new(None),
+            guard: None,
+            violation: None,
+            regions: Vec::new(),
+            protect: true,
+            warn_unset: true,
+            icache: None,
+            dcache: None,
+            stall: 0,
+        };
+        for _ in 0..r.u32()? {
+            let addr = r.u32()?;
+            let size = r.u32()?;
+            let contents = r.bytes(size as usize)?;
+            if mem.is_mapped(addr) {
+                return Err(format!("snapshot has overlapping IO at {:#010X}", addr));
+            }
+            mem.add_io(addr, size);
+            mem.mmio.devices.get_mut(&addr).unwrap().contents = contents.to_vec();
+        }
+        let mut regions = Vec::new();
+        for _ in 0..r.u32()? {
+            regions.push(Region {
+                begin: r.u32()?,
+                end: r.u32()?,
+                perms: Perms::from_bits(r.u32()?),
+            });
+        }
+        mem.set_regions(regions);
+        Ok(mem)
+    }
+
+    // check if an address is backed by main memory or an IO device
+    pub fn is_mapped(&self, addr: u32) -> bool {
+        addr < self.main.size || self.mmio.match_addr_to_key(addr).is_some()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // the access that hit a watchpoint since the last call, if any
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    // give main memory permissions by region; the rest of it is read-write,
+    // unless there are no regions at all, as before a program is loaded
+    pub fn set_regions(&mut self, regions: Vec<Region>) {
+        self.regions = regions;
+    }
+
+    // give part of memory other permissions, e.g. to write-protect code
+    pub fn add_region(&mut self, region: Region) {
+        self.regions.push(region);
+    }
+
+    // warn on every read of unset main memory, or not
+    pub fn set_unset_warnings(&mut self, warn: bool) {
+        self.warn_unset = warn;
+    }
+
+    // check permissions on every access, or allow anything
+    pub fn set_protection(&mut self, protect: bool) {
+        self.protect = protect;
+    }
+
+    pub fn protection(&self) -> bool {
+        self.protect
+    }
+
+    // the permissions at 'addr', and the region they come from
+    // MMIO can be read and written but not executed; unmapped addresses
+    // are left to rd and wr to report
+    fn perms(&self, addr: u32) -> (Perms, Option<Region>) {
+        if !self.protect {
+            return (Perms::RWX, None);
+        }
+        if addr >= self.main.size {
+            return (Perms::RW, None);
+        }
+        if self.regions.is_empty() {
+            return (Perms::RWX, None);
+        }
+        match self
+            .regions
+            .iter()
+            .rev()
+            .find(|r| addr >= r.begin && addr < r.end)
+        {
+            Some(r) => (r.perms, Some(*r)),
+            None => (Perms::RW, None),
+        }
+    }
+
+    // check the program may access 'size' bytes at 'addr'
+    pub fn check(&self, addr: u32, size: Size, kind: AccessKind) -> Result<(), Fault> {
+        if !self.is_mapped(addr) {
+            return Ok(());
+        }
+        for i in 0..size.bytes() {
+            let a = addr.wrapping_add(i);
+            let (perms, region) = self.perms(a);
+            if !perms.allows(kind) {
+                return Err(Fault {
+                    addr,
+                    kind,
+                    perms,
+                    region,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // model instruction and data caches in front of main memory
+    pub fn set_caches(&mut self, icache: Option<Cache>, dcache: Option<Cache>) {
+        self.icache = icache;
+        self.dcache = dcache;
+    }
+
+    pub fn take_caches(&mut self) -> (Option<Cache>, Option<Cache>) {
+        (self.icache.take(), self.dcache.take())
+    }
+
+    pub fn icache(&self) -> Option<&Cache> {
+        self.icache.as_ref()
+    }
+
+    pub fn dcache(&self) -> Option<&Cache> {
+        self.dcache.as_ref()
+    }
+
+    // empty the caches and clear their counts
+    pub fn reset_caches(&mut self) {
+        for cache in self.icache.iter_mut().chain(self.dcache.iter_mut()) {
+            cache.reset();
+        }
+        self.stall = 0;
+    }
+
+    // pass an access by the program through the caches; IO is not cached
+    pub fn cache(&mut self, addr: u32, size: Size, kind: AccessKind) {
+        if addr >= self.main.size {
+            return;
+        }
+        let cache = match kind {
+            AccessKind::Exec => &mut self.icache,
+            AccessKind::Read | AccessKind::Write => &mut self.dcache,
+        };
+        if let Some(cache) = cache {
+            self.stall += cache.access(addr, size.bytes(), kind == AccessKind::Write);
+        }
+    }
+
+    // the cycles of miss penalty since the last call
+    pub fn take_stall(&mut self) -> u64 {
+        std::mem::replace(&mut self.stall, 0)
+    }
+
+    // check stores and the stack pointer against 'guard', or stop with None
+    pub fn set_guard(&mut self, guard: Option<StackGuard>) {
+        self.guard = guard;
+    }
+
+    pub fn guard(&self) -> Option<StackGuard> {
+        self.guard
+    }
+
+    // the violation that stops the program since the last call, if any
+    pub fn take_violation(&mut self) -> Option<Violation> {
+        self.violation.take()
+    }
+
+    // check a new value of the stack pointer against the guard
+    pub fn check_sp<L>(&mut self, sp: u32, logger: L)
+    where
+        L: Fn(&str),
+    {
+        if let Some(guard) = self.guard {
+            if !guard.in_stack(sp) {
+                self.violate(guard, Violation::StackPointer(sp), logger);
+            }
+        }
+    }
+
+    fn violate<L>(&mut self, guard: StackGuard, violation: Violation, logger: L)
+    where
+        L: Fn(&str),
+    {
+        let (begin, end) = match violation {
+            Violation::StackPointer(_) => (guard.stack_bottom, guard.stack_top),
+            Violation::TextStore(_) => (guard.text_begin, guard.text_end),
+        };
+        logger(&format!(
+            "Warning: Stack guard: {} ({:#010X}:{:#010X}).",
+            violation, begin, end
+        ));
+        if guard.stop {
+            self.violation = Some(violation);
+        }
+    }
+
+    // read as the program would, checking watchpoints
     pub fn rd<L>(&self, addr: u32, size: Size, logger: L) -> u32
     where
         L: Fn(&str),
     {
-        if addr < self.main.size {
+        let data = self.peek(addr, size, logger);
+        self.watch_read(addr, size, data);
+        data
+    }
+
+    // check watchpoints for a read of 'data' at 'addr' that did not go
+    // through rd, as from a device's register
+    pub fn watch_read(&self, addr: u32, size: Size, data: u32) {
+        if self
+            .watchpoints
+            .iter()
+            .any(|w| w.matches(addr, size.bytes(), false))
+        {
+            self.hit.set(Some(WatchHit {
+                addr,
+                write: false,
+                old: data,
+                new: data,
+            }));
+        }
+    }
+
+    // write as the program would, checking watchpoints and the stack guard
+    pub fn wr<L>(&mut self, addr: u32, data: u32, size: Size, logger: L)
+    where
+        L: Fn(&str),
+    {
+        if let Some(guard) = self.guard {
+            if guard.in_text(addr, size.bytes()) {
+                self.violate(guard, Violation::TextStore(addr), &logger);
+            }
+        }
+        if self
+            .watchpoints
+            .iter()
+            .any(|w| w.matches(addr, size.bytes(), true))
+        {
+            // what was there, read quietly with unset bytes as zero
+            let mut old = 0;
+            for i in 0..size.bytes() {
+                let b = self.byte(addr.wrapping_add(i)).unwrap_or(0);
+                old |= (b as u32) << (8 * i);
+            }
+            self.poke(addr, data, size, logger);
+            let mask = match size {
+                Size::Byte => 0xFF,
+                Size::HalfWord => 0xFFFF,
+                Size::Word => 0xFFFFFFFF,
+            };
+            self.hit.set(Some(WatchHit {
+                addr,
+                write: true,
+                old,
+                new: data & mask,
+            }));
+        } else {
+            self.poke(addr, data, size, logger);
+        }
+    }
+
+    // read from the correct region of memory, without checking watchpoints;
+    // for instruction fetch, the debugger and devices
+    pub fn peek<L>(&self, addr: u32, size: Size, logger: L) -> u32
+    where
+        L: Fn(&str),
+    {
+        if addr < self.main.size && !self.warn_unset {
+            self.main.rd(addr, size, |_s| {})
+        } else if addr < self.main.size {
             self.main.rd(addr, size, logger)
         } else if addr >= self.mmio_begin {
             self.mmio.rd(addr, size)
@@ -300,8 +957,8 @@ impl Memory {
         }
     }
 
-    // write to the correct region of memory
-    pub fn wr<L>(&mut self, addr: u32, data: u32, size: Size, logger: L)
+    // write to the correct region of memory, without checking watchpoints
+    pub fn poke<L>(&mut self, addr: u32, data: u32, size: Size, logger: L)
     where
         L: Fn(&str),
     {
@@ -451,4 +1108,153 @@ mod tests {
         mem.wr(0x1000, 12, Size::Byte, |_s| {});
         assert_eq!(12, mem.rd(0x1000, Size::Byte, |_s| {}));
     }
+
+    #[test]
+    fn snapshot() {
+        let mut mem = Memory::new(0x1000);
+        mem.add_io(0x11000000, 2);
+        mem.add_io(0x11080000, 4);
+        mem.wr(0x10, 0xAB, Size::Byte, |_s| {});
+        mem.wr(0x21, 0x00, Size::Byte, |_s| {});
+        mem.wr(0x11080000, 0x12345678, Size::Word, |_s| {});
+
+        let mut w = snapshot::Writer::new();
+        mem.save(&mut w);
+        let mut r = snapshot::Reader::new(&w.buf).unwrap();
+        let restored = Memory::restore(&mut r).unwrap();
+        r.finish().unwrap();
+
+        assert_eq!(0x1000, restored.size());
+        for addr in 0..0x1000 {
+            assert_eq!(mem.main.mem[addr], restored.main.mem[addr]);
+        }
+        assert_eq!(None, restored.main.mem[0x20]);
+        assert_eq!(Some(0), restored.main.mem[0x21]);
+        assert_eq!(0x12345678, restored.rd(0x11080000, Size::Word, |_s| {}));
+        assert!(restored.is_mapped(0x11000001));
+        assert_eq!(0x11000000, restored.mmio_begin);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut mem = Memory::new(0x1000);
+        mem.add_io(0x11080000, 2);
+        mem.add_watchpoint(Watchpoint::parse(WatchKind::Write, "0x100").unwrap());
+        mem.add_watchpoint(Watchpoint::parse(WatchKind::Read, "0x11080000:0x11080002").unwrap());
+
+        // neighbours and the wrong kind of access do not hit
+        mem.wr(0xFC, 0x1, Size::Word, |_s| {});
+        mem.wr(0x104, 0x1, Size::Byte, |_s| {});
+        mem.rd(0x100, Size::Word, |_s| {});
+        mem.wr(0x11080000, 0x3, Size::HalfWord, |_s| {});
+        assert_eq!(None, mem.take_hit());
+
+        mem.wr(0x100, 0xAABBCCDD, Size::Word, |_s| {});
+        mem.wr(0x102, 0x1234, Size::HalfWord, |_s| {});
+        let hit = WatchHit {
+            addr: 0x102,
+            write: true,
+            old: 0xAABB,
+            new: 0x1234,
+        };
+        assert_eq!(Some(hit), mem.take_hit());
+        assert_eq!(None, mem.take_hit());
+        assert_eq!(
+            "wrote 0x00001234 over 0x0000AABB at 0x00000102",
+            hit.to_string()
+        );
+
+        // partly overlapping
+        mem.rd(0x1107FFFF, Size::HalfWord, |_s| {});
+        assert!(mem.take_hit().is_some());
+        mem.peek(0x11080000, Size::Word, |_s| {});
+        mem.poke(0x100, 0, Size::Word, |_s| {});
+        assert_eq!(None, mem.take_hit());
+
+        assert!(Watchpoint::parse(WatchKind::Access, "0x10:0x10").is_err());
+        assert!(Watchpoint::parse(WatchKind::Access, "zero").is_err());
+        assert_eq!(
+            "access 0x00000010:0x00000020",
+            Watchpoint::parse(WatchKind::Access, "0x10:0x20")
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn regions() {
+        let mut mem = Memory::new(0x1000);
+        mem.add_io(0x11000000, 4);
+        // anything goes before a program is loaded, except executing IO
+        assert_eq!(Ok(()), mem.check(0x800, Size::Word, AccessKind::Exec));
+        let fault = mem.check(0x11000000, Size::Word, AccessKind::Exec);
+        assert_eq!(
+            "cannot execute 0x11000000, in rw- memory",
+            fault.unwrap_err().to_string()
+        );
+
+        mem.prog(vec![vec![0; 4]; 4]);
+        assert_eq!(Ok(()), mem.check(0x0, Size::Word, AccessKind::Write));
+        assert!(mem.check(0x10, Size::Word, AccessKind::Exec).is_err());
+        mem.add_region(Region {
+            begin: 0x0,
+            end: 0x8,
+            perms: Perms::RX,
+        });
+        assert_eq!(Ok(()), mem.check(0x8, Size::Word, AccessKind::Write));
+        // partly overlapping
+        let fault = mem
+            .check(0x6, Size::HalfWord, AccessKind::Write)
+            .unwrap_err();
+        assert_eq!(0x6, fault.addr);
+        assert_eq!(
+            "cannot write to 0x00000006, in r-x memory at 0x00000000:0x00000008",
+            fault.to_string()
+        );
+        // unmapped addresses are left to rd and wr
+        assert_eq!(Ok(()), mem.check(0x2000, Size::Word, AccessKind::Exec));
+
+        mem.set_protection(false);
+        assert_eq!(Ok(()), mem.check(0x0, Size::Word, AccessKind::Write));
+        assert_eq!(Ok(()), mem.check(0x11000000, Size::Word, AccessKind::Exec));
+
+        // kept in snapshots
+        let mut w = snapshot::Writer::new();
+        mem.save(&mut w);
+        let mut r = snapshot::Reader::new(&w.buf).unwrap();
+        let restored = Memory::restore(&mut r).unwrap();
+        assert_eq!(mem.regions, restored.regions);
+        assert_eq!(Perms::RX, Perms::from_bits(Perms::RX.bits()));
+    }
+
+    #[test]
+    fn stack_guard() {
+        let mut mem = Memory::new(0x1000);
+        mem.set_guard(Some(StackGuard {
+            stack_bottom: 0x800,
+            stack_top: 0x1000,
+            text_begin: 0x0,
+            text_end: 0x100,
+            stop: true,
+        }));
+
+        // the stack's bounds are inclusive, sp starts at the top
+        mem.check_sp(0x800, |_s| {});
+        mem.check_sp(0x1000, |_s| {});
+        mem.wr(0x100, 0x1, Size::Word, |_s| {});
+        assert_eq!(None, mem.take_violation());
+
+        mem.check_sp(0x7FC, |_s| {});
+        assert_eq!(Some(Violation::StackPointer(0x7FC)), mem.take_violation());
+        // partly overlapping, and written anyway
+        mem.wr(0xFE, 0xABCD, Size::Word, |_s| {});
+        assert_eq!(Some(Violation::TextStore(0xFE)), mem.take_violation());
+        assert_eq!(0xABCD, mem.peek(0xFE, Size::HalfWord, |_s| {}));
+        mem.poke(0x0, 0x1, Size::Word, |_s| {});
+        assert_eq!(None, mem.take_violation());
+
+        mem.set_guard(None);
+        mem.wr(0x0, 0x1, Size::Word, |_s| {});
+        assert_eq!(None, mem.take_violation());
+    }
 }
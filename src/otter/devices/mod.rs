@@ -1,2 +1,24 @@
+pub mod cache;
+pub mod csr;
 pub mod mem;
 pub mod rf;
+pub mod timer;
+pub mod uart;
+
+// a device whose registers act when the program accesses them, and which
+// the MCU mirrors in IO memory for the debugger
+pub trait Peripheral {
+    // its word-wide registers, as offsets from its base address
+    fn registers(&self) -> &'static [u32];
+
+    // the register at 'offset', as the debugger sees it
+    fn peek(&self, offset: u32) -> u32;
+
+    // the program reading the register at 'offset'
+    fn read(&mut self, offset: u32) -> u32 {
+        self.peek(offset)
+    }
+
+    // the program writing the register at 'offset'
+    fn write(&mut self, offset: u32, data: u32);
+}
@@ -1,20 +1,33 @@
+use super::super::definedness::Origin;
+use super::super::snapshot;
+
 pub const RF_SIZE: u32 = 32;
 
 pub struct RegisterFile {
     mem: [u32; RF_SIZE as usize],
+    // shadow bits: where each undefined register's value came from, None
+    // once it holds a defined value
+    undef: [Option<Origin>; RF_SIZE as usize],
 }
 
 impl RegisterFile {
     pub fn init() -> RegisterFile {
-        RegisterFile {
+        let mut rf = RegisterFile {
             mem: [0; RF_SIZE as usize],
-        }
+            undef: [None; RF_SIZE as usize],
+        };
+        rf.reset();
+        rf
     }
 
+    // every register but x0 is zero, and undefined
     pub fn reset(&mut self) {
         for i in self.mem.iter_mut() {
             *i = 0;
         }
+        for (i, u) in self.undef.iter_mut().enumerate().skip(1) {
+            *u = Some(Origin::Reset(i as u32));
+        }
     }
 
     pub fn rd(&self, index: u32) -> u32 {
@@ -25,11 +38,40 @@ impl RegisterFile {
         }
     }
 
+    // writes a defined value
     pub fn wr(&mut self, index: u32, data: u32) {
         if index < RF_SIZE && index > 0 {
             self.mem[index as usize] = data;
+            self.undef[index as usize] = None;
         }
     }
+
+    // where the value in a register came from, if it is undefined
+    pub fn origin(&self, index: u32) -> Option<Origin> {
+        self.undef.get(index as usize).copied().flatten()
+    }
+
+    // mark the value in a register undefined, x0 excepted
+    pub fn set_origin(&mut self, index: u32, origin: Origin) {
+        if index < RF_SIZE && index > 0 {
+            self.undef[index as usize] = Some(origin);
+        }
+    }
+
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        for d in self.mem.iter() {
+            w.u32(*d);
+        }
+    }
+
+    pub fn restore(r: &mut snapshot::Reader) -> Result<RegisterFile, String> {
+        let mut rf = RegisterFile::init();
+        for i in 0..RF_SIZE {
+            let data = r.u32()?;
+            rf.wr(i, data);
+        }
+        Ok(rf)
+    }
 }
 
 #[cfg(test)]
@@ -52,6 +94,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn definedness() {
+        let mut rf = RegisterFile::init();
+        assert_eq!(None, rf.origin(0));
+        assert_eq!(Some(Origin::Reset(5)), rf.origin(5));
+        rf.wr(5, 0);
+        assert_eq!(None, rf.origin(5));
+        let origin = Origin::Load {
+            pc: 0x4,
+            addr: 0x100,
+        };
+        rf.set_origin(5, origin);
+        rf.set_origin(0, origin);
+        assert_eq!(Some(origin), rf.origin(5));
+        assert_eq!(None, rf.origin(0));
+        rf.reset();
+        assert_eq!(Some(Origin::Reset(5)), rf.origin(5));
+    }
+
     #[test]
     fn out_of_bounds() {
         let mut rf = RegisterFile::init();
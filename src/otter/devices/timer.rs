@@ -0,0 +1,156 @@
+// A timer, as five word-wide registers from its base address:
+//
+//     0x00  COUNT     the counter; writing sets it
+//     0x04  COMPARE   the match flag is set when COUNT reaches it
+//     0x08  PRESCALE  COUNT goes up once every PRESCALE + 1 ticks
+//     0x0C  CTRL      bit 0: count
+//                     bit 1: raise the timer interrupt while the match flag is set
+//                     bit 2: go back to 0 on a match, to match every COMPARE counts
+//                     bit 3: tick every cycle, instead of every instruction
+//     0x10  STATUS    bit 0: the match flag; writing 0 clears it
+//
+// It ticks as the MCU retires instructions, or as it counts cycles, which
+// include the penalties of the cache and branch predictor models.
+
+use super::Peripheral;
+
+// registers, as offsets from the base address
+pub const COUNT: u32 = 0x00;
+pub const COMPARE: u32 = 0x04;
+pub const PRESCALE: u32 = 0x08;
+pub const CTRL: u32 = 0x0C;
+pub const STATUS: u32 = 0x10;
+pub const WIDTH: u32 = 0x14;
+
+pub const CTRL_ENABLE: u32 = 0b0001;
+pub const CTRL_INTERRUPT: u32 = 0b0010;
+pub const CTRL_RESTART: u32 = 0b0100;
+pub const CTRL_CYCLES: u32 = 0b1000;
+pub const STATUS_MATCH: u32 = 0b1;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Timer {
+    count: u32,
+    compare: u32,
+    prescale: u32,
+    ctrl: u32,
+    status: u32,
+    // ticks since COUNT last went up
+    scaled: u32,
+}
+
+impl Timer {
+    // one instruction retired, taking 'cycles' cycles
+    pub fn tick(&mut self, cycles: u64) {
+        if self.ctrl & CTRL_ENABLE == 0 {
+            return;
+        }
+        let ticks = if self.ctrl & CTRL_CYCLES != 0 {
+            cycles
+        } else {
+            1
+        };
+        for _ in 0..ticks {
+            if self.scaled < self.prescale {
+                self.scaled += 1;
+                continue;
+            }
+            self.scaled = 0;
+            self.count = self.count.wrapping_add(1);
+            if self.count == self.compare {
+                self.status |= STATUS_MATCH;
+                if self.ctrl & CTRL_RESTART != 0 {
+                    self.count = 0;
+                }
+            }
+        }
+    }
+
+    // whether it is running, and its registers change as it ticks
+    pub fn enabled(&self) -> bool {
+        self.ctrl & CTRL_ENABLE != 0
+    }
+
+    // whether it is raising the timer interrupt
+    pub fn interrupt(&self) -> bool {
+        self.ctrl & CTRL_INTERRUPT != 0 && self.status & STATUS_MATCH != 0
+    }
+
+    // whether it may raise the timer interrupt later, as it counts to a match
+    pub fn can_interrupt(&self) -> bool {
+        self.ctrl & CTRL_INTERRUPT != 0 && (self.enabled() || self.status & STATUS_MATCH != 0)
+    }
+}
+
+impl Peripheral for Timer {
+    fn registers(&self) -> &'static [u32] {
+        &[COUNT, COMPARE, PRESCALE, CTRL, STATUS]
+    }
+
+    fn peek(&self, offset: u32) -> u32 {
+        match offset {
+            COUNT => self.count,
+            COMPARE => self.compare,
+            PRESCALE => self.prescale,
+            CTRL => self.ctrl,
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, data: u32) {
+        match offset {
+            COUNT => self.count = data,
+            COMPARE => self.compare = data,
+            PRESCALE => {
+                self.prescale = data;
+                self.scaled = 0;
+            }
+            CTRL => self.ctrl = data & 0b1111,
+            STATUS => self.status = data & STATUS_MATCH,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let mut timer = Timer::default();
+        timer.tick(1);
+        assert_eq!(0, timer.peek(COUNT));
+
+        timer.write(PRESCALE, 2);
+        timer.write(COMPARE, 2);
+        timer.write(CTRL, CTRL_ENABLE);
+        for _ in 0..5 {
+            timer.tick(1);
+        }
+        // once every three instructions
+        assert_eq!(1, timer.peek(COUNT));
+        timer.tick(1);
+        assert_eq!(2, timer.peek(COUNT));
+        assert_eq!(STATUS_MATCH, timer.peek(STATUS));
+        // not interrupting unless asked to
+        assert!(!timer.interrupt());
+        assert!(!timer.can_interrupt());
+        timer.write(CTRL, CTRL_ENABLE | CTRL_INTERRUPT);
+        assert!(timer.interrupt());
+        timer.write(STATUS, 0);
+        assert!(!timer.interrupt());
+        assert!(timer.can_interrupt());
+
+        // counting cycles, restarting on a match
+        timer.write(COUNT, 0);
+        timer.write(PRESCALE, 0);
+        timer.write(CTRL, CTRL_ENABLE | CTRL_RESTART | CTRL_CYCLES);
+        timer.tick(3);
+        assert_eq!(1, timer.peek(COUNT));
+        assert_eq!(STATUS_MATCH, timer.peek(STATUS));
+        timer.write(CTRL, 0xFF);
+        assert_eq!(0b1111, timer.peek(CTRL));
+    }
+}
@@ -0,0 +1,150 @@
+// A UART, as four word-wide registers from its base address:
+//
+//     0x0  TX      write a byte to send it
+//     0x4  RX      read the oldest byte received, or 0 if there is none
+//     0x8  STATUS  bit 0: a byte has been received, bit 1: ready to send,
+//                  which it always is
+//     0xC  CTRL    bit 0: raise the external interrupt while a byte has
+//                  been received
+//
+// Sent bytes go to the console, and received ones come from the frontend,
+// which feeds them from the keyboard or stdin.
+
+use super::Peripheral;
+use std::collections::VecDeque;
+
+// registers, as offsets from the base address
+pub const TX: u32 = 0x0;
+pub const RX: u32 = 0x4;
+pub const STATUS: u32 = 0x8;
+pub const CTRL: u32 = 0xC;
+pub const WIDTH: u32 = 16;
+
+pub const STATUS_RX_READY: u32 = 0b01;
+pub const STATUS_TX_READY: u32 = 0b10;
+pub const CTRL_RX_INTERRUPT: u32 = 0b1;
+
+#[derive(Default)]
+pub struct Uart {
+    // received, oldest first
+    rx: VecDeque<u8>,
+    // sent, that the frontend has not printed yet
+    tx: Vec<u8>,
+    ctrl: u32,
+    // taken from RX since the last call to take_read, for the undo log
+    read: Option<u8>,
+}
+
+impl Uart {
+    // bytes arriving from outside
+    pub fn receive(&mut self, data: &[u8]) {
+        self.rx.extend(data);
+    }
+
+    // take the bytes sent since the last call
+    pub fn take_tx(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.tx)
+    }
+
+    // take the byte a read of RX took since the last call, if any
+    pub fn take_read(&mut self) -> Option<u8> {
+        self.read.take()
+    }
+
+    // put back a byte taken from RX, as when stepping back
+    pub fn unread(&mut self, byte: u8) {
+        self.rx.push_front(byte);
+    }
+
+    // whether it is raising the external interrupt
+    pub fn interrupt(&self) -> bool {
+        self.ctrl & CTRL_RX_INTERRUPT != 0 && !self.rx.is_empty()
+    }
+
+    // whether it may raise the external interrupt later, when a byte arrives
+    pub fn can_interrupt(&self) -> bool {
+        self.ctrl & CTRL_RX_INTERRUPT != 0
+    }
+}
+
+impl Peripheral for Uart {
+    fn registers(&self) -> &'static [u32] {
+        &[TX, RX, STATUS, CTRL]
+    }
+
+    // reading RX here does not take the byte
+    fn peek(&self, offset: u32) -> u32 {
+        match offset {
+            RX => self.rx.front().copied().unwrap_or(0) as u32,
+            STATUS => {
+                let rx_ready = if self.rx.is_empty() {
+                    0
+                } else {
+                    STATUS_RX_READY
+                };
+                rx_ready | STATUS_TX_READY
+            }
+            CTRL => self.ctrl,
+            _ => 0,
+        }
+    }
+
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            RX => {
+                self.read = self.rx.pop_front();
+                self.read.unwrap_or(0) as u32
+            }
+            _ => self.peek(offset),
+        }
+    }
+
+    fn write(&mut self, offset: u32, data: u32) {
+        match offset {
+            TX => self.tx.push(data as u8),
+            CTRL => self.ctrl = data & CTRL_RX_INTERRUPT,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let mut uart = Uart::default();
+        assert_eq!(STATUS_TX_READY, uart.read(STATUS));
+        assert_eq!(0, uart.read(RX));
+
+        uart.write(TX, 0x148);
+        uart.write(TX, 0x69);
+        assert_eq!(b"Hi".to_vec(), uart.take_tx());
+        assert!(uart.take_tx().is_empty());
+
+        uart.receive(b"ok");
+        assert_eq!(STATUS_RX_READY | STATUS_TX_READY, uart.read(STATUS));
+        assert_eq!(b'o' as u32, uart.peek(RX));
+        assert_eq!(b'o' as u32, uart.read(RX));
+        assert_eq!(b'k' as u32, uart.read(RX));
+        assert_eq!(STATUS_TX_READY, uart.read(STATUS));
+
+        assert_eq!(Some(b'k'), uart.take_read());
+        assert_eq!(None, uart.take_read());
+        uart.unread(b'k');
+        assert_eq!(b'k' as u32, uart.read(RX));
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut uart = Uart::default();
+        uart.receive(b"x");
+        assert!(!uart.interrupt());
+        uart.write(CTRL, 0xFF);
+        assert_eq!(CTRL_RX_INTERRUPT, uart.read(CTRL));
+        assert!(uart.interrupt());
+        uart.read(RX);
+        assert!(!uart.interrupt());
+    }
+}
@@ -0,0 +1,354 @@
+// Differential testing: random RV32I programs run on the MCU and on an
+// independent reference interpreter, comparing architectural state after
+// every instruction. A divergence is minimized by replacing instructions
+// with NOPs for as long as the same instruction still diverges.
+//
+// Set OEMU_DIFFTEST_SEED to replay a failing seed.
+
+mod reference;
+
+use super::mcu::{MCU, MEM_SIZE};
+use super::rv32i::decode::{self, Instruction, Operation};
+use super::rv32i::disasm;
+use super::rv32i::encode::encode;
+use super::test_util::load_words;
+use super::Size;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use reference::Reference;
+
+const NOP: u32 = 0x00000013;
+
+// programs per test run, and instructions per program after the prologue
+const PROGRAMS: usize = 300;
+const PROGRAM_LEN: usize = 150;
+
+// minimizing can turn a forward jump into a backward one, so cap how
+// long any one program may run
+const STEP_LIMIT: usize = 10_000;
+
+// loads and stores use a base register set to this with lui,
+// so they land in RAM well clear of the program
+const DATA_BASE: u32 = 0x8000;
+
+const R_OPS: [Operation; 10] = [
+    Operation::ADD,
+    Operation::SUB,
+    Operation::SLL,
+    Operation::SLT,
+    Operation::SLTU,
+    Operation::XOR,
+    Operation::SRL,
+    Operation::SRA,
+    Operation::OR,
+    Operation::AND,
+];
+
+const I_OPS: [Operation; 9] = [
+    Operation::ADDI,
+    Operation::SLTI,
+    Operation::SLTIU,
+    Operation::XORI,
+    Operation::ORI,
+    Operation::ANDI,
+    Operation::SLLI,
+    Operation::SRLI,
+    Operation::SRAI,
+];
+
+const LOAD_OPS: [Operation; 5] = [
+    Operation::LB,
+    Operation::LH,
+    Operation::LW,
+    Operation::LBU,
+    Operation::LHU,
+];
+
+const STORE_OPS: [Operation; 3] = [Operation::SB, Operation::SH, Operation::SW];
+
+const BRANCH_OPS: [Operation; 6] = [
+    Operation::BEQ,
+    Operation::BNE,
+    Operation::BLT,
+    Operation::BGE,
+    Operation::BLTU,
+    Operation::BGEU,
+];
+
+fn ir(op: Operation, rd: u32, rs1: u32, rs2: u32, imm: u32) -> Instruction {
+    Instruction {
+        op,
+        rs1,
+        rs2,
+        rd,
+        imm,
+    }
+}
+
+fn reg(rng: &mut StdRng) -> u32 {
+    rng.gen_range(0, 32)
+}
+
+// a signed 12-bit immediate
+fn imm12(rng: &mut StdRng) -> u32 {
+    rng.gen_range(-2048i32, 2048) as u32
+}
+
+// Every register starts at a random value, then PROGRAM_LEN random
+// instructions follow. Branches and jumps only go forward, so every
+// program runs off its end.
+fn random_program(rng: &mut StdRng) -> Vec<u32> {
+    let mut p = Vec::new();
+    for r in 1..32 {
+        let v: u32 = rng.gen();
+        let hi = v.wrapping_add(0x800) & 0xFFFFF000;
+        p.push(ir(Operation::LUI, r, 0, 0, hi));
+        p.push(ir(Operation::ADDI, r, r, 0, v.wrapping_sub(hi)));
+    }
+
+    // instructions that depend on the one before them setting a base
+    // register, which nothing may jump to directly
+    let mut paired = vec![false; p.len()];
+
+    let len = p.len() + PROGRAM_LEN;
+    while p.len() < len {
+        // instructions left until the end, including this one
+        let left = (len - p.len()) as u32;
+        let mut pair = false;
+        let next = match rng.gen_range(0, 10) {
+            0..=3 => {
+                let op = R_OPS[rng.gen_range(0, R_OPS.len())];
+                ir(op, reg(rng), reg(rng), reg(rng), 0)
+            }
+            4 | 5 => {
+                let op = I_OPS[rng.gen_range(0, I_OPS.len())];
+                let imm = match op {
+                    Operation::SLLI | Operation::SRLI => rng.gen_range(0, 32),
+                    Operation::SRAI => 0x400 | rng.gen_range(0, 32),
+                    _ => imm12(rng),
+                };
+                ir(op, reg(rng), reg(rng), 0, imm)
+            }
+            6 => {
+                let op = if rng.gen_range(0, 2) == 0 {
+                    Operation::LUI
+                } else {
+                    Operation::AUIPC
+                };
+                ir(op, reg(rng), 0, 0, rng.gen::<u32>() & 0xFFFFF000)
+            }
+            7 | 8 if left >= 2 => {
+                let base = rng.gen_range(1, 32);
+                p.push(ir(Operation::LUI, base, 0, 0, DATA_BASE));
+                paired.push(false);
+                pair = true;
+                if rng.gen_range(0, 2) == 0 {
+                    let op = LOAD_OPS[rng.gen_range(0, LOAD_OPS.len())];
+                    ir(op, reg(rng), base, 0, imm12(rng))
+                } else {
+                    let op = STORE_OPS[rng.gen_range(0, STORE_OPS.len())];
+                    ir(op, 0, base, reg(rng), imm12(rng))
+                }
+            }
+            // auipc + jalr, landing at least past the jalr
+            9 if left >= 3 && rng.gen_range(0, 3) == 0 => {
+                let link = rng.gen_range(1, 32);
+                let k = rng.gen_range(2, left.min(16) + 1);
+                p.push(ir(Operation::AUIPC, link, 0, 0, 0));
+                paired.push(false);
+                pair = true;
+                // jalr clears the low bit of the target
+                ir(
+                    Operation::JALR,
+                    reg(rng),
+                    link,
+                    0,
+                    4 * k + rng.gen_range(0, 2),
+                )
+            }
+            _ => {
+                let k = rng.gen_range(1, left.min(16) + 1);
+                if rng.gen_range(0, 4) == 0 {
+                    ir(Operation::JAL, reg(rng), 0, 0, 4 * k)
+                } else {
+                    let op = BRANCH_OPS[rng.gen_range(0, BRANCH_OPS.len())];
+                    ir(op, 0, reg(rng), reg(rng), 4 * k)
+                }
+            }
+        };
+        paired.push(pair);
+        p.push(next);
+    }
+
+    // land on the first of a pair instead of the second; a jump is at
+    // least one instruction before its target, so this stays forward
+    for (i, jump) in p.iter_mut().enumerate() {
+        let from = match jump.op {
+            Operation::JALR => i - 1,
+            op if BRANCH_OPS.contains(&op) || op == Operation::JAL => i,
+            _ => continue,
+        };
+        let target = from + (jump.imm / 4) as usize;
+        if target < len && paired[target] {
+            jump.imm -= 4;
+        }
+    }
+
+    p.iter().map(|ir| encode(ir).unwrap()).collect()
+}
+
+struct Divergence {
+    // instructions executed before the diverging one
+    count: usize,
+    pc: u32,
+    word: u32,
+    diff: Vec<String>,
+}
+
+// run a program on both models, returning the first divergence
+fn run(program: &[u32]) -> Option<Divergence> {
+    let mut mcu = MCU::new();
+    load_words(&mut mcu, program);
+    let mut reference = Reference::new(program, MEM_SIZE as u32);
+    let end = 4 * program.len() as u32;
+
+    let mut count = 0;
+    while reference.pc < end && count < STEP_LIMIT {
+        let pc = reference.pc;
+        let word = reference.load(pc, 4);
+        let mut diff = Vec::new();
+
+        let stored = match reference.step() {
+            Ok(s) => s,
+            Err(e) => {
+                diff.push(format!("reference: {}", e));
+                None
+            }
+        };
+        mcu.step(|_s| {});
+
+        if mcu.pc != reference.pc {
+            diff.push(format!(
+                "pc: expected {:#010X}, got {:#010X}",
+                reference.pc, mcu.pc
+            ));
+        }
+        for i in 1..32 {
+            if mcu.rf_rd(i) != reference.x[i as usize] {
+                diff.push(format!(
+                    "x{} ({}): expected {:#010X}, got {:#010X}",
+                    i,
+                    decode::reg_name(i),
+                    reference.x[i as usize],
+                    mcu.rf_rd(i)
+                ));
+            }
+        }
+        if let Some((addr, bytes)) = stored {
+            for a in addr..addr.wrapping_add(bytes) {
+                let exp = reference.load(a, 1);
+                let got = mcu.mem_rd(a, Size::Byte);
+                if exp != got {
+                    diff.push(format!(
+                        "mem[{:#010X}]: expected {:#04X}, got {:#04X}",
+                        a, exp, got
+                    ));
+                }
+            }
+        }
+
+        if !diff.is_empty() {
+            return Some(Divergence {
+                count,
+                pc,
+                word,
+                diff,
+            });
+        }
+        count += 1;
+    }
+    None
+}
+
+// NOP out instructions while the same instruction still diverges
+fn minimize(program: &[u32], first: Divergence) -> (Vec<u32>, Divergence) {
+    let mut program = program.to_vec();
+    let mut div = first;
+    for i in (0..program.len()).rev() {
+        if program[i] == NOP || i as u32 == div.pc / 4 {
+            continue;
+        }
+        let mut candidate = program.clone();
+        candidate[i] = NOP;
+        if let Some(d) = run(&candidate) {
+            if d.pc == div.pc && d.word == div.word {
+                program = candidate;
+                div = d;
+            }
+        }
+    }
+    (program, div)
+}
+
+fn disassemble(word: u32, pc: u32) -> String {
+    disasm::disassemble(&decode::decode(word), pc)
+}
+
+fn report(seed: u64, program: &[u32], div: Divergence) -> String {
+    let (program, div) = minimize(program, div);
+    let mut s = format!(
+        "seed {}: diverged at {:#010X} ({:08x} {}) after {} instructions\n",
+        seed,
+        div.pc,
+        div.word,
+        disassemble(div.word, div.pc),
+        div.count
+    );
+    for d in div.diff.iter() {
+        s.push_str(&format!("    {}\n", d));
+    }
+    s.push_str("  minimized program:\n");
+    for (i, w) in program.iter().enumerate() {
+        if *w != NOP {
+            s.push_str(&format!(
+                "    {:#010X}: {:08x}  {}\n",
+                4 * i,
+                w,
+                disassemble(*w, 4 * i as u32)
+            ));
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_programs() {
+        let seed = match std::env::var("OEMU_DIFFTEST_SEED") {
+            Ok(s) => s.parse().unwrap(),
+            Err(_) => rand::thread_rng().gen(),
+        };
+        let mut rng = StdRng::seed_from_u64(seed);
+        for _ in 0..PROGRAMS {
+            let program = random_program(&mut rng);
+            if let Some(div) = run(&program) {
+                panic!("\n{}", report(seed, &program, div));
+            }
+        }
+    }
+
+    #[test]
+    fn catches_divergence() {
+        // the reference does not model ecall, so this must diverge
+        let program = [
+            encode(&ir(Operation::ADDI, 5, 0, 0, 1)).unwrap(),
+            0x00000073,
+        ];
+        let div = run(&program).unwrap();
+        assert_eq!(4, div.pc);
+        assert_eq!(1, div.count);
+    }
+}
@@ -0,0 +1,176 @@
+// A deliberately simple RV32I interpreter, written straight from the spec
+// and sharing no code with the emulator, to check it against.
+// Only RAM is modeled; anything outside it reads as zero and ignores writes.
+
+use std::collections::HashMap;
+
+pub struct Reference {
+    pub pc: u32,
+    pub x: [u32; 32],
+    mem: HashMap<u32, u8>,
+    mem_size: u32,
+}
+
+fn sext(value: u32, bits: u32) -> u32 {
+    let shift = 32 - bits;
+    (((value << shift) as i32) >> shift) as u32
+}
+
+impl Reference {
+    pub fn new(program: &[u32], mem_size: u32) -> Reference {
+        let mut r = Reference {
+            pc: 0,
+            x: [0; 32],
+            mem: HashMap::new(),
+            mem_size,
+        };
+        for (i, w) in program.iter().enumerate() {
+            r.store(4 * i as u32, *w, 4);
+        }
+        r
+    }
+
+    pub fn load(&self, addr: u32, bytes: u32) -> u32 {
+        let mut data = 0;
+        for i in 0..bytes {
+            let a = addr.wrapping_add(i);
+            if a < self.mem_size {
+                data |= (*self.mem.get(&a).unwrap_or(&0) as u32) << (8 * i);
+            }
+        }
+        data
+    }
+
+    fn store(&mut self, addr: u32, data: u32, bytes: u32) {
+        for i in 0..bytes {
+            let a = addr.wrapping_add(i);
+            if a < self.mem_size {
+                self.mem.insert(a, (data >> (8 * i)) as u8);
+            }
+        }
+    }
+
+    // execute one instruction
+    // returns the address and width of any store, or Err for anything
+    // the reference does not model
+    pub fn step(&mut self) -> Result<Option<(u32, u32)>, String> {
+        let w = self.load(self.pc, 4);
+        let opcode = w & 0x7F;
+        let rd = ((w >> 7) & 0x1F) as usize;
+        let funct3 = (w >> 12) & 0x7;
+        let rs1 = self.x[((w >> 15) & 0x1F) as usize];
+        let rs2 = self.x[((w >> 20) & 0x1F) as usize];
+        let funct7 = w >> 25;
+
+        let imm_i = sext(w >> 20, 12);
+        let imm_s = sext(((w >> 25) << 5) | ((w >> 7) & 0x1F), 12);
+        let imm_b = sext(
+            ((w >> 31) << 12)
+                | (((w >> 7) & 1) << 11)
+                | (((w >> 25) & 0x3F) << 5)
+                | (((w >> 8) & 0xF) << 1),
+            13,
+        );
+        let imm_u = w & 0xFFFFF000;
+        let imm_j = sext(
+            ((w >> 31) << 20)
+                | (w & 0xFF000)
+                | (((w >> 20) & 1) << 11)
+                | (((w >> 21) & 0x3FF) << 1),
+            21,
+        );
+
+        let mut next = self.pc.wrapping_add(4);
+        let mut result: Option<u32> = None;
+        let mut stored = None;
+
+        match opcode {
+            0x37 => result = Some(imm_u),
+            0x17 => result = Some(self.pc.wrapping_add(imm_u)),
+            0x6F => {
+                result = Some(next);
+                next = self.pc.wrapping_add(imm_j);
+            }
+            0x67 => {
+                result = Some(next);
+                next = rs1.wrapping_add(imm_i) & !1;
+            }
+            0x63 => {
+                let taken = match funct3 {
+                    0 => rs1 == rs2,
+                    1 => rs1 != rs2,
+                    4 => (rs1 as i32) < (rs2 as i32),
+                    5 => (rs1 as i32) >= (rs2 as i32),
+                    6 => rs1 < rs2,
+                    7 => rs1 >= rs2,
+                    _ => return Err(format!("bad branch {:08x}", w)),
+                };
+                if taken {
+                    next = self.pc.wrapping_add(imm_b);
+                }
+            }
+            0x03 => {
+                let addr = rs1.wrapping_add(imm_i);
+                result = Some(match funct3 {
+                    0 => sext(self.load(addr, 1), 8),
+                    1 => sext(self.load(addr, 2), 16),
+                    2 => self.load(addr, 4),
+                    4 => self.load(addr, 1),
+                    5 => self.load(addr, 2),
+                    _ => return Err(format!("bad load {:08x}", w)),
+                });
+            }
+            0x23 => {
+                let addr = rs1.wrapping_add(imm_s);
+                let bytes = match funct3 {
+                    0 => 1,
+                    1 => 2,
+                    2 => 4,
+                    _ => return Err(format!("bad store {:08x}", w)),
+                };
+                self.store(addr, rs2, bytes);
+                stored = Some((addr, bytes));
+            }
+            0x13 => {
+                let shamt = imm_i & 0x1F;
+                result = Some(match funct3 {
+                    0 => rs1.wrapping_add(imm_i),
+                    2 => ((rs1 as i32) < (imm_i as i32)) as u32,
+                    3 => (rs1 < imm_i) as u32,
+                    4 => rs1 ^ imm_i,
+                    6 => rs1 | imm_i,
+                    7 => rs1 & imm_i,
+                    1 => rs1 << shamt,
+                    5 if funct7 == 0x20 => ((rs1 as i32) >> shamt) as u32,
+                    5 => rs1 >> shamt,
+                    _ => return Err(format!("bad op-imm {:08x}", w)),
+                });
+            }
+            0x33 => {
+                let shamt = rs2 & 0x1F;
+                result = Some(match (funct7, funct3) {
+                    (0x00, 0) => rs1.wrapping_add(rs2),
+                    (0x20, 0) => rs1.wrapping_sub(rs2),
+                    (0x00, 1) => rs1 << shamt,
+                    (0x00, 2) => ((rs1 as i32) < (rs2 as i32)) as u32,
+                    (0x00, 3) => (rs1 < rs2) as u32,
+                    (0x00, 4) => rs1 ^ rs2,
+                    (0x00, 5) => rs1 >> shamt,
+                    (0x20, 5) => ((rs1 as i32) >> shamt) as u32,
+                    (0x00, 6) => rs1 | rs2,
+                    (0x00, 7) => rs1 & rs2,
+                    _ => return Err(format!("bad op {:08x}", w)),
+                });
+            }
+            _ => return Err(format!("unmodeled instruction {:08x}", w)),
+        }
+
+        if let Some(v) = result {
+            if rd != 0 {
+                self.x[rd] = v;
+            }
+        }
+        self.pc = next;
+        Ok(stored)
+    }
+}
@@ -0,0 +1,304 @@
+// A small expression language over machine state, for breakpoint
+// conditions and tracepoint messages, e.g. 'a0 == 5 && mem32[sp+4] != 0'.
+//
+//     numbers      5, 0x1F (as parse_int reads them)
+//     registers    ABI names or xN, and pc
+//     memory       mem8[ADDR], mem16[ADDR], mem32[ADDR]
+//     operators    as in C, loosest first:
+//                  ||  &&  |  ^  &  == !=  < <= > >=  << >>  + -  * / %
+//                  and the unary - ! ~
+//
+// Values are 32-bit and unsigned, so comparisons are unsigned and
+// arithmetic wraps. Division by zero gives all ones and the remainder the
+// dividend, as RV32M does. Comparisons and logical operators give 1 or 0.
+
+use super::super::util::parse::parse_int;
+use super::devices::mem::Size;
+use super::mcu::MCU;
+use super::rv32i::decode::reg_index;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(u32),
+    Reg(u32),
+    Pc,
+    Mem(Size, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+// binary operators by precedence, loosest first
+const LEVELS: [&[(&str, BinOp)]; 10] = [
+    &[("||", BinOp::Or)],
+    &[("&&", BinOp::And)],
+    &[("|", BinOp::BitOr)],
+    &[("^", BinOp::BitXor)],
+    &[("&", BinOp::BitAnd)],
+    &[("==", BinOp::Eq), ("!=", BinOp::Ne)],
+    &[
+        ("<=", BinOp::Le),
+        (">=", BinOp::Ge),
+        ("<", BinOp::Lt),
+        (">", BinOp::Gt),
+    ],
+    &[("<<", BinOp::Shl), (">>", BinOp::Shr)],
+    &[("+", BinOp::Add), ("-", BinOp::Sub)],
+    &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Rem)],
+];
+
+// operators and punctuation, longest first so '<=' is not read as '<'
+const SYMBOLS: [&str; 23] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[",
+];
+
+fn tokenize(s: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let len = if c.is_ascii_alphanumeric() || c == '_' {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len())
+        } else if c == ']' {
+            1
+        } else {
+            match SYMBOLS.iter().find(|sym| rest.starts_with(*sym)) {
+                Some(sym) => sym.len(),
+                None => return Err(format!("unexpected '{}'", c)),
+            }
+        };
+        tokens.push(String::from(&rest[..len]));
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| String::from("unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.next()? {
+            ref t if t == token => Ok(()),
+            t => Err(format!("expected '{}', found '{}'", token, t)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&(_, op)) = LEVELS[level]
+            .iter()
+            .find(|(sym, _)| self.peek() == Some(*sym))
+        {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some("-") => UnOp::Neg,
+            Some("!") => UnOp::Not,
+            Some("~") => UnOp::BitNot,
+            _ => return self.primary(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let t = self.next()?;
+        let size = match t.as_str() {
+            "(" => {
+                let e = self.binary(0)?;
+                self.expect(")")?;
+                return Ok(e);
+            }
+            "pc" => return Ok(Expr::Pc),
+            "mem8" => Size::Byte,
+            "mem16" => Size::HalfWord,
+            "mem32" => Size::Word,
+            _ => {
+                if let Some(i) = reg_index(&t) {
+                    return Ok(Expr::Reg(i));
+                }
+                return match parse_int(&t) {
+                    Ok(n) => Ok(Expr::Num(n)),
+                    Err(_) => Err(format!("unknown name '{}'", t)),
+                };
+            }
+        };
+        self.expect("[")?;
+        let addr = self.binary(0)?;
+        self.expect("]")?;
+        Ok(Expr::Mem(size, Box::new(addr)))
+    }
+}
+
+pub fn parse(s: &str) -> Result<Expr, String> {
+    let mut p = Parser {
+        tokens: tokenize(s)?,
+        pos: 0,
+    };
+    let e = p.binary(0)?;
+    match p.peek() {
+        None => Ok(e),
+        Some(t) => Err(format!("unexpected '{}'", t)),
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, mcu: &MCU) -> u32 {
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(i) => mcu.rf_rd(*i),
+            Expr::Pc => mcu.pc,
+            Expr::Mem(size, addr) => mcu.mem_rd(addr.eval(mcu), *size),
+            Expr::Unary(op, e) => {
+                let v = e.eval(mcu);
+                match op {
+                    UnOp::Neg => v.wrapping_neg(),
+                    UnOp::Not => (v == 0) as u32,
+                    UnOp::BitNot => !v,
+                }
+            }
+            // evaluate the right side only when needed
+            Expr::Binary(BinOp::Or, a, b) => (a.eval(mcu) != 0 || b.eval(mcu) != 0) as u32,
+            Expr::Binary(BinOp::And, a, b) => (a.eval(mcu) != 0 && b.eval(mcu) != 0) as u32,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(mcu), b.eval(mcu));
+                match op {
+                    BinOp::BitOr => a | b,
+                    BinOp::BitXor => a ^ b,
+                    BinOp::BitAnd => a & b,
+                    BinOp::Eq => (a == b) as u32,
+                    BinOp::Ne => (a != b) as u32,
+                    BinOp::Lt => (a < b) as u32,
+                    BinOp::Le => (a <= b) as u32,
+                    BinOp::Gt => (a > b) as u32,
+                    BinOp::Ge => (a >= b) as u32,
+                    BinOp::Shl => a.wrapping_shl(b),
+                    BinOp::Shr => a.wrapping_shr(b),
+                    BinOp::Add => a.wrapping_add(b),
+                    BinOp::Sub => a.wrapping_sub(b),
+                    BinOp::Mul => a.wrapping_mul(b),
+                    BinOp::Div => a.checked_div(b).unwrap_or(u32::MAX),
+                    BinOp::Rem => a.checked_rem(b).unwrap_or(a),
+                    BinOp::Or | BinOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::load_words;
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        let mcu = MCU::new();
+        let cases: [(&str, u32); 12] = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("1 << 2 + 1", 8),
+            ("1 | 2 ^ 3 & 1", 3),
+            ("2 < 3 == 1", 1),
+            ("0 || 2 && 3", 1),
+            ("-1", 0xFFFFFFFF),
+            ("!5 + ~0", 0xFFFFFFFF),
+            ("0x10 / 0", 0xFFFFFFFF),
+            ("7 % 0", 7),
+            ("10 - 4 - 3", 3),
+            ("-1 > 0", 1),
+        ];
+        for (s, v) in cases.iter() {
+            assert_eq!(*v, parse(s).unwrap().eval(&mcu), "{}", s);
+        }
+    }
+
+    #[test]
+    fn machine_state() {
+        let mut mcu = MCU::new();
+        // li a0, 5; li sp, 0x100; sw a0, 4(sp)
+        let prog: [u32; 3] = [0x00500513, 0x10000113, 0x00a12223];
+        load_words(&mut mcu, &prog);
+        for _ in 0..3 {
+            mcu.step(|_s| {});
+        }
+        let e = parse("a0 == 5 && mem32[sp+4] != 0").unwrap();
+        assert_eq!(1, e.eval(&mcu));
+        assert_eq!(5, parse("mem8[x2 + 4]").unwrap().eval(&mcu));
+        assert_eq!(12, parse("pc").unwrap().eval(&mcu));
+        assert_eq!(0, parse("mem16[0x106]").unwrap().eval(&mcu));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Err(String::from("unknown name 'foo'")), parse("foo + 1"));
+        assert_eq!(Err(String::from("unexpected '$'")), parse("a0 $ 1"));
+        assert_eq!(
+            Err(String::from("unexpected end of expression")),
+            parse("a0 +")
+        );
+        assert_eq!(Err(String::from("unexpected ')'")), parse("a0)"));
+        assert_eq!(
+            Err(String::from("expected '[', found '4'")),
+            parse("mem32 4")
+        );
+        assert!(parse("(1 + 2").is_err());
+        assert!(parse("").is_err());
+    }
+}
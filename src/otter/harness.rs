@@ -0,0 +1,240 @@
+// Runs every program under res/programs/test and checks its final state
+// against the 'expect' file next to its binary.
+//
+// Each line of an expect file is one directive; '#' starts a comment.
+// Numbers may be decimal or 0x-prefixed hex.
+//
+//     halt <addr>          stop before executing the instruction at <addr>
+//     budget <n>           stop after executing <n> instructions
+//     text <begin>:<end>   write-protect the code in this range
+//     stop <reason>        self_loop, halt, exit, exit_write, budget, tohost or trap
+//     reg <name> <value>   register by ABI name or xN
+//     mem <addr> <value>   memory word
+//     sseg <value>         seven-segment display
+//     leds <value>         LEDs as a 16-bit value
+//     count <n>            instructions executed
+
+use super::super::util::parse::parse_int;
+use super::mcu::{HaltConfig, StopReason, MCU};
+use super::rv32i::{decode, disasm};
+use super::trace::Filter;
+use super::Size;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+const PROGRAMS_DIR: &str = "res/programs/test";
+
+// how many executed instructions to show when a program fails
+const HISTORY_LEN: usize = 16;
+
+// keeps a broken program from hanging the test suite
+const DEFAULT_BUDGET: u64 = 10_000_000;
+
+enum Check {
+    Stop(String),
+    Reg(u32, u32),
+    Mem(u32, u32),
+    Sseg(u32),
+    Leds(u32),
+    Count(u64),
+}
+
+struct Expect {
+    halt: HaltConfig,
+    text: Option<(u32, u32)>,
+    checks: Vec<Check>,
+}
+
+fn parse_num(s: Option<&&str>, line: usize) -> Result<u32, String> {
+    let s = s.ok_or(format!("line {}: missing value", line))?;
+    parse_int(s).map_err(|e| format!("line {}: {}: {}", line, s, e))
+}
+
+fn parse_expect(src: &str) -> Result<Expect, String> {
+    let mut expect = Expect {
+        halt: HaltConfig {
+            budget: Some(DEFAULT_BUDGET),
+            ..HaltConfig::default()
+        },
+        text: None,
+        checks: Vec::new(),
+    };
+
+    for (i, line) in src.lines().enumerate() {
+        let n = i + 1;
+        let line = line.split('#').next().unwrap().trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        match words[0] {
+            "halt" => expect.halt.addr = Some(parse_num(words.get(1), n)?),
+            "budget" => expect.halt.budget = Some(parse_num(words.get(1), n)? as u64),
+            "text" => {
+                let range = words.get(1).ok_or(format!("line {}: missing range", n))?;
+                expect.text =
+                    Some(Filter::parse_range(range).map_err(|e| format!("line {}: {}", n, e))?);
+            }
+            "stop" => {
+                let reason = words.get(1).ok_or(format!("line {}: missing reason", n))?;
+                expect.checks.push(Check::Stop(reason.to_string()));
+            }
+            "reg" => {
+                let name = words
+                    .get(1)
+                    .ok_or(format!("line {}: missing register", n))?;
+                let index = decode::reg_index(name)
+                    .ok_or(format!("line {}: unknown register {}", n, name))?;
+                expect
+                    .checks
+                    .push(Check::Reg(index, parse_num(words.get(2), n)?));
+            }
+            "mem" => expect.checks.push(Check::Mem(
+                parse_num(words.get(1), n)?,
+                parse_num(words.get(2), n)?,
+            )),
+            "sseg" => expect.checks.push(Check::Sseg(parse_num(words.get(1), n)?)),
+            "leds" => expect.checks.push(Check::Leds(parse_num(words.get(1), n)?)),
+            "count" => expect
+                .checks
+                .push(Check::Count(parse_num(words.get(1), n)? as u64)),
+            other => return Err(format!("line {}: unknown directive {}", n, other)),
+        }
+    }
+    Ok(expect)
+}
+
+fn stop_name(reason: &StopReason) -> &'static str {
+    match reason {
+        StopReason::Trap(..) => "trap",
+        StopReason::Exit(_) => "exit",
+        StopReason::SelfLoop(_) => "self_loop",
+        StopReason::HaltAddress(_) => "halt",
+        StopReason::ExitWrite(_) => "exit_write",
+        StopReason::Budget(_) => "budget",
+        StopReason::ToHost(_) => "tohost",
+        StopReason::Watchpoint(..) => "watchpoint",
+        StopReason::Reached(_) => "reached",
+        StopReason::Breakpoint(..) => "breakpoint",
+        StopReason::Guard(..) => "guard",
+    }
+}
+
+// run one program, returning a report of everything that did not match
+fn run_program(dir: &Path) -> Result<(), String> {
+    let src = fs::read_to_string(dir.join("expect"))
+        .map_err(|e| format!("could not read expect file: {}", e))?;
+    let expect = parse_expect(&src)?;
+
+    let mut mcu = MCU::from_bin(dir.join("bin").to_str().unwrap());
+    mcu.set_halt_config(expect.halt);
+    if let Some((begin, end)) = expect.text {
+        mcu.protect_text(begin, end);
+    }
+
+    // (pc, raw instruction) of the last few instructions
+    let mut history: VecDeque<(u32, u32)> = VecDeque::with_capacity(HISTORY_LEN);
+    let stop = loop {
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back((mcu.pc, mcu.fetch(|_s| {}).1));
+        if let Some(reason) = mcu.step(|_s| {}) {
+            break reason;
+        }
+    };
+
+    let leds = mcu
+        .leds()
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, l)| acc | ((*l as u32) << i));
+
+    let mut diff = Vec::new();
+    for check in expect.checks.iter() {
+        let (what, exp, got) = match check {
+            Check::Stop(s) => {
+                if s != stop_name(&stop) {
+                    diff.push(format!("stop: expected {}, got {}", s, stop));
+                }
+                continue;
+            }
+            Check::Reg(i, v) => (
+                format!("x{} ({})", i, decode::reg_name(*i)),
+                *v as u64,
+                mcu.rf_rd(*i) as u64,
+            ),
+            Check::Mem(a, v) => (
+                format!("mem[{:#010X}]", a),
+                *v as u64,
+                mcu.mem_rd(*a, Size::Word) as u64,
+            ),
+            Check::Sseg(v) => (String::from("sseg"), *v as u64, mcu.sseg() as u64),
+            Check::Leds(v) => (String::from("leds"), *v as u64, leds as u64),
+            Check::Count(n) => (String::from("count"), *n, mcu.instr_count()),
+        };
+        if exp != got {
+            diff.push(format!(
+                "{}: expected {:#010X}, got {:#010X}",
+                what, exp, got
+            ));
+        }
+    }
+
+    if diff.is_empty() {
+        return Ok(());
+    }
+    let mut report = format!("stopped: {}\n", stop);
+    for d in diff {
+        report.push_str(&format!("    {}\n", d));
+    }
+    report.push_str("  last instructions:\n");
+    for (pc, raw) in history {
+        report.push_str(&format!(
+            "    {:#010X}: {:08x}  {}\n",
+            pc,
+            raw,
+            disasm::disassemble(&decode::decode(raw), pc)
+        ));
+    }
+    Err(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs() {
+        let mut dirs: Vec<_> = fs::read_dir(PROGRAMS_DIR)
+            .unwrap()
+            .map(|d| d.unwrap().path())
+            .filter(|p| p.is_dir())
+            .collect();
+        dirs.sort();
+        assert!(!dirs.is_empty());
+
+        let mut failures = String::new();
+        for dir in dirs.iter() {
+            if let Err(report) = run_program(dir) {
+                failures.push_str(&format!("{}: {}\n", dir.display(), report));
+            }
+        }
+        assert!(failures.is_empty(), "\n{}", failures);
+    }
+
+    #[test]
+    fn parse() {
+        let expect = parse_expect("halt 0x40 # done\n\nreg t0 5\nmem 0x100 0xFF\n").unwrap();
+        assert_eq!(Some(0x40), expect.halt.addr);
+        assert_eq!(2, expect.checks.len());
+        assert_eq!(None, expect.text);
+        assert_eq!(
+            Some((0x0, 0x100)),
+            parse_expect("text 0x0:0x100").unwrap().text
+        );
+        assert!(parse_expect("reg q0 5").is_err());
+        assert!(parse_expect("bogus 1").is_err());
+    }
+}
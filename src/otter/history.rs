@@ -0,0 +1,123 @@
+// An undo log of recent instructions, so the MCU can step backward and
+// report which instruction last wrote a register or address.
+//
+// Each entry holds what one instruction overwrote, including the timer and
+// a byte it took from the UART. Output already sent to the console, by
+// semihosting or the UART, and semihosted reads are not taken back.
+
+use super::callstack::Frame;
+use super::devices::timer::Timer;
+use std::collections::VecDeque;
+
+// how to take back one instruction
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Undo {
+    // instruction number, as counted in traces
+    pub count: u64,
+    // PC of the instruction
+    pub pc: u32,
+    // registers written, with their old values
+    pub regs: Vec<(u32, u32)>,
+    // CSRs written, with their old values
+    pub csrs: Vec<(u32, u32)>,
+    // bytes written, with their old contents or None if uninitialized
+    pub mem: Vec<(u32, Option<u8>)>,
+    // a call or return: the call stack depth to go back to, and the
+    // frames to push again
+    pub calls: Option<(usize, Vec<Frame>)>,
+    // cycles counted before it
+    pub cycles: u64,
+    // the timer before it
+    pub timer: Timer,
+    // the UART's control register before it, and the byte it read from RX
+    pub uart_ctrl: u32,
+    pub uart_rx: Option<u8>,
+}
+
+pub struct History {
+    entries: VecDeque<Undo>,
+    depth: usize,
+}
+
+impl History {
+    // keep at most 'depth' instructions
+    pub fn new(depth: usize) -> History {
+        History {
+            entries: VecDeque::new(),
+            depth,
+        }
+    }
+
+    pub fn push(&mut self, undo: Undo) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(undo);
+    }
+
+    // the most recent instruction
+    pub fn pop(&mut self) -> Option<Undo> {
+        self.entries.pop_back()
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // the last instruction that wrote register 'reg'
+    pub fn last_reg_write(&self, reg: u32) -> Option<&Undo> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|u| u.regs.iter().any(|(r, _)| *r == reg))
+    }
+
+    // the last instruction that wrote the byte at 'addr'
+    pub fn last_mem_write(&self, addr: u32) -> Option<&Undo> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|u| u.mem.iter().any(|(a, _)| *a == addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let mut history = History::new(2);
+        for count in 1..=3 {
+            history.push(Undo {
+                count,
+                pc: 4 * count as u32,
+                regs: vec![(count as u32, 0)],
+                mem: vec![(0x100, None)],
+                ..Undo::default()
+            });
+        }
+        assert_eq!(2, history.len());
+        // the oldest was dropped
+        assert_eq!(None, history.last_reg_write(1));
+        assert_eq!(Some(2), history.last_reg_write(2).map(|u| u.count));
+        assert_eq!(Some(12), history.last_mem_write(0x100).map(|u| u.pc));
+        assert_eq!(None, history.last_mem_write(0x101));
+
+        assert_eq!(Some(3), history.pop().map(|u| u.count));
+        assert_eq!(Some(8), history.last_mem_write(0x100).map(|u| u.pc));
+        history.clear();
+        assert_eq!(None, history.pop());
+    }
+}
@@ -1,7 +1,26 @@
 use super::super::util::*;
+use super::breakpoint::{self, Entry};
+pub use super::breakpoint::{Breakpoint, Breakpoints};
+use super::callstack::{self, CallStack};
+use super::definedness::{self, Origin, Shadow};
+use super::devices::cache::{Cache, CacheConfig};
+use super::devices::csr;
 use super::devices::mem;
 use super::devices::rf;
+use super::devices::timer::{self, Timer};
+use super::devices::uart::{self, Uart};
+use super::devices::Peripheral;
+use super::history;
+use super::predictor::{Predictor, PredictorConfig};
+use super::profile::Profiler;
 use super::rv32i::*;
+use super::snapshot;
+use super::syscall;
+use super::trace;
+use super::trap::{Exception, Interrupt, TrapFallback};
+use super::vcd;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
@@ -18,10 +37,157 @@ const SSEG_WIDTH: u32 = 2;
 const SWITCHES_ADDR: u32 = 0x11000000;
 const SWITCHES_WIDTH: u32 = 2;
 
+// where the UART is, unless it is moved
+const UART_ADDR: u32 = 0x11100000;
+
+// where the timer is, unless it is moved
+const TIMER_ADDR: u32 = 0x11140000;
+
+// why the MCU stopped executing
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StopReason {
+    // an exception was raised with no trap handler installed
+    Trap(Exception, u32),
+    // the program called exit under semihosting
+    Exit(u32),
+    // a jump or branch to itself at the PC
+    SelfLoop(u32),
+    // the PC reached the configured halt address
+    HaltAddress(u32),
+    // the program wrote this value to the exit register
+    ExitWrite(u32),
+    // the instruction budget ran out
+    Budget(u64),
+    // the program wrote this value to tohost, riscv-tests style:
+    // 1 for a pass, (test number << 1) | 1 for a failure
+    ToHost(u32),
+    // the instruction at the PC hit a watchpoint; execution can continue
+    Watchpoint(u32, mem::WatchHit),
+    // breakpoint 'id' at the PC, before executing it
+    Breakpoint(u32, u32),
+    // a run toward a goal reached it at the PC
+    Reached(u32),
+    // the instruction at the PC broke the stack guard; execution can continue
+    Guard(u32, mem::Violation),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Trap(e, pc) => write!(f, "unhandled {} at {:#010X}", e, pc),
+            StopReason::Exit(code) => write!(f, "exited with status {}", *code as i32),
+            StopReason::SelfLoop(pc) => write!(f, "jump to self at {:#010X}", pc),
+            StopReason::HaltAddress(pc) => write!(f, "reached halt address {:#010X}", pc),
+            StopReason::ExitWrite(data) => write!(f, "wrote {:#010X} to the exit register", data),
+            StopReason::Budget(n) => write!(f, "instruction budget of {} exhausted", n),
+            StopReason::ToHost(1) => write!(f, "test passed"),
+            StopReason::ToHost(data) if data & 1 == 1 => {
+                write!(f, "test {} failed", data >> 1)
+            }
+            StopReason::ToHost(data) => write!(f, "wrote {:#010X} to tohost", data),
+            StopReason::Watchpoint(pc, hit) => {
+                write!(f, "watchpoint hit at {:#010X}: {}", pc, hit)
+            }
+            StopReason::Breakpoint(id, pc) => write!(f, "breakpoint {} at {:#010X}", id, pc),
+            StopReason::Reached(pc) => write!(f, "reached {:#010X}", pc),
+            StopReason::Guard(pc, v) => write!(f, "{} at {:#010X}", v, pc),
+        }
+    }
+}
+
+// conditions under which a program is considered finished
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HaltConfig {
+    // stop on an instruction that jumps to itself, e.g. 'done: j done'
+    pub self_loop: bool,
+    // stop before executing the instruction at this address
+    pub addr: Option<u32>,
+    // stop when the program writes to this MMIO register
+    pub exit_addr: Option<u32>,
+    // stop after executing this many instructions
+    pub budget: Option<u64>,
+    // stop when the program writes a nonzero word here
+    pub tohost: Option<u32>,
+}
+
+impl Default for HaltConfig {
+    fn default() -> HaltConfig {
+        HaltConfig {
+            self_loop: true,
+            addr: None,
+            exit_addr: None,
+            budget: None,
+            tohost: None,
+        }
+    }
+}
+
+// where a run toward a goal ends, if nothing stops it first
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Goal {
+    // after one instruction
+    Step,
+    // at 'pc' with the stack pointer at 'sp', as when a call returns
+    Return { pc: u32, sp: u32 },
+    // after returning from the function 'depth' calls down
+    Out { depth: u32 },
+    // before executing the instruction at this address
+    Address(u32),
+}
+
 pub struct MCU {
     pub pc: u32,
     mem: mem::Memory,
     rf: rf::RegisterFile,
+    csr: csr::CSRFile,
+    trap_fallback: TrapFallback,
+    stop: Option<StopReason>,
+    semihosting: Option<syscall::Semihosting>,
+    // first address past the loaded program
+    prog_end: u32,
+    // where a restored snapshot left the program break, for semihosting
+    // enabled after restoring
+    brk: Option<u32>,
+    // where execution starts after a reset
+    entry: u32,
+    halt: HaltConfig,
+    // instructions executed since reset
+    count: u64,
+    // cycles since reset: one for each instruction, plus the penalties of
+    // cache misses and branch mispredictions
+    cycles: u64,
+    tracer: Option<trace::Tracer>,
+    vcd: Option<vcd::Vcd>,
+    // the last instruction executed
+    retired: Option<trace::Record>,
+    // undo log, and the entry for the instruction being executed
+    history: history::History,
+    undo: Option<history::Undo>,
+    // memory access and exception of the instruction being executed, and
+    // the result a semihosted call it made returned in a0
+    access: Option<trace::Access>,
+    raised: Option<Exception>,
+    syscall_ret: Option<u32>,
+    breakpoints: Breakpoints,
+    // the PC of the breakpoint last stopped at, which running steps past
+    resume_at: Option<u32>,
+    // symbols of the loaded ELF, at their addresses in memory
+    symbols: HashMap<String, u32>,
+    // calls that have not returned
+    calls: CallStack,
+    // undefined values in memory, when they are tracked
+    shadow: Option<Shadow>,
+    // executions of each instruction, function and operation, when profiling
+    profiler: Option<Profiler>,
+    // a model of a branch predictor, counting its mispredictions
+    predictor: Option<Predictor>,
+    // the UART and its base address; its registers are mirrored in IO
+    // memory for the debugger
+    uart: Uart,
+    uart_addr: u32,
+    // the timer and its base address, mirrored the same way
+    timer: Timer,
+    timer_addr: u32,
 }
 
 impl MCU {
@@ -30,12 +196,44 @@ impl MCU {
             pc: 0,
             mem: mem::Memory::new(MEM_SIZE as u32),
             rf: rf::RegisterFile::init(),
+            csr: csr::CSRFile::init(),
+            trap_fallback: TrapFallback::Halt,
+            stop: None,
+            semihosting: None,
+            prog_end: 0,
+            brk: None,
+            entry: 0,
+            tracer: None,
+            vcd: None,
+            retired: None,
+            history: history::History::new(0),
+            undo: None,
+            access: None,
+            raised: None,
+            syscall_ret: None,
+            breakpoints: Breakpoints::default(),
+            resume_at: None,
+            symbols: HashMap::new(),
+            calls: CallStack::default(),
+            shadow: None,
+            profiler: None,
+            predictor: None,
+            uart: Uart::default(),
+            uart_addr: UART_ADDR,
+            timer: Timer::default(),
+            timer_addr: TIMER_ADDR,
+            halt: HaltConfig::default(),
+            count: 0,
+            cycles: 0,
         };
 
         // map IO
         mcu.mem.add_io(LEDS_ADDR, LEDS_WIDTH);
         mcu.mem.add_io(SSEG_ADDR, SSEG_WIDTH);
         mcu.mem.add_io(SWITCHES_ADDR, SWITCHES_WIDTH);
+        mcu.mem.add_io(UART_ADDR, uart::WIDTH);
+        mcu.mem.add_io(TIMER_ADDR, timer::WIDTH);
+        mcu.sync_io();
 
         mcu
     }
@@ -50,7 +248,281 @@ impl MCU {
     // Loads a binary from the path "binary" into the main memory.
     // Text section begins at zero. Binary should not exceed 64 kB.
     pub fn load_bin(&mut self, binary: &str) {
-        self.mem.prog(io::file_to_bytes(binary));
+        self.load(io::file_to_bytes(binary));
+    }
+
+    // Loads a binary already in memory, as a list of 4-byte words.
+    pub fn load(&mut self, binary: Vec<Vec<u8>>) {
+        self.prog_end = (binary.len() * 4) as u32;
+        self.brk = None;
+        self.entry = 0;
+        self.history.clear();
+        self.resume_at = None;
+        self.symbols.clear();
+        self.calls.clear();
+        self.clear_shadow();
+        self.clear_stats();
+        self.mem.prog(binary);
+        self.relayout_guard();
+        if let Some(sh) = &mut self.semihosting {
+            sh.set_heap(self.prog_end, MEM_SIZE as u32);
+        }
+    }
+
+    // Loads an ELF executable and jumps to its entry point.
+    // Segments are placed relative to the 64 kB window holding the lowest
+    // one, so programs linked high up, like riscv-tests at 0x80000000, run
+    // from main memory as long as their code is PC-relative.
+    // Returns the offset subtracted from every address in the file.
+    pub fn load_elf(&mut self, elf: &elf::Elf) -> Result<u32, String> {
+        let base = match elf.segments.iter().map(|s| s.addr).min() {
+            None => return Err(String::from("no loadable segments")),
+            Some(addr) => addr & !(MEM_SIZE as u32 - 1),
+        };
+
+        let mut end = 0;
+        for seg in elf.segments.iter() {
+            let addr = seg.addr - base;
+            if addr as usize + seg.data.len() > MEM_SIZE {
+                return Err(format!(
+                    "segment at {:#010X} does not fit in memory",
+                    seg.addr
+                ));
+            }
+            for (i, byte) in seg.data.iter().enumerate() {
+                self.mem
+                    .poke(addr + i as u32, *byte as u32, mem::Size::Byte, |_s| {});
+            }
+            end = end.max(addr + seg.data.len() as u32);
+        }
+
+        // each segment with its own permissions, the rest of memory read-write
+        self.mem.set_regions(
+            elf.segments
+                .iter()
+                .map(|seg| mem::Region {
+                    begin: seg.addr - base,
+                    end: seg.addr - base + seg.data.len() as u32,
+                    perms: mem::Perms {
+                        read: seg.flags & elf::PF_R != 0,
+                        write: seg.flags & elf::PF_W != 0,
+                        exec: seg.flags & elf::PF_X != 0,
+                    },
+                })
+                .collect(),
+        );
+        self.prog_end = end;
+        self.brk = None;
+        self.entry = elf.entry.wrapping_sub(base);
+        self.history.clear();
+        self.resume_at = None;
+        self.symbols = elf
+            .symbols
+            .iter()
+            .map(|(name, addr)| (name.clone(), addr.wrapping_sub(base)))
+            .collect();
+        self.calls.clear();
+        self.clear_shadow();
+        self.clear_stats();
+        self.relayout_guard();
+        self.pc = self.entry;
+        if let Some(sh) = &mut self.semihosting {
+            sh.set_heap(self.prog_end, MEM_SIZE as u32);
+        }
+        Ok(base)
+    }
+
+    // service ecalls as newlib-style syscalls instead of trapping
+    // 'input' feeds reads from stdin, None reads as end-of-file
+    pub fn enable_semihosting(&mut self, input: Option<Box<dyn Read + Send>>) {
+        let mut sh = syscall::Semihosting::new(input);
+        sh.set_heap(self.prog_end, MEM_SIZE as u32);
+        if let Some(brk) = self.brk {
+            sh.brk(brk);
+        }
+        self.semihosting = Some(sh);
+    }
+
+    pub fn disable_semihosting(&mut self) {
+        self.semihosting = None;
+    }
+
+    // take any console output written by the program since the last call
+    pub fn take_console(&mut self) -> Vec<u8> {
+        let mut out = match &mut self.semihosting {
+            None => Vec::new(),
+            Some(sh) => sh.take_output(),
+        };
+        out.extend(self.uart.take_tx());
+        out
+    }
+
+    // move the UART to 'addr'
+    pub fn set_uart_addr(&mut self, addr: u32) {
+        self.mem.remove_io(self.uart_addr);
+        self.uart_addr = addr;
+        self.mem.add_io(addr, uart::WIDTH);
+        self.sync_io();
+    }
+
+    // move the timer to 'addr'
+    pub fn set_timer_addr(&mut self, addr: u32) {
+        self.mem.remove_io(self.timer_addr);
+        self.timer_addr = addr;
+        self.mem.add_io(addr, timer::WIDTH);
+        self.sync_io();
+    }
+
+    // bytes arriving at the UART, e.g. typed on the keyboard
+    pub fn uart_receive(&mut self, data: &[u8]) {
+        self.uart.receive(data);
+        self.sync_io();
+    }
+
+    // the peripheral with a register at 'addr', and the register's offset
+    // from its base
+    fn peripheral(&mut self, addr: u32) -> Option<(&mut dyn Peripheral, u32)> {
+        let uart_offset = addr.wrapping_sub(self.uart_addr);
+        let timer_offset = addr.wrapping_sub(self.timer_addr);
+        let (device, offset): (&mut dyn Peripheral, u32) = if uart_offset < uart::WIDTH {
+            (&mut self.uart, uart_offset)
+        } else if timer_offset < timer::WIDTH {
+            (&mut self.timer, timer_offset)
+        } else {
+            return None;
+        };
+        if offset & 0b11 == 0 {
+            Some((device, offset))
+        } else {
+            None
+        }
+    }
+
+    // copy the peripherals' registers to their IO memory
+    fn sync_io(&mut self) {
+        let devices: [(u32, &dyn Peripheral); 2] =
+            [(self.uart_addr, &self.uart), (self.timer_addr, &self.timer)];
+        for (base, device) in devices.iter() {
+            for offset in device.registers() {
+                let data = device.peek(*offset);
+                self.mem.poke(base + offset, data, mem::Size::Word, |_s| {});
+            }
+        }
+    }
+
+    // the whole machine, with its breakpoints, in the format described in
+    // snapshot.rs
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = snapshot::Writer::new();
+        w.u32(self.pc);
+        w.u32(self.entry);
+        w.u32(self.prog_end);
+        w.u32(match &self.semihosting {
+            Some(sh) => sh.program_break(),
+            None => self.brk.unwrap_or(self.prog_end),
+        });
+        w.u64(self.count);
+        w.u64(self.cycles);
+        self.rf.save(&mut w);
+        self.csr.save(&mut w);
+        self.mem.save(&mut w);
+        w.u32(self.uart_addr);
+        w.u32(self.timer_addr);
+        w.u32(self.breakpoints.list().len() as u32);
+        for e in self.breakpoints.list() {
+            w.u32(e.enabled as u32);
+            w.u64(e.bp.hits);
+            w.str(&e.bp.to_string());
+        }
+        w.buf
+    }
+
+    // resume from a snapshot, replacing the breakpoints with its own
+    // on an error the MCU is left as it was
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        let mut r = snapshot::Reader::new(snapshot)?;
+        let pc = r.u32()?;
+        let entry = r.u32()?;
+        let prog_end = r.u32()?;
+        let brk = r.u32()?;
+        let count = r.u64()?;
+        let cycles = r.u64()?;
+        let rf = rf::RegisterFile::restore(&mut r)?;
+        let csr = csr::CSRFile::restore(&mut r)?;
+        let mem = mem::Memory::restore(&mut r)?;
+        if mem.size() != MEM_SIZE as u32 {
+            return Err(format!(
+                "snapshot has {} bytes of memory, expected {}",
+                mem.size(),
+                MEM_SIZE
+            ));
+        }
+        let uart_addr = r.u32()?;
+        let timer_addr = r.u32()?;
+        let mut breakpoints = Breakpoints::default();
+        for _ in 0..r.u32()? {
+            let enabled = r.u32()? != 0;
+            let hits = r.u64()?;
+            let spec = r.str()?;
+            let mut bp = Breakpoint::parse(&spec, &HashMap::new())
+                .map_err(|e| format!("bad breakpoint {}: {}", spec, e))?;
+            bp.hits = hits;
+            let id = breakpoints.add(bp);
+            breakpoints.set_enabled(id, enabled);
+        }
+        r.finish()?;
+
+        self.pc = pc;
+        self.entry = entry;
+        self.prog_end = prog_end;
+        self.brk = Some(brk);
+        if let Some(sh) = &mut self.semihosting {
+            sh.set_heap(prog_end, MEM_SIZE as u32);
+            sh.brk(brk);
+        }
+        self.count = count;
+        self.cycles = cycles;
+        self.rf = rf;
+        self.csr = csr;
+        let watchpoints = self.mem.watchpoints().to_vec();
+        let guard = self.mem.guard();
+        let protect = self.mem.protection();
+        let (icache, dcache) = self.mem.take_caches();
+        self.mem = mem;
+        self.mem.set_caches(icache, dcache);
+        self.mem.set_guard(guard);
+        self.mem.set_protection(protect);
+        for w in watchpoints {
+            self.mem.add_watchpoint(w);
+        }
+        self.breakpoints = breakpoints;
+        self.clear_shadow();
+        self.clear_stats();
+        // the UART keeps its control register, but not what it received
+        self.uart_addr = uart_addr;
+        self.timer_addr = timer_addr;
+        if !self.mem.is_mapped(self.uart_addr) {
+            self.mem.add_io(self.uart_addr, uart::WIDTH);
+        }
+        let ctrl = self.mem_rd(self.uart_addr + uart::CTRL, mem::Size::Word);
+        self.uart.write(uart::CTRL, ctrl);
+        // and the timer all of its registers
+        if !self.mem.is_mapped(self.timer_addr) {
+            self.mem.add_io(self.timer_addr, timer::WIDTH);
+        }
+        self.timer = Timer::default();
+        for offset in self.timer.registers() {
+            let data = self.mem_rd(self.timer_addr + offset, mem::Size::Word);
+            self.timer.write(*offset, data);
+        }
+        self.sync_io();
+        // resuming continues past a breakpoint at the PC
+        self.resume_at = Some(pc);
+        self.calls.clear();
+        self.stop = None;
+        self.retired = None;
+        self.history.clear();
+        Ok(())
     }
 
     pub fn dump<L>(&self, path: &str, logger: L)
@@ -126,18 +598,544 @@ impl MCU {
     }
 
     // step once; closure defines logging method
-    pub fn step<L>(&mut self, logger: L)
+    // returns the reason the MCU stopped, if it has
+    pub fn step<L>(&mut self, logger: L) -> Option<StopReason>
+    where
+        L: Fn(&str),
+    {
+        self.resume_at = None;
+        if self.stop.is_some() {
+            return self.stop;
+        }
+        if let Some(budget) = self.halt.budget {
+            if self.count >= budget {
+                self.stop = Some(StopReason::Budget(budget));
+                return self.stop;
+            }
+        }
+        if self.halt.addr == Some(self.pc) {
+            self.stop = Some(StopReason::HaltAddress(self.pc));
+            return self.stop;
+        }
+
+        if self.history.enabled() {
+            self.undo = Some(history::Undo {
+                count: self.count + 1,
+                pc: self.pc,
+                cycles: self.cycles,
+                timer: self.timer,
+                uart_ctrl: self.uart.peek(uart::CTRL),
+                ..history::Undo::default()
+            });
+        }
+        // an interrupt enters its handler, which runs from this instruction
+        self.take_interrupt();
+        let cycles = self.cycles;
+
+        let pc = self.pc;
+        // a fetch the permissions do not allow reads nothing
+        let fault = self
+            .check_access(pc, mem::Size::Word, mem::AccessKind::Exec, |s| logger(s))
+            .err();
+        let (ir, raw) = match fault {
+            None => {
+                self.mem.cache(pc, mem::Size::Word, mem::AccessKind::Exec);
+                self.fetch(|s| logger(s))
+            }
+            Some(_) => (decode::decode(0), 0),
+        };
+        self.access = None;
+        self.raised = None;
+        self.syscall_ret = None;
+        self.mem.take_hit();
+        self.mem.take_violation();
+        let old_rd = self.rf.rd(ir.rd);
+        let sources = (self.rf.origin(ir.rs1), self.rf.origin(ir.rs2));
+        match fault {
+            Some(e) => self.trap(e, pc, |s| logger(s)),
+            None => match MCU::validate(ir, self.pc, |s| logger(s)) {
+                Ok(ir) => self.exec(ir, |s| logger(s)),
+                Err(e) => self.trap(e, raw, |s| logger(s)),
+            },
+        }
+        self.count += 1;
+        self.cycles += 1 + self.mem.take_stall();
+        self.retired = Some(self.retire(pc, raw, ir));
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(&self.retired.unwrap(), self.pc, self.calls.frames());
+        }
+        if let Some(predictor) = &mut self.predictor {
+            // a branch or jump that traps never reaches its target
+            if self.retired.unwrap().trap.is_none() {
+                self.cycles += predictor.observe(pc, &ir, self.pc);
+            }
+        }
+        // the timer ticks for this instruction, or each of its cycles
+        if self.timer.enabled() {
+            self.timer.tick(self.cycles - cycles);
+            self.sync_io();
+        }
+        let calls = self.track_calls(pc, ir, |s| logger(s));
+        if self.shadow.is_some() {
+            self.track_definedness(pc, ir, sources, |s| logger(s));
+        }
+        if let Some((2, sp)) = self.retired.unwrap().rd {
+            self.mem.check_sp(sp, |s| logger(s));
+        }
+        let uart_rx = self.uart.take_read();
+        if let Some(mut undo) = self.undo.take() {
+            // a semihosted call logs its own write to a0
+            if let (Some((rd, _)), None) = (self.retired.unwrap().rd, self.syscall_ret) {
+                undo.regs.push((rd, old_rd));
+            }
+            undo.calls = calls;
+            undo.uart_rx = uart_rx;
+            self.history.push(undo);
+        }
+        if self.tracer.is_some() {
+            self.trace(|s| logger(s));
+        }
+        if self.vcd.is_some() {
+            self.sample_vcd(pc, raw, |s| logger(s));
+        }
+
+        // nothing can change the PC after this, so the program is done,
+        // unless it is waiting for an interrupt
+        if self.stop.is_none() && self.halt.self_loop && self.pc == pc && !self.can_interrupt() {
+            self.stop = Some(StopReason::SelfLoop(pc));
+        }
+        // unlike the others, this stop only lasts for one step
+        if let (None, Some(hit)) = (self.stop, self.mem.take_hit()) {
+            return Some(StopReason::Watchpoint(pc, hit));
+        }
+        if let (None, Some(v)) = (self.stop, self.mem.take_violation()) {
+            return Some(StopReason::Guard(pc, v));
+        }
+        self.stop
+    }
+
+    // step until the MCU stops
+    pub fn run<L>(&mut self, logger: L) -> StopReason
     where
         L: Fn(&str),
     {
-        let ir = self.fetch(|s| logger(s));
-        let ir = MCU::validate(ir.0, self.pc, |s| logger(s));
-        self.exec(ir, |s| logger(s));
+        loop {
+            if let Some(reason) = self.step(&logger) {
+                return reason;
+            }
+        }
+    }
+
+    // run until a breakpoint, a stop or 'limit' instructions, whichever
+    // comes first; returns None at the limit
+    // breakpoints are checked before executing, except one the MCU last
+    // stopped at, so running again continues past it
+    pub fn run_until<L>(&mut self, limit: Option<u64>, logger: L) -> Option<StopReason>
+    where
+        L: Fn(&str),
+    {
+        self.run_toward(None, limit, logger)
+    }
+
+    // as run_until, but stopping with Reached at 'goal', which keeps track
+    // of the run so it can be continued in steps of 'limit' instructions
+    pub fn run_goal<L>(
+        &mut self,
+        goal: &mut Goal,
+        limit: Option<u64>,
+        logger: L,
+    ) -> Option<StopReason>
+    where
+        L: Fn(&str),
+    {
+        self.run_toward(Some(goal), limit, logger)
+    }
+
+    // the goal of stepping over the instruction at the PC: a call runs
+    // until it returns, anything else is one step
+    pub fn step_over_goal(&self) -> Goal {
+        let (ir, _) = self.fetch(|_s| {});
+        if ir.is_call() {
+            Goal::Return {
+                pc: self.pc.wrapping_add(4),
+                sp: self.rf.rd(2),
+            }
+        } else {
+            Goal::Step
+        }
+    }
+
+    fn run_toward<L>(
+        &mut self,
+        mut goal: Option<&mut Goal>,
+        limit: Option<u64>,
+        logger: L,
+    ) -> Option<StopReason>
+    where
+        L: Fn(&str),
+    {
+        let mut n = 0;
+        loop {
+            if limit == Some(n) {
+                return None;
+            }
+            if self.stop.is_none()
+                && self.resume_at != Some(self.pc)
+                && !self.breakpoints.is_empty()
+            {
+                // the breakpoints read the MCU they belong to
+                let mut breakpoints = std::mem::take(&mut self.breakpoints);
+                let hit = breakpoints.check(self, &logger);
+                self.breakpoints = breakpoints;
+                if let Some(id) = hit {
+                    self.resume_at = Some(self.pc);
+                    return Some(StopReason::Breakpoint(id, self.pc));
+                }
+            }
+            if let Some(reason) = self.step(&logger) {
+                return Some(reason);
+            }
+            n += 1;
+            if let Some(goal) = &mut goal {
+                if self.reached(goal) {
+                    // a breakpoint here would stop at the same place again
+                    self.resume_at = Some(self.pc);
+                    return Some(StopReason::Reached(self.pc));
+                }
+            }
+        }
+    }
+
+    // whether the instruction just executed reached 'goal'
+    fn reached(&self, goal: &mut Goal) -> bool {
+        match goal {
+            Goal::Step => true,
+            Goal::Return { pc, sp } => self.pc == *pc && self.rf.rd(2) == *sp,
+            Goal::Address(addr) => self.pc == *addr,
+            Goal::Out { depth } => match self.retired {
+                Some(r) if r.trap.is_none() && r.ir.is_call() => {
+                    *depth += 1;
+                    false
+                }
+                Some(r) if r.trap.is_none() && r.ir.is_return() => {
+                    if *depth == 0 {
+                        return true;
+                    }
+                    *depth -= 1;
+                    false
+                }
+                _ => false,
+            },
+        }
     }
 
     pub fn reset(&mut self) {
-        self.pc = 0;
+        self.pc = self.entry;
+        self.resume_at = None;
+        self.calls.clear();
+        self.clear_shadow();
+        self.clear_stats();
         self.rf.reset();
+        self.csr.reset();
+        self.uart.write(uart::CTRL, 0);
+        self.timer = Timer::default();
+        self.sync_io();
+        self.stop = None;
+        self.count = 0;
+        self.cycles = 0;
+        self.retired = None;
+        self.history.clear();
+    }
+
+    // choose when a program is considered finished
+    // the exit register is mapped as a word-wide IO device if needed
+    pub fn set_halt_config(&mut self, halt: HaltConfig) {
+        if let Some(addr) = halt.exit_addr {
+            if addr >= MEM_SIZE as u32 && !self.mem.is_mapped(addr) {
+                self.mem.add_io(addr, 4);
+            }
+        }
+        self.halt = halt;
+    }
+
+    // track undefined values through registers and memory, and report one
+    // that decides a branch, forms an address or is written to IO; reads
+    // of unset memory are no longer warned of, as most are harmless
+    pub fn set_definedness(&mut self, track: bool) {
+        self.shadow = if track { Some(Shadow::default()) } else { None };
+        self.mem.set_unset_warnings(!track);
+    }
+
+    // forget undefined values in memory and what was reported
+    fn clear_shadow(&mut self) {
+        if let Some(shadow) = &mut self.shadow {
+            shadow.clear();
+        }
+    }
+
+    // count executions of each instruction, function and operation, and
+    // how often each branch is taken
+    pub fn set_profiling(&mut self, profile: bool) {
+        self.profiler = if profile {
+            Some(Profiler::default())
+        } else {
+            None
+        };
+    }
+
+    // start the profile, the caches and the branch predictor over
+    fn clear_stats(&mut self) {
+        if self.profiler.is_some() {
+            self.profiler = Some(Profiler::default());
+        }
+        self.mem.reset_caches();
+        if let Some(predictor) = &mut self.predictor {
+            predictor.reset();
+        }
+    }
+
+    // the profile as tables, with the 'top' hottest instructions
+    pub fn profile_report(&self, top: usize) -> Option<String> {
+        self.profiler.as_ref().map(|p| p.report(&self.symbols, top))
+    }
+
+    // the profile as collapsed stacks, for flamegraph tools
+    pub fn profile_stacks(&self) -> Option<String> {
+        self.profiler.as_ref().map(|p| p.collapsed(&self.symbols))
+    }
+
+    // check the permissions of memory regions, raising access faults, or
+    // let the program write its code and execute data
+    pub fn set_protection(&mut self, protect: bool) {
+        self.mem.set_protection(protect);
+    }
+
+    // make the program's code read-execute, as a raw binary's is not
+    pub fn protect_text(&mut self, begin: u32, end: u32) {
+        self.mem.add_region(mem::Region {
+            begin,
+            end,
+            perms: mem::Perms::RX,
+        });
+    }
+
+    // warn of, or stop on, a stack pointer outside the stack and stores to
+    // the text section; None turns the checks off
+    pub fn set_stack_guard(&mut self, guard: Option<mem::StackGuard>) {
+        self.mem.set_guard(guard);
+    }
+
+    // the stack and text sections of the loaded program: from the ELF
+    // symbols _stack_bottom, _stack_top and _etext where it has them, or
+    // else the stack is the rest of memory above the program
+    pub fn stack_layout(&self) -> mem::StackGuard {
+        let symbol = |names: &[&str]| names.iter().find_map(|n| self.symbols.get(*n).copied());
+        mem::StackGuard {
+            stack_bottom: symbol(&["_stack_bottom", "__stack_bottom"]).unwrap_or(self.prog_end),
+            stack_top: symbol(&["_stack_top", "__stack_top"]).unwrap_or(MEM_SIZE as u32),
+            text_begin: 0,
+            text_end: symbol(&["_etext", "etext", "__etext"]).unwrap_or(self.prog_end),
+            stop: false,
+        }
+    }
+
+    // move the stack guard, if there is one, to a newly loaded program
+    fn relayout_guard(&mut self) {
+        if let Some(guard) = self.mem.guard() {
+            self.mem.set_guard(Some(mem::StackGuard {
+                stop: guard.stop,
+                ..self.stack_layout()
+            }));
+        }
+    }
+
+    // record every instruction retired from now on, or stop with None
+    pub fn set_tracer(&mut self, tracer: Option<trace::Tracer>) {
+        self.tracer = tracer;
+    }
+
+    // dump a waveform of every instruction retired from now on, or stop with None
+    pub fn set_vcd(&mut self, vcd: Option<vcd::Vcd>) {
+        self.vcd = vcd;
+    }
+
+    // what the last instruction executed did, if one has been since reset
+    pub fn retired(&self) -> Option<&trace::Record> {
+        self.retired.as_ref()
+    }
+
+    // stop after an instruction that makes a matching access
+    pub fn add_watchpoint(&mut self, watchpoint: mem::Watchpoint) {
+        self.mem.add_watchpoint(watchpoint);
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.mem.clear_watchpoints();
+    }
+
+    pub fn watchpoints(&self) -> &[mem::Watchpoint] {
+        self.mem.watchpoints()
+    }
+
+    // add a breakpoint, as described in breakpoint.rs, returning its id
+    pub fn add_breakpoint(&mut self, spec: &str) -> Result<u32, String> {
+        let bp = Breakpoint::parse(spec, &self.symbols)?;
+        Ok(self.breakpoints.add(bp))
+    }
+
+    // returns false if there is no breakpoint 'id'
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        self.breakpoints.remove(id)
+    }
+
+    // returns false if there is no breakpoint 'id'
+    pub fn enable_breakpoint(&mut self, id: u32, enabled: bool) -> bool {
+        self.breakpoints.set_enabled(id, enabled)
+    }
+
+    pub fn breakpoints(&self) -> &[Entry] {
+        self.breakpoints.list()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    // the calls that have not returned, innermost first, starting with the
+    // PC, e.g. '#1  0x00000018 in main+0x8'; symbols come from the loaded ELF
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut frames = vec![self.pc];
+        frames.extend(self.calls.frames().iter().rev().map(|f| f.call));
+        frames
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| format!("#{:<2} {}", i, callstack::describe(*addr, &self.symbols)))
+            .collect()
+    }
+
+    // the address of an address or 'SYMBOL[+OFFSET]' in the loaded ELF
+    pub fn locate(&self, location: &str) -> Result<u32, String> {
+        breakpoint::locate(location, &self.symbols)
+    }
+
+    // keep an undo log of the last 'depth' instructions, or none with 0
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history = history::History::new(depth);
+    }
+
+    // instructions that can be stepped back over
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    // undo the last instruction, returns false if there is no history
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.pop() {
+            None => return false,
+            Some(u) => u,
+        };
+        for (addr, byte) in undo.mem.iter().rev() {
+            self.mem.set_byte(*addr, *byte);
+        }
+        for (addr, data) in undo.csrs.iter().rev() {
+            self.csr.wr(*addr, *data);
+        }
+        for (reg, data) in undo.regs.iter().rev() {
+            self.rf.wr(*reg, *data);
+        }
+        if let Some((depth, popped)) = &undo.calls {
+            self.calls.rewind(*depth, popped);
+        }
+        self.pc = undo.pc;
+        self.count = undo.count - 1;
+        self.cycles = undo.cycles;
+        self.timer = undo.timer;
+        self.uart.write(uart::CTRL, undo.uart_ctrl);
+        if let Some(byte) = undo.uart_rx {
+            self.uart.unread(byte);
+        }
+        self.sync_io();
+        self.stop = None;
+        self.retired = None;
+        true
+    }
+
+    // step back until a breakpoint would stop the MCU, returning its id,
+    // or None if the history ran out first
+    // hits are not counted, and running again continues past it
+    pub fn reverse_continue(&mut self) -> Option<u32> {
+        while self.step_back() {
+            if let Some(id) = self.breakpoints.matching(self) {
+                self.resume_at = Some(self.pc);
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    // the last instruction in the history to write register 'reg'
+    pub fn last_reg_write(&self, reg: u32) -> Option<&history::Undo> {
+        self.history.last_reg_write(reg)
+    }
+
+    // the last instruction in the history to write the byte at 'addr'
+    pub fn last_mem_write(&self, addr: u32) -> Option<&history::Undo> {
+        self.history.last_mem_write(addr)
+    }
+
+    // instructions executed since reset
+    pub fn instr_count(&self) -> u64 {
+        self.count
+    }
+
+    // cycles since reset, as the cycle CSR counts them: one for each
+    // instruction, plus the penalties of cache misses and mispredictions
+    pub fn cycle_count(&self) -> u64 {
+        self.cycles
+    }
+
+    // model instruction and data caches, or None for no cache
+    pub fn set_caches(&mut self, icache: Option<CacheConfig>, dcache: Option<CacheConfig>) {
+        self.mem
+            .set_caches(icache.map(Cache::new), dcache.map(Cache::new));
+    }
+
+    // the configuration and counts of each cache, None if there are none
+    pub fn cache_report(&self) -> Option<String> {
+        let report: String = [
+            ("Instruction cache", self.mem.icache()),
+            ("Data cache", self.mem.dcache()),
+        ]
+        .iter()
+        .filter_map(|(name, cache)| cache.map(|c| c.report(name)))
+        .collect();
+        if report.is_empty() {
+            None
+        } else {
+            Some(report)
+        }
+    }
+
+    // model a branch predictor, or None for no predictor
+    pub fn set_predictor(&mut self, config: Option<PredictorConfig>) {
+        self.predictor = config.map(Predictor::new);
+    }
+
+    // the predictor's accuracy, with the 'top' most mispredicted PCs
+    pub fn predictor_report(&self, top: usize) -> Option<String> {
+        self.predictor
+            .as_ref()
+            .map(|p| p.report(&self.symbols, top))
+    }
+
+    // choose what happens to exceptions raised without a trap handler
+    pub fn set_trap_fallback(&mut self, fallback: TrapFallback) {
+        self.trap_fallback = fallback;
+    }
+
+    #[cfg(test)]
+    pub fn csr_rd(&self, addr: u32) -> Option<u32> {
+        self.csr.rd(addr)
     }
 
     // dump the register file
@@ -149,56 +1147,567 @@ impl MCU {
         rf_dump
     }
 
-    #[allow(dead_code)]
     pub fn rf_rd(&self, addr: u32) -> u32 {
         self.rf.rd(addr)
     }
 
     pub fn mem_rd(&self, addr: u32, size: mem::Size) -> u32 {
-        self.mem.rd(addr, size, |_s| {})
+        self.mem.peek(addr, size, |_s| {})
     }
 
     fn incr_pc(&mut self) {
         self.pc = self.pc.overflowing_add(4).0;
     }
 
-    // validates the instruction, logging errors, returns a fixed instruction
-    pub fn validate<L>(ir: decode::Instruction, pc: u32, logger: L) -> decode::Instruction
+    // validates the instruction, logging errors
+    // returns the exception to raise if it cannot be executed
+    pub fn validate<L>(
+        ir: decode::Instruction,
+        pc: u32,
+        logger: L,
+    ) -> Result<decode::Instruction, Exception>
+    where
+        L: Fn(&str),
+    {
+        // check for invalid instruction
+        if let decode::Operation::Invalid = ir.op {
+            logger(&format!("[{:#010X}] Error: Invalid instruction.", pc));
+            return Err(Exception::IllegalInstr);
+        };
+
+        // check for read/write non-existent register
+        if ir.rd > 31 || ir.rs1 > 31 || ir.rs2 > 31 {
+            logger(&format!(
+                "[{:#010X}] Error: Access to a non-existent register.",
+                pc
+            ));
+            return Err(Exception::IllegalInstr);
+        }
+        Ok(ir)
+    }
+
+    // raise an exception at the current PC
+    // 'tval' is written to mtval, e.g. the faulting instruction or address
+    fn trap<L>(&mut self, e: Exception, tval: u32, logger: L)
+    where
+        L: Fn(&str),
+    {
+        self.raised = Some(e);
+        let vector = self.csr.rd(csr::MTVEC).unwrap_or(0) & !0b11;
+
+        // no handler installed
+        if vector == 0 {
+            match self.trap_fallback {
+                TrapFallback::Halt => {
+                    logger(&format!(
+                        "[{:#010X}] Error: Unhandled exception: {}. Halting.",
+                        self.pc, e
+                    ));
+                    self.stop = Some(StopReason::Trap(e, self.pc));
+                }
+                TrapFallback::Skip => {
+                    logger(&format!(
+                        "[{:#010X}] Warning: Unhandled exception: {}. Skipping.",
+                        self.pc, e
+                    ));
+                    self.incr_pc();
+                }
+            }
+            return;
+        }
+
+        self.enter_trap(e.cause(), tval, vector);
+    }
+
+    // take the highest priority interrupt that is pending and enabled, if
+    // interrupts are enabled and there is a handler
+    fn take_interrupt(&mut self) {
+        // mip follows the devices, which the program cannot change
+        let mut mip = self.csr.rd(csr::MIP).unwrap_or(0)
+            & !(Interrupt::External.bit() | Interrupt::Timer.bit());
+        if self.uart.interrupt() {
+            mip |= Interrupt::External.bit();
+        }
+        if self.timer.interrupt() {
+            mip |= Interrupt::Timer.bit();
+        }
+        self.csr.wr(csr::MIP, mip);
+
+        let mstatus = self.csr.rd(csr::MSTATUS).unwrap_or(0);
+        let mtvec = self.csr.rd(csr::MTVEC).unwrap_or(0);
+        let pending = mip & self.csr.rd(csr::MIE).unwrap_or(0);
+        if mstatus & csr::MSTATUS_MIE == 0 || mtvec & !0b11 == 0 {
+            return;
+        }
+        let interrupt = match [Interrupt::External, Interrupt::Timer]
+            .iter()
+            .find(|i| pending & i.bit() != 0)
+        {
+            None => return,
+            Some(i) => *i,
+        };
+        let mut vector = mtvec & !0b11;
+        if mtvec & csr::MTVEC_VECTORED != 0 {
+            vector += 4 * interrupt.code();
+        }
+        self.enter_trap(interrupt.cause(), 0, vector);
+    }
+
+    // whether an interrupt could still be taken: interrupts are enabled,
+    // there is a handler and a device that may raise one is enabled in mie
+    fn can_interrupt(&self) -> bool {
+        let mstatus = self.csr.rd(csr::MSTATUS).unwrap_or(0);
+        let mtvec = self.csr.rd(csr::MTVEC).unwrap_or(0);
+        let mie = self.csr.rd(csr::MIE).unwrap_or(0);
+        if mstatus & csr::MSTATUS_MIE == 0 || mtvec & !0b11 == 0 {
+            return false;
+        }
+        (mie & Interrupt::External.bit() != 0 && self.uart.can_interrupt())
+            || (mie & Interrupt::Timer.bit() != 0 && self.timer.can_interrupt())
+    }
+
+    // save the PC and cause and jump to the handler at 'vector'
+    fn enter_trap(&mut self, cause: u32, tval: u32, vector: u32) {
+        self.csr_wr(csr::MEPC, self.pc);
+        self.csr_wr(csr::MCAUSE, cause);
+        self.csr_wr(csr::MTVAL, tval);
+        // stash the interrupt enable and disable interrupts in the handler
+        let mut mstatus = self.csr.rd(csr::MSTATUS).unwrap_or(0);
+        if mstatus & csr::MSTATUS_MIE != 0 {
+            mstatus |= csr::MSTATUS_MPIE;
+        } else {
+            mstatus &= !csr::MSTATUS_MPIE;
+        }
+        mstatus &= !csr::MSTATUS_MIE;
+        self.csr_wr(csr::MSTATUS, mstatus);
+        self.pc = vector;
+    }
+
+    // service an ecall under semihosting
+    // a7 holds the syscall number, a0-a2 the arguments, the result goes to a0
+    fn syscall<L>(&mut self, logger: L)
+    where
+        L: Fn(&str),
+    {
+        let num = self.rf.rd(17);
+        let a0 = self.rf.rd(10);
+        let a1 = self.rf.rd(11);
+        // a read or write moves no more than all of memory, and a shorter
+        // one is allowed, so a huge length cannot run the host out of memory
+        let a2 = self.rf.rd(12).min(MEM_SIZE as u32);
+
+        let ret = match num {
+            syscall::SYS_EXIT => {
+                self.stop = Some(StopReason::Exit(a0));
+                return;
+            }
+
+            // write(fd, buf, len)
+            syscall::SYS_WRITE => {
+                let data: Vec<u8> = (0..a2)
+                    .map(|i| {
+                        self.mem
+                            .rd(a1.overflowing_add(i).0, mem::Size::Byte, &logger)
+                            as u8
+                    })
+                    .collect();
+                match &mut self.semihosting {
+                    None => return,
+                    Some(sh) => sh.write(a0, &data) as u32,
+                }
+            }
+
+            // read(fd, buf, len)
+            syscall::SYS_READ => {
+                let data = match &mut self.semihosting {
+                    None => return,
+                    Some(sh) => sh.read(a0, a2),
+                };
+                match data {
+                    Err(e) => e as u32,
+                    Ok(data) => {
+                        for (i, b) in data.iter().enumerate() {
+                            self.mem_wr(
+                                a1.overflowing_add(i as u32).0,
+                                *b as u32,
+                                mem::Size::Byte,
+                                &logger,
+                            );
+                        }
+                        data.len() as u32
+                    }
+                }
+            }
+
+            // brk(addr)
+            syscall::SYS_BRK => match &mut self.semihosting {
+                None => return,
+                Some(sh) => sh.brk(a0),
+            },
+
+            _ => {
+                logger(&format!(
+                    "[{:#010X}] Warning: Unsupported syscall {}.",
+                    self.pc, num
+                ));
+                -syscall::ENOSYS as u32
+            }
+        };
+        if let Some(undo) = &mut self.undo {
+            undo.regs.push((10, self.rf.rd(10)));
+        }
+        self.rf.wr(10, ret);
+        self.syscall_ret = Some(ret);
+        self.incr_pc();
+    }
+
+    // keep the call stack for the instruction just executed at 'pc', warning
+    // of a return to an address no call would return to
+    // returns how to undo the change, if any
+    fn track_calls<L>(
+        &mut self,
+        pc: u32,
+        ir: decode::Instruction,
+        logger: L,
+    ) -> Option<(usize, Vec<callstack::Frame>)>
+    where
+        L: Fn(&str),
+    {
+        if self.raised.is_some() {
+            return None;
+        }
+        let depth = self.calls.frames().len();
+        if ir.is_call() {
+            self.calls.call(callstack::Frame {
+                call: pc,
+                target: self.pc,
+                ret: pc.wrapping_add(4),
+                sp: self.rf.rd(2),
+            });
+            return Some((depth, Vec::new()));
+        }
+        if !ir.is_return() {
+            return None;
+        }
+        match self.calls.ret(pc, self.pc) {
+            Ok(popped) if popped.is_empty() => None,
+            Ok(popped) => Some((self.calls.frames().len(), popped)),
+            Err(m) => {
+                logger(&format!(
+                    "[{:#010X}] Warning: Return to {}, which is not on the call stack. Expected {}.",
+                    m.pc,
+                    callstack::describe(m.target, &self.symbols),
+                    callstack::describe(m.expected, &self.symbols)
+                ));
+                None
+            }
+        }
+    }
+
+    // carry undefined values through the instruction just executed at 'pc',
+    // given where its source registers' values came from if undefined, and
+    // report a use of one
+    fn track_definedness<L>(
+        &mut self,
+        pc: u32,
+        ir: decode::Instruction,
+        sources: (Option<Origin>, Option<Origin>),
+        logger: L,
+    ) where
+        L: Fn(&str),
+    {
+        let mut shadow = match self.shadow.take() {
+            None => return,
+            Some(shadow) => shadow,
+        };
+        let rs1 = sources.0.filter(|_| ir.op.reads_rs1());
+        let rs2 = sources.1.filter(|_| ir.op.reads_rs2());
+        let class = ir.op.class();
+        let mut report = |used: definedness::Use, origin: Option<Origin>| {
+            if let Some(msg) = origin.and_then(|o| shadow.report(pc, used, o)) {
+                logger(&msg);
+            }
+        };
+        match class {
+            decode::OpClass::Branch => report(definedness::Use::Branch, rs1.or(rs2)),
+            decode::OpClass::Load | decode::OpClass::Store => {
+                report(definedness::Use::Address, rs1)
+            }
+            _ if ir.op == decode::Operation::JALR => report(definedness::Use::Address, rs1),
+            _ => (),
+        }
+
+        let access = self.access.filter(|_| self.raised.is_none());
+        let result = match (class, access) {
+            (decode::OpClass::Alu, _) => rs1.or(rs2),
+            (decode::OpClass::Load, Some(a)) => {
+                let mem = &self.mem;
+                shadow.load(pc, a.addr, a.bytes, |addr| mem.byte(addr).is_some())
+            }
+            (decode::OpClass::Store, Some(a)) => {
+                if a.addr >= MEM_SIZE as u32 {
+                    report(definedness::Use::Io, rs2);
+                }
+                shadow.store(a.addr, a.bytes, rs2);
+                None
+            }
+            _ => None,
+        };
+        if let (Some(origin), true) = (result, ir.op.writes_rd()) {
+            if self.raised.is_none() {
+                self.rf.set_origin(ir.rd, origin);
+            }
+        }
+        self.shadow = Some(shadow);
+    }
+
+    // what the instruction just executed did
+    fn retire(&self, pc: u32, raw: u32, ir: decode::Instruction) -> trace::Record {
+        let rd = if self.raised.is_some() {
+            None
+        } else if let Some(ret) = self.syscall_ret {
+            Some((10, ret))
+        } else if ir.op.writes_rd() && ir.rd != 0 {
+            Some((ir.rd, self.rf.rd(ir.rd)))
+        } else {
+            None
+        };
+        trace::Record {
+            count: self.count,
+            pc,
+            raw,
+            ir,
+            rd,
+            access: self.access,
+            trap: self.raised,
+        }
+    }
+
+    // write a trace record for the instruction just executed
+    fn trace<L>(&mut self, logger: L)
+    where
+        L: Fn(&str),
+    {
+        if let (Some(tracer), Some(record)) = (&mut self.tracer, &self.retired) {
+            if let Err(why) = tracer.record(record) {
+                logger(&format!(
+                    "Error: Could not write trace: {}. Tracing stopped.",
+                    why
+                ));
+                self.tracer = None;
+            }
+        }
+    }
+
+    // write the state after the instruction just executed to the waveform
+    fn sample_vcd<L>(&mut self, pc: u32, raw: u32, logger: L)
+    where
+        L: Fn(&str),
+    {
+        let state = vcd::State {
+            pc,
+            ir: raw,
+            rf: self.rf(),
+            leds: self.mem_rd(LEDS_ADDR, mem::Size::HalfWord),
+            sseg: self.mem_rd(SSEG_ADDR, mem::Size::HalfWord),
+            switches: self.mem_rd(SWITCHES_ADDR, mem::Size::HalfWord),
+            access: self.access,
+        };
+        if let Some(vcd) = &mut self.vcd {
+            if let Err(why) = vcd.sample(self.count - 1, &state) {
+                logger(&format!(
+                    "Error: Could not write waveform: {}. Waveform stopped.",
+                    why
+                ));
+                self.vcd = None;
+            }
+        }
+    }
+
+    // check the program may access memory, logging why not
+    // returns the access fault to raise if it may not
+    fn check_access<L>(
+        &self,
+        addr: u32,
+        size: mem::Size,
+        kind: mem::AccessKind,
+        logger: L,
+    ) -> Result<(), Exception>
+    where
+        L: Fn(&str),
+    {
+        match self.mem.check(addr, size, kind) {
+            Ok(()) => Ok(()),
+            Err(fault) => {
+                logger(&format!(
+                    "[{:#010X}] Error: Access fault: {}.",
+                    self.pc, fault
+                ));
+                Err(match kind {
+                    mem::AccessKind::Read => Exception::LoadAccessFault,
+                    mem::AccessKind::Write => Exception::StoreAccessFault,
+                    mem::AccessKind::Exec => Exception::InstrAccessFault,
+                })
+            }
+        }
+    }
+
+    // read memory for a load instruction
+    // returns None if an access fault was raised
+    fn load_data<L>(&mut self, addr: u32, size: mem::Size, logger: L) -> Option<u32>
+    where
+        L: Fn(&str),
+    {
+        if let Err(e) = self.check_access(addr, size, mem::AccessKind::Read, &logger) {
+            self.trap(e, addr, logger);
+            return None;
+        }
+        self.mem.cache(addr, size, mem::AccessKind::Read);
+        let data = match self.peripheral(addr) {
+            Some((device, offset)) => {
+                let data = device.read(offset) & size.mask();
+                self.sync_io();
+                self.mem.watch_read(addr, size, data);
+                data
+            }
+            None => self.mem.rd(addr, size, logger),
+        };
+        self.access = Some(trace::Access {
+            addr,
+            bytes: size.bytes(),
+            data,
+            write: false,
+        });
+        Some(data)
+    }
+
+    // write memory, saving what was there in the undo log
+    fn mem_wr<L>(&mut self, addr: u32, data: u32, size: mem::Size, logger: L)
+    where
+        L: Fn(&str),
+    {
+        if let Some(undo) = &mut self.undo {
+            for i in 0..size.bytes() {
+                let a = addr.wrapping_add(i);
+                undo.mem.push((a, self.mem.byte(a)));
+            }
+        }
+        self.mem.wr(addr, data, size, logger);
+    }
+
+    // write a CSR, saving its old value in the undo log
+    // returns false if it is not implemented or read-only
+    fn csr_wr(&mut self, addr: u32, data: u32) -> bool {
+        let old = self.csr.rd(addr);
+        if !self.csr.wr(addr, data) {
+            return false;
+        }
+        if let (Some(undo), Some(old)) = (&mut self.undo, old) {
+            undo.csrs.push((addr, old));
+        }
+        true
+    }
+
+    // store to memory, watching for writes to the exit register
+    // returns false if an access fault was raised
+    fn store<L>(&mut self, addr: u32, data: u32, size: mem::Size, logger: L) -> bool
+    where
+        L: Fn(&str),
+    {
+        if let Err(e) = self.check_access(addr, size, mem::AccessKind::Write, &logger) {
+            self.trap(e, addr, logger);
+            return false;
+        }
+        self.mem.cache(addr, size, mem::AccessKind::Write);
+        self.mem_wr(addr, data, size, logger);
+        if let Some((device, offset)) = self.peripheral(addr) {
+            device.write(offset, data & size.mask());
+            self.sync_io();
+        }
+        let data = match size {
+            mem::Size::Byte => data & 0xFF,
+            mem::Size::HalfWord => data & 0xFFFF,
+            mem::Size::Word => data,
+        };
+        self.access = Some(trace::Access {
+            addr,
+            bytes: size.bytes(),
+            data,
+            write: true,
+        });
+        if self.halt.exit_addr == Some(addr) {
+            self.stop = Some(StopReason::ExitWrite(data));
+        }
+        if self.halt.tohost == Some(addr) && data != 0 {
+            self.stop = Some(StopReason::ToHost(data));
+        }
+        true
+    }
+
+    // set the PC to a jump or branch target, raising an exception if misaligned
+    // returns false if the exception was raised
+    fn jump_to<L>(&mut self, target: u32, logger: L) -> bool
+    where
+        L: Fn(&str),
+    {
+        if target & 0b11 != 0 {
+            self.trap(Exception::InstrAddrMisaligned, target, logger);
+            return false;
+        }
+        self.pc = target;
+        true
+    }
+
+    // CSR read-modify-write
+    fn exec_csr<L>(&mut self, ir: &decode::Instruction, rs1: u32, logger: L)
     where
         L: Fn(&str),
     {
-        //    - check mem read value on LOAD
-        //    - check jump/branch target within text
-        let nop = decode::Instruction {
-            op: decode::Operation::ADD,
-            rs1: 0,
-            rs2: 0,
-            rd: 0,
-            imm: 0,
+        self.csr.set_counters(self.cycles, self.count);
+        let old = match self.csr.rd(ir.imm) {
+            None => {
+                logger(&format!(
+                    "[{:#010X}] Error: Access to a non-existent CSR {:#05X}.",
+                    self.pc, ir.imm
+                ));
+                self.trap(Exception::IllegalInstr, 0, logger);
+                return;
+            }
+            Some(d) => d,
         };
 
-        // check for invalid instruction
-        if let decode::Operation::Invalid = ir.op {
-            logger(&format!("[{:#010X}] Error: Invalid instruction.", pc));
-            return nop;
+        // immediate variants use the rs1 field as a zero-extended immediate
+        let src = match ir.op {
+            decode::Operation::CSRRWI | decode::Operation::CSRRSI | decode::Operation::CSRRCI => {
+                ir.rs1
+            }
+            _ => rs1,
         };
 
-        // check for read/write non-existent register
-        if ir.rd > 31 || ir.rs1 > 31 || ir.rs2 > 31 {
+        // set and clear do not write when rs1 is x0 (or zimm is 0)
+        let (new, write) = match ir.op {
+            decode::Operation::CSRRW | decode::Operation::CSRRWI => (src, true),
+            decode::Operation::CSRRS | decode::Operation::CSRRSI => (old | src, ir.rs1 != 0),
+            _ => (old & !src, ir.rs1 != 0),
+        };
+
+        if write && !self.csr_wr(ir.imm, new) {
             logger(&format!(
-                "[{:#010X}] Error: Access to a non-existent register.",
-                pc
+                "[{:#010X}] Error: Write to read-only CSR {:#05X}.",
+                self.pc, ir.imm
             ));
-            return nop;
+            self.trap(Exception::IllegalInstr, 0, logger);
+            return;
         }
-        ir
+        self.rf.wr(ir.rd, old);
+        self.incr_pc();
     }
 
     pub fn fetch<L>(&self, logger: L) -> (decode::Instruction, u32)
     where
         L: Fn(&str),
     {
-        let ir = self.mem.rd(self.pc, mem::Size::Word, logger);
+        let ir = self.mem.peek(self.pc, mem::Size::Word, logger);
         (decode::decode(ir), ir)
     }
 
@@ -209,7 +1718,7 @@ impl MCU {
         let rs1: u32 = self.rf.rd(ir.rs1);
         let rs2: u32 = self.rf.rd(ir.rs2);
         let mem_addr = rs1.overflowing_add(ir.imm).0;
-        let jalr_target = rs1.overflowing_add(ir.imm).0;
+        let jalr_target = rs1.overflowing_add(ir.imm).0 & !0b1;
         let branch_target = self.pc.overflowing_add(ir.imm).0;
         let jump_target = self.pc.overflowing_add(ir.imm).0;
 
@@ -219,6 +1728,7 @@ impl MCU {
         match ir.op {
             decode::Operation::Invalid => {
                 logger(&format!("[{:#010X}] Error: Instruction was corrupted. This is an error in the emulator, not the program.", self.pc));
+                self.trap(Exception::IllegalInstr, 0, logger);
             }
 
             decode::Operation::LUI => {
@@ -232,18 +1742,22 @@ impl MCU {
             }
 
             decode::Operation::JAL => {
-                self.rf.wr(ir.rd, self.pc as u32 + 4);
-                self.pc = jump_target;
+                let ret = self.pc as u32 + 4;
+                if self.jump_to(jump_target, logger) {
+                    self.rf.wr(ir.rd, ret);
+                }
             }
 
             decode::Operation::JALR => {
-                self.rf.wr(ir.rd, self.pc as u32 + 4);
-                self.pc = jalr_target;
+                let ret = self.pc as u32 + 4;
+                if self.jump_to(jalr_target, logger) {
+                    self.rf.wr(ir.rd, ret);
+                }
             }
 
             decode::Operation::BEQ => {
                 if rs1 as i32 == rs2 as i32 {
-                    self.pc = branch_target;
+                    self.jump_to(branch_target, logger);
                 } else {
                     self.incr_pc();
                 }
@@ -251,7 +1765,7 @@ impl MCU {
 
             decode::Operation::BNE => {
                 if rs1 as i32 != rs2 as i32 {
-                    self.pc = branch_target;
+                    self.jump_to(branch_target, logger);
                 } else {
                     self.incr_pc();
                 }
@@ -259,7 +1773,7 @@ impl MCU {
 
             decode::Operation::BLT => {
                 if (rs1 as i32) < (rs2 as i32) {
-                    self.pc = branch_target;
+                    self.jump_to(branch_target, logger);
                 } else {
                     self.incr_pc();
                 }
@@ -267,7 +1781,7 @@ impl MCU {
 
             decode::Operation::BGE => {
                 if (rs1 as i32) >= (rs2 as i32) {
-                    self.pc = branch_target;
+                    self.jump_to(branch_target, logger);
                 } else {
                     self.incr_pc();
                 }
@@ -275,7 +1789,7 @@ impl MCU {
 
             decode::Operation::BLTU => {
                 if rs1 < rs2 {
-                    self.pc = branch_target;
+                    self.jump_to(branch_target, logger);
                 } else {
                     self.incr_pc();
                 }
@@ -283,98 +1797,71 @@ impl MCU {
 
             decode::Operation::BGEU => {
                 if rs1 >= rs2 {
-                    self.pc = branch_target;
+                    self.jump_to(branch_target, logger);
                 } else {
                     self.incr_pc();
                 }
             }
 
             decode::Operation::LB => {
-                let mut byte =
-                    self.mem
-                        .rd(mem_addr.overflowing_add(ir.imm).0, mem::Size::Byte, logger);
-                // sign extend
-                if byte & 0b10000000 != 0 {
-                    byte |= 0xFFFFFF00;
+                if let Some(mut byte) = self.load_data(mem_addr, mem::Size::Byte, logger) {
+                    // sign extend
+                    if byte & 0b10000000 != 0 {
+                        byte |= 0xFFFFFF00;
+                    }
+                    self.rf.wr(ir.rd, byte);
+                    self.incr_pc();
                 }
-                self.rf.wr(ir.rd, byte);
-                self.incr_pc();
             }
 
             decode::Operation::LH => {
-                let mut halfword: u32 = self.mem.rd(
-                    mem_addr.overflowing_add(ir.imm).0,
-                    mem::Size::HalfWord,
-                    logger,
-                );
-                // sign extend
-                if halfword & 0b1000000000000000 != 0 {
-                    halfword |= 0xFFFF0000;
+                if let Some(mut halfword) = self.load_data(mem_addr, mem::Size::HalfWord, logger) {
+                    // sign extend
+                    if halfword & 0b1000000000000000 != 0 {
+                        halfword |= 0xFFFF0000;
+                    }
+                    self.rf.wr(ir.rd, halfword);
+                    self.incr_pc();
                 }
-                self.rf.wr(ir.rd, halfword);
-                self.incr_pc();
             }
 
             decode::Operation::LW => {
-                self.rf.wr(
-                    ir.rd,
-                    self.mem
-                        .rd(mem_addr.overflowing_add(ir.imm).0, mem::Size::Word, logger),
-                );
-                self.incr_pc();
+                if let Some(word) = self.load_data(mem_addr, mem::Size::Word, logger) {
+                    self.rf.wr(ir.rd, word);
+                    self.incr_pc();
+                }
             }
 
-            // unimplemented
             decode::Operation::LBU => {
-                self.rf.wr(
-                    ir.rd,
-                    self.mem
-                        .rd(mem_addr.overflowing_add(ir.imm).0, mem::Size::Byte, logger),
-                );
-                self.incr_pc();
+                if let Some(byte) = self.load_data(mem_addr, mem::Size::Byte, logger) {
+                    self.rf.wr(ir.rd, byte);
+                    self.incr_pc();
+                }
             }
 
-            // unimplemented
             decode::Operation::LHU => {
-                self.rf.wr(
-                    ir.rd,
-                    self.mem.rd(
-                        mem_addr.overflowing_add(ir.imm).0,
-                        mem::Size::HalfWord,
-                        logger,
-                    ),
-                );
-                self.incr_pc();
+                if let Some(halfword) = self.load_data(mem_addr, mem::Size::HalfWord, logger) {
+                    self.rf.wr(ir.rd, halfword);
+                    self.incr_pc();
+                }
             }
 
             decode::Operation::SB => {
-                self.mem.wr(
-                    mem_addr.overflowing_add(ir.imm).0,
-                    rs1,
-                    mem::Size::Byte,
-                    logger,
-                );
-                self.incr_pc();
+                if self.store(mem_addr, rs2, mem::Size::Byte, logger) {
+                    self.incr_pc();
+                }
             }
 
             decode::Operation::SH => {
-                self.mem.wr(
-                    mem_addr.overflowing_add(ir.imm).0,
-                    rs2,
-                    mem::Size::HalfWord,
-                    logger,
-                );
-                self.incr_pc();
+                if self.store(mem_addr, rs2, mem::Size::HalfWord, logger) {
+                    self.incr_pc();
+                }
             }
 
             decode::Operation::SW => {
-                self.mem.wr(
-                    mem_addr.overflowing_add(ir.imm).0,
-                    rs2,
-                    mem::Size::Word,
-                    logger,
-                );
-                self.incr_pc();
+                if self.store(mem_addr, rs2, mem::Size::Word, logger) {
+                    self.incr_pc();
+                }
             }
 
             decode::Operation::ADDI => {
@@ -474,6 +1961,45 @@ impl MCU {
                 self.rf.wr(ir.rd, rs1 & rs2);
                 self.incr_pc();
             }
+
+            // a single hart fetching straight from memory has nothing to order
+            decode::Operation::FENCE | decode::Operation::FENCEI => {
+                self.incr_pc();
+            }
+
+            decode::Operation::ECALL => {
+                if self.semihosting.is_some() {
+                    self.syscall(logger);
+                } else {
+                    self.trap(Exception::EcallM, 0, logger);
+                }
+            }
+
+            decode::Operation::EBREAK => {
+                self.trap(Exception::Breakpoint, self.pc, logger);
+            }
+
+            decode::Operation::MRET => {
+                self.pc = self.csr.rd(csr::MEPC).unwrap_or(0);
+                // restore the interrupt enable
+                let mut mstatus = self.csr.rd(csr::MSTATUS).unwrap_or(0);
+                if mstatus & csr::MSTATUS_MPIE != 0 {
+                    mstatus |= csr::MSTATUS_MIE;
+                } else {
+                    mstatus &= !csr::MSTATUS_MIE;
+                }
+                mstatus |= csr::MSTATUS_MPIE;
+                self.csr_wr(csr::MSTATUS, mstatus);
+            }
+
+            decode::Operation::CSRRW
+            | decode::Operation::CSRRS
+            | decode::Operation::CSRRC
+            | decode::Operation::CSRRWI
+            | decode::Operation::CSRRSI
+            | decode::Operation::CSRRCI => {
+                self.exec_csr(&ir, rs1, logger);
+            }
         };
     }
 
@@ -496,7 +2022,7 @@ impl MCU {
         let updated_state: u32;
         updated_state = prev_state ^ (0b1 << index);
         self.mem
-            .wr(SWITCHES_ADDR, updated_state, mem::Size::HalfWord, |_s| {});
+            .poke(SWITCHES_ADDR, updated_state, mem::Size::HalfWord, |_s| {});
     }
 }
 
@@ -504,33 +2030,26 @@ impl MCU {
 
 #[cfg(test)]
 mod tests {
+    use super::super::test_util::load_words;
     use super::*;
     use rand::Rng;
 
     #[test]
     fn test_all() {
-        // FAILING
-        // - 0x14, 20 SW
-        // - 0x1F, 31 LH
-        // - 0x20, 32 LB
-        // - 0x23, 35 SH
-        // - 0x24, 36 SB
-        let mut do_break = false;
         let mut mcu = MCU::new();
         mcu.load_bin("res/programs/test/all/bin");
-        loop {
-            // first test
-            if mcu.pc == 0x18 {
-                if do_break {
-                    break;
-                } else {
-                    do_break = true;
-                }
-            }
-            mcu.exec(mcu.fetch(|_s| {}).0, |_s| {});
+        // stop at 'j loop' after one pass through the tests
+        mcu.set_halt_config(HaltConfig {
+            addr: Some(0x1D4),
+            ..HaltConfig::default()
+        });
+        let mut stop = None;
+        while stop.is_none() {
+            stop = mcu.step(|_s| {});
             // if ssegs are 0xffff, test-all fails
             assert!(mcu.sseg() != 0xFFFF);
         }
+        assert_eq!(Some(StopReason::HaltAddress(0x1D4)), stop);
     }
 
     #[test]
@@ -620,4 +2139,1135 @@ mod tests {
         mcu.load_bin("./res/programs/test/all/bin");
         mcu.step(|_s| {});
     }
+
+    // write instruction words to memory starting at 'addr'
+    fn prog_at(mcu: &mut MCU, addr: u32, words: &[u32]) {
+        for (i, w) in words.iter().enumerate() {
+            mcu.mem
+                .wr(addr + 4 * i as u32, *w, mem::Size::Word, |_s| {});
+        }
+    }
+
+    #[test]
+    fn illegal_halts() {
+        let mut mcu = MCU::new();
+        prog_at(&mut mcu, 0, &[0xFFFFFFFF]);
+        let stop = Some(StopReason::Trap(Exception::IllegalInstr, 0));
+        assert_eq!(stop, mcu.step(|_s| {}));
+        // stays halted
+        assert_eq!(stop, mcu.step(|_s| {}));
+        assert_eq!(0, mcu.pc);
+        mcu.reset();
+        assert_eq!(None, mcu.stop);
+    }
+
+    #[test]
+    fn trap_handler() {
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x04000293, // addi t0, zero, 0x40
+                0x30529073, // csrrw zero, mtvec, t0
+                0x00000073, // ecall
+                0x00100073, // ebreak
+            ],
+        );
+        // handler skips the trapping instruction
+        prog_at(
+            &mut mcu,
+            0x40,
+            &[
+                0x34102373, // csrrs t1, mepc, zero
+                0x00430313, // addi t1, t1, 4
+                0x34131073, // csrrw zero, mepc, t1
+                0x30200073, // mret
+            ],
+        );
+        for _ in 0..3 {
+            assert_eq!(None, mcu.step(|_s| {}));
+        }
+        assert_eq!(0x40, mcu.pc);
+        assert_eq!(Some(11), mcu.csr_rd(csr::MCAUSE));
+        assert_eq!(Some(0x8), mcu.csr_rd(csr::MEPC));
+        for _ in 0..4 {
+            mcu.step(|_s| {});
+        }
+        assert_eq!(0xC, mcu.pc);
+        mcu.step(|_s| {});
+        assert_eq!(0x40, mcu.pc);
+        assert_eq!(Some(3), mcu.csr_rd(csr::MCAUSE));
+        assert_eq!(Some(0xC), mcu.csr_rd(csr::MTVAL));
+    }
+
+    #[test]
+    fn semihosting() {
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x04000793, // addi a5, zero, 0x40
+                0x00007737, // lui a4, 7
+                0x96870713, // addi a4, a4, -0x698 ("hi")
+                0x00e7a023, // sw a4, 0(a5)
+                0x00100513, // addi a0, zero, 1
+                0x04000593, // addi a1, zero, 0x40
+                0x00200613, // addi a2, zero, 2
+                0x04000893, // addi a7, zero, 64
+                0x00000073, // ecall
+                0x00700513, // addi a0, zero, 7
+                0x05d00893, // addi a7, zero, 93
+                0x00000073, // ecall
+            ],
+        );
+        mcu.enable_semihosting(None);
+        let mut stop = None;
+        while stop.is_none() {
+            stop = mcu.step(|_s| {});
+        }
+        assert_eq!(Some(StopReason::Exit(7)), stop);
+        assert_eq!(b"hi".to_vec(), mcu.take_console());
+
+        // a huge write is cut short
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00100513, // addi a0, zero, 1
+                0x00000593, // addi a1, zero, 0
+                0xfff00613, // addi a2, zero, -1
+                0x04000893, // addi a7, zero, 64
+                0x00000073, // ecall
+                0x0000006f, // j 0
+            ],
+        );
+        mcu.enable_semihosting(None);
+        assert_eq!(StopReason::SelfLoop(0x14), mcu.run(|_s| {}));
+        assert_eq!(MEM_SIZE as u32, mcu.rf_rd(10));
+        assert_eq!(MEM_SIZE, mcu.take_console().len());
+    }
+
+    #[test]
+    fn halt_conditions() {
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00100293, // addi t0, zero, 1
+                0x0000006f, // j 0
+            ],
+        );
+        assert_eq!(StopReason::SelfLoop(0x4), mcu.run(|_s| {}));
+
+        mcu.reset();
+        mcu.set_halt_config(HaltConfig {
+            self_loop: false,
+            budget: Some(10),
+            ..HaltConfig::default()
+        });
+        assert_eq!(StopReason::Budget(10), mcu.run(|_s| {}));
+        assert_eq!(10, mcu.instr_count());
+
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x110802b7, // lui t0, 0x11080
+                0x02a00313, // addi t1, zero, 42
+                0x0062a023, // sw t1, 0(t0)
+            ],
+        );
+        mcu.set_halt_config(HaltConfig {
+            exit_addr: Some(LEDS_ADDR),
+            ..HaltConfig::default()
+        });
+        assert_eq!(StopReason::ExitWrite(42), mcu.run(|_s| {}));
+    }
+
+    #[test]
+    fn misaligned_jump() {
+        let mut mcu = MCU::new();
+        // jal zero, 6
+        prog_at(&mut mcu, 0, &[0x0060006f, 0x0060006f]);
+        assert_eq!(
+            Some(StopReason::Trap(Exception::InstrAddrMisaligned, 0)),
+            mcu.step(|_s| {})
+        );
+        mcu.reset();
+        mcu.set_trap_fallback(TrapFallback::Skip);
+        assert_eq!(None, mcu.step(|_s| {}));
+        assert_eq!(4, mcu.pc);
+    }
+
+    #[test]
+    fn tracing() {
+        let path = std::env::temp_dir().join(format!("oemu_test_{}.trace", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00500293, // addi t0, zero, 5
+                0x10502023, // sw t0, 0x100(zero)
+                0x10002303, // lw t1, 0x100(zero)
+                0x00000073, // ecall
+            ],
+        );
+        let filter = trace::Filter::default();
+        mcu.set_tracer(Some(
+            trace::Tracer::create(path, trace::Format::Json, filter).unwrap(),
+        ));
+        mcu.run(|_s| {});
+        mcu.set_tracer(None);
+
+        let trace = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(4, lines.len());
+        assert!(lines[0].contains("\"asm\":\"addi t0, zero, 5\",\"rd\":5,\"value\":5"));
+        assert!(lines[1].contains("\"mem\":{\"addr\":256,\"bytes\":4,\"data\":5,\"write\":true}"));
+        assert!(lines[2].contains("\"rd\":6,\"value\":5"));
+        assert!(lines[3].contains("\"trap\":{\"cause\":11"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn semihosted_trace() {
+        let path = std::env::temp_dir().join(format!("oemu_test_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00100513, // addi a0, zero, 1
+                0x00000593, // addi a1, zero, 0
+                0x00200613, // addi a2, zero, 2
+                0x04000893, // addi a7, zero, 64
+                0x00000073, // ecall
+                0x0000006f, // j 0
+            ],
+        );
+        mcu.enable_semihosting(None);
+        mcu.set_history_depth(16);
+        let filter = trace::Filter::default();
+        mcu.set_tracer(Some(
+            trace::Tracer::create(path, trace::Format::Spike, filter).unwrap(),
+        ));
+        for _ in 0..5 {
+            mcu.step(|_s| {});
+        }
+        mcu.set_tracer(None);
+
+        // the write returns how many bytes it wrote in a0
+        let trace = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            "core   0: 3 0x00000010 (0x00000073) x10 0x00000002",
+            lines[4]
+        );
+        assert!(mcu.step_back());
+        assert_eq!(1, mcu.rf_rd(10));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn waveform() {
+        let path = std::env::temp_dir().join(format!("oemu_test_{}.vcd", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x110802b7, // lui t0, 0x11080
+                0x00300313, // addi t1, zero, 3
+                0x00629023, // sh t1, 0(t0)
+                0x0000006f, // j 0
+            ],
+        );
+        mcu.set_vcd(Some(vcd::Vcd::create(path, true).unwrap()));
+        mcu.run(|_s| {});
+        mcu.set_vcd(None);
+
+        let dump = std::fs::read_to_string(path).unwrap();
+        let body = &dump[dump.find("#0").unwrap()..];
+        // the LEDs and the bus change with the store
+        let store = &body[body.find("#2").unwrap()..body.find("#3").unwrap()];
+        assert!(store.contains("b11 C"));
+        assert!(store.contains(&format!("b{:b} F", 0x11080000)));
+        assert!(store.contains("1H"));
+        assert!(body.trim_end().ends_with("#4"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let prog = [
+            0x00000293, // li t0, 0
+            0x00a00313, // li t1, 10
+            0x00128293, // loop: addi t0, t0, 1
+            0x10502023, // sw t0, 0x100(zero)
+            0xfe629ce3, // bne t0, t1, loop
+            0x0000006f, // j 0
+        ];
+        let mut mcu = MCU::new();
+        prog_at(&mut mcu, 0, &prog);
+        mcu.csr.wr(csr::MSCRATCH, 0x1234);
+        mcu.add_breakpoint("0x8 ignore 100").unwrap();
+        assert_eq!(None, mcu.run_until(Some(12), |_s| {}));
+        mcu.add_breakpoint("0x8 if t0 == 11").unwrap();
+        let id = mcu.add_breakpoint("0x10").unwrap();
+        mcu.enable_breakpoint(id, false);
+        mcu.enable_semihosting(None);
+        mcu.semihosting.as_mut().unwrap().brk(0x2000);
+        mcu.set_uart_addr(0x11200000);
+        let snap = mcu.snapshot();
+        mcu.clear_breakpoints();
+        let stop = mcu.run(|_s| {});
+
+        let mut resumed = MCU::new();
+        assert_eq!(Ok(()), resumed.restore(&snap));
+        let bps: Vec<(bool, u64, String)> = resumed
+            .breakpoints()
+            .iter()
+            .map(|e| (e.enabled, e.bp.hits, e.bp.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                (true, 4, String::from("0x00000008 ignore 100")),
+                (true, 0, String::from("0x00000008 if t0 == 11")),
+                (false, 0, String::from("0x00000010"))
+            ],
+            bps
+        );
+        // semihosting enabled after restoring keeps the program break
+        resumed.enable_semihosting(None);
+        assert_eq!(
+            0x2000,
+            resumed.semihosting.as_ref().unwrap().program_break()
+        );
+        // and the UART is where it was moved to
+        resumed.uart_receive(b"x");
+        assert_eq!(
+            uart::STATUS_RX_READY | uart::STATUS_TX_READY,
+            resumed.mem_rd(0x11200000 + uart::STATUS, mem::Size::Word)
+        );
+        resumed.clear_breakpoints();
+        assert_eq!(12, resumed.instr_count());
+        assert_eq!(Some(0x1234), resumed.csr_rd(csr::MSCRATCH));
+        assert_eq!(stop, resumed.run(|_s| {}));
+        assert_eq!(mcu.instr_count(), resumed.instr_count());
+        assert_eq!(mcu.rf(), resumed.rf());
+        assert_eq!(10, resumed.mem_rd(0x100, mem::Size::Word));
+        // the IO devices came back too
+        assert!(resumed.mem.is_mapped(LEDS_ADDR));
+
+        // a bad snapshot leaves the MCU alone
+        assert!(resumed.restore(&snap[..snap.len() - 1]).is_err());
+        assert_eq!(mcu.rf(), resumed.rf());
+    }
+
+    #[test]
+    fn reverse() {
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00500293, // addi t0, zero, 5
+                0x10502023, // sw t0, 0x100(zero)
+                0x105002a3, // sb t0, 0x105(zero)
+                0x34029373, // csrrw t1, mscratch, t0
+                0x04000393, // addi t2, zero, 0x40
+                0x30539073, // csrrw zero, mtvec, t2
+                0x00000073, // ecall
+                0x00128293, // addi t0, t0, 1
+                0x0000006f, // j 0
+            ],
+        );
+        prog_at(
+            &mut mcu,
+            0x40,
+            &[
+                0x341023f3, // csrr t2, mepc
+                0x00438393, // addi t2, t2, 4
+                0x34139073, // csrw mepc, t2
+                0x30200073, // mret
+            ],
+        );
+        mcu.set_history_depth(100);
+        let stop = mcu.run(|_s| {});
+        let rf = mcu.rf();
+        assert_eq!(13, mcu.history_len());
+
+        let write = |w: Option<&history::Undo>| w.map(|u| (u.count, u.pc));
+        assert_eq!(Some((12, 0x1C)), write(mcu.last_reg_write(5)));
+        assert_eq!(Some((9, 0x44)), write(mcu.last_reg_write(7)));
+        assert_eq!(Some((3, 0x08)), write(mcu.last_mem_write(0x105)));
+        assert_eq!(Some((2, 0x04)), write(mcu.last_mem_write(0x101)));
+        assert_eq!(None, mcu.last_mem_write(0x104));
+
+        // back to just before the ecall, with the trap taken back
+        let id = mcu.add_breakpoint("0x18").unwrap();
+        assert_eq!(Some(id), mcu.reverse_continue());
+        assert_eq!(0x18, mcu.pc);
+        assert_eq!(6, mcu.instr_count());
+        assert_eq!(Some(0), mcu.csr_rd(csr::MEPC));
+        assert_eq!(Some(0), mcu.csr_rd(csr::MCAUSE));
+        assert_eq!(Some(0x40), mcu.csr_rd(csr::MTVEC));
+
+        // and to the start
+        assert_eq!(None, mcu.reverse_continue());
+        mcu.clear_breakpoints();
+        assert!(!mcu.step_back());
+        assert_eq!(0, mcu.pc);
+        assert_eq!(0, mcu.instr_count());
+        assert_eq!(vec![0; 32], mcu.rf());
+        assert_eq!(None, mcu.mem.byte(0x100));
+        assert_eq!(Some(0), mcu.csr_rd(csr::MSCRATCH));
+
+        // replaying gets the same result
+        assert_eq!(stop, mcu.run(|_s| {}));
+        assert_eq!(rf, mcu.rf());
+
+        // the history is bounded
+        mcu.reset();
+        mcu.set_history_depth(4);
+        mcu.run(|_s| {});
+        assert_eq!(4, mcu.history_len());
+        for _ in 0..4 {
+            assert!(mcu.step_back());
+        }
+        assert!(!mcu.step_back());
+        assert_eq!(9, mcu.instr_count());
+    }
+
+    #[test]
+    fn access_faults() {
+        let run = |prog: &[u32], text: bool| {
+            let mut mcu = MCU::new();
+            load_words(&mut mcu, prog);
+            if text {
+                mcu.protect_text(0, 4 * prog.len() as u32);
+            }
+            let log = std::cell::RefCell::new(Vec::new());
+            let stop = mcu.run(|s| log.borrow_mut().push(String::from(s)));
+            (stop, log.into_inner().into_iter().next(), mcu)
+        };
+
+        // a store to write-protected code
+        let (stop, log, mcu) = run(&[0x00002023], true); // sw zero, 0(zero)
+        assert_eq!(StopReason::Trap(Exception::StoreAccessFault, 0x0), stop);
+        assert_eq!(
+            Some(String::from(
+"[0x00000000] Error: Access fault: cannot write to 0x00000000, in r-x memory at 0x00000000:0x00000004.")),
+            log
+        );
+        assert_eq!(0x00002023, mcu.mem_rd(0x0, mem::Size::Word));
+        // unless the code is not protected
+        let prog = [0x00002023, 0x0000006f]; // sw zero, 0(zero); j 0
+        assert_eq!(StopReason::SelfLoop(0x4), run(&prog, false).0);
+        // or is only part of the binary, with data after
+        let mut mcu = MCU::new();
+        load_words(&mut mcu, &[0x00002423, 0x0000006f, 0x1]); // sw zero, 8(zero); j 0
+        mcu.protect_text(0, 0x8);
+        assert_eq!(StopReason::SelfLoop(0x4), mcu.run(|_s| {}));
+        assert_eq!(0, mcu.mem_rd(0x8, mem::Size::Word));
+
+        // a jump into data, past the program
+        let (stop, log, _) = run(&[0x000012b7, 0x00028067], true); // li t0, 0x1000; jr t0
+        assert_eq!(StopReason::Trap(Exception::InstrAccessFault, 0x1000), stop);
+        assert_eq!(
+            Some(String::from(
+                "[0x00001000] Error: Access fault: cannot execute 0x00001000, in rw- memory."
+            )),
+            log
+        );
+        // or into MMIO
+        let (stop, _, _) = run(&[0x110002b7, 0x00028067], true); // li t0, 0x11000000; jr t0
+        assert_eq!(
+            StopReason::Trap(Exception::InstrAccessFault, 0x11000000),
+            stop
+        );
+
+        // a load from memory that cannot be read, raising mcause 5 for a handler
+        let mut mcu = MCU::new();
+        let prog: [u32; 3] = [
+            0x40002303, // lw t1, 0x400(zero)
+            0x0000006f, // j 0
+            0x0000006f, // handler: j 0
+        ];
+        load_words(&mut mcu, &prog);
+        mcu.mem.add_region(mem::Region {
+            begin: 0x400,
+            end: 0x404,
+            perms: mem::Perms::from_bits(0),
+        });
+        mcu.csr.wr(csr::MTVEC, 0x8);
+        assert_eq!(StopReason::SelfLoop(0x8), mcu.run(|_s| {}));
+        assert_eq!(Some(5), mcu.csr.rd(csr::MCAUSE));
+        assert_eq!(Some(0x400), mcu.csr.rd(csr::MTVAL));
+        assert_eq!(Some(0x0), mcu.csr.rd(csr::MEPC));
+        assert_eq!(0, mcu.rf_rd(6));
+
+        // no checks at all
+        mcu.reset();
+        mcu.csr.wr(csr::MTVEC, 0x0);
+        mcu.set_protection(false);
+        assert_eq!(StopReason::SelfLoop(0x4), mcu.run(|_s| {}));
+    }
+
+    #[test]
+    fn caches() {
+        let mut mcu = MCU::new();
+        let prog: [u32; 9] = [
+            0x10000293, // addi t0, zero, 0x100
+            0x00400313, // addi t1, zero, 4
+            0x0002a383, // lw t2, 0(t0)
+            0x00428293, // addi t0, t0, 4
+            0xfff30313, // addi t1, t1, -1
+            0xfe031ae3, // bne t1, zero, -12
+            0xc0002573, // rdcycle a0
+            0xc02025f3, // rdinstret a1
+            0x0000006f, // j 0
+        ];
+        load_words(&mut mcu, &prog);
+        let config = CacheConfig::parse("size=64,penalty=10").unwrap();
+        mcu.set_caches(Some(config), Some(config));
+        assert_eq!(StopReason::SelfLoop(0x20), mcu.run(|_s| {}));
+        // the code is three lines, and the data one
+        let (icache, dcache) = (mcu.mem.icache().unwrap(), mcu.mem.dcache().unwrap());
+        assert_eq!((21, 3), (icache.stats().reads, icache.stats().read_misses));
+        assert_eq!((4, 1), (dcache.stats().reads, dcache.stats().read_misses));
+        assert_eq!(21 + 40, mcu.cycle_count());
+        // before rdcycle, 18 instructions and three misses
+        assert_eq!(48, mcu.rf_rd(10));
+        assert_eq!(19, mcu.rf_rd(11));
+        assert!(mcu
+            .cache_report()
+            .unwrap()
+            .starts_with("Instruction cache: 64 bytes"));
+
+        // a snapshot keeps the cycles, penalties included
+        let mut resumed = MCU::new();
+        resumed.restore(&mcu.snapshot()).unwrap();
+        assert_eq!(21 + 40, resumed.cycle_count());
+
+        mcu.reset();
+        assert_eq!(0, mcu.cycle_count());
+        assert_eq!(0, mcu.mem.icache().unwrap().stats().reads);
+        mcu.set_caches(None, None);
+        assert_eq!(None, mcu.cache_report());
+    }
+
+    #[test]
+    fn uart() {
+        let mut mcu = MCU::new();
+        let mut prog = vec![
+            0x111002b7, // lui t0, 0x11100
+            0x04000313, // addi t1, zero, 0x40
+            0x30531073, // csrw mtvec, t1
+            0x00100313, // addi t1, zero, 1
+            0x0062a623, // sw t1, 12(t0)
+            0x00b31313, // slli t1, t1, 11
+            0x30432073, // csrs mie, t1
+            0x30046073, // csrsi mstatus, 8
+            0x00158593, // loop: addi a1, a1, 1
+            0xffdff06f, // j loop
+        ];
+        prog.resize(16, 0);
+        prog.extend_from_slice(&[
+            0x0042a503, // handler: lw a0, 4(t0)
+            0x00a2a023, // sw a0, 0(t0)
+            0x30200073, // mret
+        ]);
+        load_words(&mut mcu, &prog);
+        mcu.set_history_depth(10);
+        for _ in 0..20 {
+            mcu.step(|_s| {});
+        }
+        // waiting in the loop, with nothing received
+        assert_eq!(0x20, mcu.pc & !0b111);
+        let reg = |mcu: &MCU, offset| mcu.mem_rd(UART_ADDR + offset, mem::Size::Word);
+        assert_eq!(uart::STATUS_TX_READY, reg(&mcu, uart::STATUS));
+
+        // the debugger reading RX does not take the byte
+        mcu.uart_receive(b"A");
+        assert_eq!(b'A' as u32, reg(&mcu, uart::RX));
+        let interrupted = mcu.pc;
+        mcu.step(|_s| {});
+        assert_eq!(0x44, mcu.pc);
+        assert_eq!(Some(0x8000000B), mcu.csr_rd(csr::MCAUSE));
+        assert_eq!(Some(interrupted), mcu.csr_rd(csr::MEPC));
+        assert_eq!(b'A' as u32, mcu.rf_rd(10));
+        assert_eq!(uart::STATUS_TX_READY, reg(&mcu, uart::STATUS));
+
+        // stepping back undoes entering the handler
+        assert!(mcu.step_back());
+        assert_eq!(interrupted, mcu.pc);
+        assert_eq!(
+            csr::MSTATUS_MIE,
+            mcu.csr_rd(csr::MSTATUS).unwrap() & csr::MSTATUS_MIE
+        );
+        // and puts back the byte the handler read
+        assert_eq!(b'A' as u32, reg(&mcu, uart::RX));
+        mcu.step(|_s| {});
+        assert_eq!(b'A' as u32, mcu.rf_rd(10));
+
+        mcu.step(|_s| {});
+        mcu.step(|_s| {});
+        assert_eq!(b"A".to_vec(), mcu.take_console());
+        assert_eq!(interrupted, mcu.pc);
+        mcu.step(|_s| {});
+        assert_eq!(0x20, mcu.pc & !0b111);
+
+        // reading RX hits a watchpoint on it
+        mcu.add_watchpoint(
+            mem::Watchpoint::parse(mem::WatchKind::Read, &format!("{}", UART_ADDR + uart::RX))
+                .unwrap(),
+        );
+        mcu.uart_receive(b"B");
+        let hit = mem::WatchHit {
+            addr: UART_ADDR + uart::RX,
+            write: false,
+            old: b'B' as u32,
+            new: b'B' as u32,
+        };
+        assert_eq!(Some(StopReason::Watchpoint(0x40, hit)), mcu.step(|_s| {}));
+
+        mcu.set_uart_addr(0x11200000);
+        assert!(!mcu.mem.is_mapped(UART_ADDR));
+        assert_eq!(
+            uart::STATUS_TX_READY,
+            mcu.mem_rd(0x11200008, mem::Size::Word)
+        );
+    }
+
+    #[test]
+    fn timer() {
+        let mut mcu = MCU::new();
+        let mut prog = vec![
+            0x111402b7, // lui t0, 0x11140
+            0x00a00313, // addi t1, zero, 10
+            0x0062a223, // sw t1, 4(t0)
+            0x04000313, // addi t1, zero, 0x40
+            0x30531073, // csrw mtvec, t1
+            0x08000313, // addi t1, zero, 0x80
+            0x30432073, // csrs mie, t1
+            0x30046073, // csrsi mstatus, 8
+            0x00700313, // addi t1, zero, 7
+            0x0062a623, // sw t1, 12(t0)
+            0x00158593, // loop: addi a1, a1, 1
+            0xffdff06f, // j loop
+        ];
+        prog.resize(16, 0);
+        prog.extend_from_slice(&[
+            0x00160613, // handler: addi a2, a2, 1
+            0x0002a823, // sw zero, 16(t0)
+            0x30200073, // mret
+        ]);
+        load_words(&mut mcu, &prog);
+        let reg = |mcu: &MCU, offset| mcu.mem_rd(TIMER_ADDR + offset, mem::Size::Word);
+        for _ in 0..19 {
+            mcu.step(|_s| {});
+        }
+        // counting from the store that enabled it, to the match, then
+        // restarting
+        assert_eq!(0x28, mcu.pc & !0b111);
+        assert_eq!(0, reg(&mcu, timer::COUNT));
+        assert_eq!(timer::STATUS_MATCH, reg(&mcu, timer::STATUS));
+        let interrupted = mcu.pc;
+        mcu.step(|_s| {});
+        assert_eq!(0x44, mcu.pc);
+        assert_eq!(Some(0x80000007), mcu.csr_rd(csr::MCAUSE));
+        assert_eq!(Some(interrupted), mcu.csr_rd(csr::MEPC));
+        mcu.step(|_s| {});
+        mcu.step(|_s| {});
+        assert_eq!(interrupted, mcu.pc);
+        assert_eq!(0, reg(&mcu, timer::STATUS));
+
+        // stepping back rewinds it, so replaying reads the same count
+        mcu.set_history_depth(10);
+        for _ in 0..4 {
+            mcu.step(|_s| {});
+        }
+        let count = reg(&mcu, timer::COUNT);
+        for _ in 0..4 {
+            assert!(mcu.step_back());
+        }
+        assert_eq!(count - 4, reg(&mcu, timer::COUNT));
+        for _ in 0..4 {
+            mcu.step(|_s| {});
+        }
+        assert_eq!(count, reg(&mcu, timer::COUNT));
+
+        // every 10 instructions, the handler's included
+        for _ in 0..46 {
+            mcu.step(|_s| {});
+        }
+        assert_eq!(6, mcu.rf_rd(12));
+
+        mcu.reset();
+        assert_eq!(0, reg(&mcu, timer::CTRL));
+        mcu.set_timer_addr(0x11200000);
+        assert!(!mcu.mem.is_mapped(TIMER_ADDR));
+        assert!(mcu.mem.is_mapped(0x11200000));
+    }
+
+    #[test]
+    fn idle_loop() {
+        let handler = [
+            0x00160613, // handler: addi a2, a2, 1
+            0x0002a623, // sw zero, 12(t0)
+            0x30200073, // mret
+        ];
+        // waiting in a jump to self for the timer, which the handler stops
+        let mut mcu = MCU::new();
+        let mut prog = vec![
+            0x111402b7, // lui t0, 0x11140
+            0x00500313, // addi t1, zero, 5
+            0x0062a223, // sw t1, 4(t0)
+            0x04000313, // addi t1, zero, 0x40
+            0x30531073, // csrw mtvec, t1
+            0x08000313, // addi t1, zero, 0x80
+            0x30432073, // csrs mie, t1
+            0x30046073, // csrsi mstatus, 8
+            0x00300313, // addi t1, zero, 3
+            0x0062a623, // sw t1, 12(t0)
+            0x0000006f, // idle: j idle
+        ];
+        prog.resize(16, 0);
+        prog.extend_from_slice(&handler);
+        load_words(&mut mcu, &prog);
+        assert_eq!(StopReason::SelfLoop(0x28), mcu.run(|_s| {}));
+        assert_eq!(1, mcu.rf_rd(12));
+
+        // and for the UART, which waits for as long as it takes
+        let mut mcu = MCU::new();
+        let mut prog = vec![
+            0x111002b7, // lui t0, 0x11100
+            0x04000313, // addi t1, zero, 0x40
+            0x30531073, // csrw mtvec, t1
+            0x00100313, // addi t1, zero, 1
+            0x0062a623, // sw t1, 12(t0)
+            0x00b31313, // slli t1, t1, 11
+            0x30432073, // csrs mie, t1
+            0x30046073, // csrsi mstatus, 8
+            0x0000006f, // idle: j idle
+        ];
+        prog.resize(16, 0);
+        prog.extend_from_slice(&handler);
+        load_words(&mut mcu, &prog);
+        assert_eq!(None, mcu.run_until(Some(100), |_s| {}));
+        assert_eq!(0x20, mcu.pc);
+        mcu.uart_receive(b"x");
+        assert_eq!(StopReason::SelfLoop(0x20), mcu.run(|_s| {}));
+        assert_eq!(1, mcu.rf_rd(12));
+    }
+
+    #[test]
+    fn predictor() {
+        let mut mcu = MCU::new();
+        let prog: [u32; 6] = [
+            0x00300513, // addi a0, zero, 3
+            0x008000ef, // jal ra, 8
+            0x0000006f, // j 0
+            0xfff50513, // addi a0, a0, -1
+            0xfe051ee3, // bne a0, zero, -4
+            0x00008067, // ret
+        ];
+        load_words(&mut mcu, &prog);
+        mcu.set_predictor(Some(PredictorConfig::parse("2bit,penalty=2").unwrap()));
+        assert_eq!(StopReason::SelfLoop(0x8), mcu.run(|_s| {}));
+        // the first bne, ret and the not taken bne, and the j, whose target
+        // is in the instruction
+        let report = mcu.predictor_report(10).unwrap();
+        assert!(report.contains("branches          3  mispredicted          2"));
+        assert!(report.contains("jumps             3  mispredicted          1"));
+        assert_eq!(10 + 3 * 2, mcu.cycle_count());
+        assert_eq!(0, mcu.rf_rd(10));
+
+        mcu.reset();
+        assert!(mcu
+            .predictor_report(10)
+            .unwrap()
+            .contains("all               0"));
+    }
+
+    #[test]
+    fn profile() {
+        let mut mcu = MCU::new();
+        let prog: [u32; 6] = [
+            0x00300513, // addi a0, zero, 3
+            0x008000ef, // jal ra, 8
+            0x0000006f, // j 0
+            0xfff50513, // addi a0, a0, -1
+            0xfe051ee3, // bne a0, zero, -4
+            0x00008067, // ret
+        ];
+        load_words(&mut mcu, &prog);
+        assert_eq!(None, mcu.profile_report(10));
+        mcu.set_profiling(true);
+        assert_eq!(StopReason::SelfLoop(0x8), mcu.run(|_s| {}));
+        assert_eq!(
+            Some(String::from("program 3\nprogram;0x0000000C 7\n")),
+            mcu.profile_stacks()
+        );
+        let report = mcu.profile_report(10).unwrap();
+        assert!(report.starts_with("Profile of 10 instructions\n"));
+        assert!(report.contains("         2          1  66.7%  0x00000010\n"));
+
+        // loading starts over
+        load_words(&mut mcu, &prog);
+        assert_eq!(Some(String::new()), mcu.profile_stacks());
+    }
+
+    #[test]
+    fn definedness() {
+        let mut mcu = MCU::new();
+        let prog: [u32; 14] = [
+            0x10002283, // lw t0, 0x100(zero)
+            0x00128313, // addi t1, t0, 1
+            0x10602223, // sw t1, 0x104(zero)
+            0x10402383, // lw t2, 0x104(zero)
+            0x00038263, // beq t2, zero, 4
+            0x0004ae03, // lw t3, 0(s1)
+            0x11080eb7, // lui t4, 0x11080
+            0x005ea023, // sw t0, 0(t4)
+            0x10602423, // sw t1, 0x108(zero)
+            0x00000313, // addi t1, zero, 0
+            0x10602423, // sw t1, 0x108(zero)
+            0x10802f03, // lw t5, 0x108(zero)
+            0x000f1263, // bne t5, zero, 4
+            0x0000006f, // j 0
+        ];
+        load_words(&mut mcu, &prog);
+        mcu.set_definedness(true);
+        let log = std::cell::RefCell::new(Vec::new());
+        assert_eq!(
+            StopReason::SelfLoop(0x34),
+            mcu.run(|s| log.borrow_mut().push(String::from(s)))
+        );
+        // copying undefined values through registers and memory is fine,
+        // and so is a defined value stored over an undefined one
+        assert_eq!(
+            vec![
+                "[0x00000010] Warning: Undefined value used in a branch condition: loaded from unset memory at 0x00000100 by the instruction at 0x00000000.",
+                "[0x00000014] Warning: Undefined value used as an address: x9 (s1) has not been written since reset.",
+                "[0x0000001C] Warning: Undefined value used as data written to IO: loaded from unset memory at 0x00000100 by the instruction at 0x00000000.",
+            ],
+            *log.borrow()
+        );
+        assert_eq!(None, mcu.rf.origin(30));
+        assert_eq!(
+            Some(Origin::Load {
+                pc: 0x0,
+                addr: 0x100
+            }),
+            mcu.rf.origin(7)
+        );
+
+        // without tracking, only reads of unset memory are warned of
+        mcu.set_definedness(false);
+        mcu.reset();
+        log.borrow_mut().clear();
+        mcu.run(|s| log.borrow_mut().push(String::from(s)));
+        assert!(!log.borrow().is_empty());
+        assert!(log.borrow().iter().all(|s| s.contains("Read unset memory")));
+    }
+
+    #[test]
+    fn stack_guard() {
+        let mut mcu = MCU::new();
+        let prog: [u32; 5] = [
+            0x00010137, // lui sp, 0x10
+            0xff010113, // addi sp, sp, -16
+            0x00002023, // sw zero, 0(zero)
+            0x00800113, // addi sp, zero, 8
+            0x0000006f, // j 0
+        ];
+        load_words(&mut mcu, &prog);
+        let guard = mcu.stack_layout();
+        assert_eq!((0x14, 0x10000), (guard.stack_bottom, guard.stack_top));
+        assert_eq!((0x0, 0x14), (guard.text_begin, guard.text_end));
+
+        // warnings only
+        mcu.set_stack_guard(Some(guard));
+        let warnings = std::cell::RefCell::new(Vec::new());
+        let stop = mcu.run(|s| warnings.borrow_mut().push(String::from(s)));
+        assert_eq!(StopReason::SelfLoop(0x10), stop);
+        assert_eq!(
+            vec![
+                "Warning: Stack guard: store to 0x00000000 in the text section (0x00000000:0x00000014).",
+                "Warning: Stack guard: stack pointer 0x00000008 is outside the stack (0x00000014:0x00010000).",
+            ],
+            *warnings.borrow()
+        );
+
+        // stopping, and continuing past each; the store overwrote the program
+        load_words(&mut mcu, &prog);
+        mcu.reset();
+        mcu.set_stack_guard(Some(mem::StackGuard {
+            stop: true,
+            ..guard
+        }));
+        let stop = mcu.run(|_s| {});
+        assert_eq!(
+            "store to 0x00000000 in the text section at 0x00000008",
+            stop.to_string()
+        );
+        assert_eq!(
+            StopReason::Guard(0xC, mem::Violation::StackPointer(0x8)),
+            mcu.run(|_s| {})
+        );
+        assert_eq!(StopReason::SelfLoop(0x10), mcu.run(|_s| {}));
+    }
+
+    #[test]
+    fn watchpoint_stops() {
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00500293, // addi t0, zero, 5
+                0x10502023, // sw t0, 0x100(zero)
+                0x10002303, // lw t1, 0x100(zero)
+                0x0000006f, // j 0
+            ],
+        );
+        mcu.add_watchpoint(mem::Watchpoint::parse(mem::WatchKind::Access, "0x100").unwrap());
+
+        let hit = mcu.run(|_s| {});
+        assert_eq!(
+            StopReason::Watchpoint(
+                0x4,
+                mem::WatchHit {
+                    addr: 0x100,
+                    write: true,
+                    old: 0,
+                    new: 5
+                }
+            ),
+            hit
+        );
+        assert_eq!(
+            "watchpoint hit at 0x00000004: wrote 0x00000005 over 0x00000000 at 0x00000100",
+            hit.to_string()
+        );
+        // the store finished, and running again continues after it
+        assert_eq!(0x8, mcu.pc);
+        match mcu.run(|_s| {}) {
+            StopReason::Watchpoint(0x8, h) => assert!(!h.write && h.new == 5),
+            other => panic!("{}", other),
+        }
+        // the debugger reading memory does not count
+        mcu.mem_rd(0x100, mem::Size::Word);
+        assert_eq!(StopReason::SelfLoop(0xC), mcu.run(|_s| {}));
+
+        // watchpoints survive restoring a snapshot
+        let snap = mcu.snapshot();
+        mcu.restore(&snap).unwrap();
+        assert_eq!(1, mcu.watchpoints().len());
+        mcu.clear_watchpoints();
+        assert!(mcu.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn run_until() {
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x00000293, // li t0, 0
+                0x00128293, // loop: addi t0, t0, 1
+                0xffdff06f, // j loop
+            ],
+        );
+        // a breakpoint at the first instruction is not skipped
+        let start = mcu.add_breakpoint("0").unwrap();
+        assert_eq!(
+            Some(StopReason::Breakpoint(start, 0)),
+            mcu.run_until(None, |_s| {})
+        );
+        assert_eq!(0, mcu.instr_count());
+
+        // running again continues past it
+        let id = mcu.add_breakpoint("4 if t0 == 3").unwrap();
+        let stop = mcu.run_until(None, |_s| {}).unwrap();
+        assert_eq!(StopReason::Breakpoint(id, 4), stop);
+        assert_eq!("breakpoint 2 at 0x00000004", stop.to_string());
+        assert_eq!(3, mcu.rf_rd(5));
+        assert_eq!(None, mcu.run_until(Some(2), |_s| {}));
+        assert_eq!(4, mcu.rf_rd(5));
+
+        // disabled and removed breakpoints do not stop
+        mcu.reset();
+        assert!(mcu.enable_breakpoint(start, false));
+        assert!(mcu.remove_breakpoint(id));
+        assert!(!mcu.remove_breakpoint(id));
+        assert_eq!(None, mcu.run_until(Some(10), |_s| {}));
+        assert_eq!(1, mcu.breakpoints().len());
+        assert!(mcu.add_breakpoint("main").is_err());
+
+        // other stops still apply
+        mcu.set_halt_config(HaltConfig {
+            budget: Some(12),
+            ..HaltConfig::default()
+        });
+        assert_eq!(Some(StopReason::Budget(12)), mcu.run_until(None, |_s| {}));
+    }
+
+    // main calls f, which calls g
+    fn prog_calls(mcu: &mut MCU) {
+        prog_at(
+            mcu,
+            0,
+            &[
+                0x10000113, // li sp, 0x100
+                0x010000ef, // call f
+                0x00150513, // addi a0, a0, 1
+                0x0000006f, // j 0
+                0x00000013, // nop
+                0xff010113, // f: addi sp, sp, -16
+                0x00112623, // sw ra, 12(sp)
+                0x010000ef, // call g
+                0x00c12083, // lw ra, 12(sp)
+                0x01010113, // addi sp, sp, 16
+                0x00008067, // ret
+                0x00550513, // g: addi a0, a0, 5
+                0x00008067, // ret
+            ],
+        );
+    }
+
+    // run until 'goal' is reached or the MCU stops
+    fn reach(mcu: &mut MCU, mut goal: Goal) -> StopReason {
+        mcu.run_goal(&mut goal, None, |_s| {}).unwrap()
+    }
+
+    fn step_over(mcu: &mut MCU) -> StopReason {
+        let goal = mcu.step_over_goal();
+        reach(mcu, goal)
+    }
+
+    #[test]
+    fn step_over_out() {
+        let mut mcu = MCU::new();
+        prog_calls(&mut mcu);
+        assert_eq!(StopReason::Reached(0x4), step_over(&mut mcu));
+        assert_eq!(1, mcu.instr_count());
+        // over both calls, back at the same stack depth
+        assert_eq!(StopReason::Reached(0x8), step_over(&mut mcu));
+        assert_eq!((5, 0x100), (mcu.rf_rd(10), mcu.rf_rd(2)));
+        assert_eq!(StopReason::Reached(0xC), step_over(&mut mcu));
+        assert_eq!(StopReason::SelfLoop(0xC), step_over(&mut mcu));
+
+        // out of g, then out of f
+        mcu.reset();
+        assert_eq!(
+            StopReason::Reached(0x2C),
+            reach(&mut mcu, Goal::Address(0x2C))
+        );
+        assert_eq!(
+            StopReason::Reached(0x20),
+            reach(&mut mcu, Goal::Out { depth: 0 })
+        );
+        assert_eq!(
+            StopReason::Reached(0x8),
+            reach(&mut mcu, Goal::Out { depth: 0 })
+        );
+        // the call to g inside f does not end stepping out of f
+        mcu.reset();
+        reach(&mut mcu, Goal::Address(0x14));
+        assert_eq!(
+            StopReason::Reached(0x8),
+            reach(&mut mcu, Goal::Out { depth: 0 })
+        );
+
+        // a breakpoint in the callee stops stepping over
+        mcu.reset();
+        let id = mcu.add_breakpoint("0x2C").unwrap();
+        mcu.step(|_s| {});
+        assert_eq!(StopReason::Breakpoint(id, 0x2C), step_over(&mut mcu));
+        assert_eq!(
+            StopReason::Reached(0x20),
+            reach(&mut mcu, Goal::Out { depth: 0 })
+        );
+        mcu.clear_breakpoints();
+
+        // a goal can be run toward a few instructions at a time
+        mcu.reset();
+        reach(&mut mcu, Goal::Address(0x14));
+        let mut goal = Goal::Out { depth: 0 };
+        let mut runs = 1;
+        while mcu.run_goal(&mut goal, Some(2), |_s| {}).is_none() {
+            runs += 1;
+        }
+        assert_eq!(4, runs);
+        assert_eq!(0x8, mcu.pc);
+        assert_eq!(
+            "reached 0x00000008",
+            StopReason::Reached(mcu.pc).to_string()
+        );
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut mcu = MCU::new();
+        prog_calls(&mut mcu);
+        mcu.set_history_depth(100);
+        reach(&mut mcu, Goal::Address(0x2C));
+        assert_eq!(
+            vec!["#0  0x0000002C", "#1  0x0000001C", "#2  0x00000004"],
+            mcu.backtrace()
+        );
+        mcu.symbols.insert(String::from("main"), 0x0);
+        mcu.symbols.insert(String::from("f"), 0x14);
+        mcu.symbols.insert(String::from("g"), 0x2C);
+        assert_eq!(
+            vec![
+                "#0  0x0000002C in g",
+                "#1  0x0000001C in f+0x8",
+                "#2  0x00000004 in main+0x4"
+            ],
+            mcu.backtrace()
+        );
+
+        // returning pops a frame, and stepping back pushes it again
+        reach(&mut mcu, Goal::Out { depth: 0 });
+        assert_eq!(2, mcu.backtrace().len());
+        assert!(mcu.step_back());
+        assert_eq!(3, mcu.backtrace().len());
+        mcu.reset();
+        assert_eq!(vec!["#0  0x00000000 in main"], mcu.backtrace());
+
+        // f returns somewhere main did not call it from
+        let mut mcu = MCU::new();
+        prog_at(
+            &mut mcu,
+            0,
+            &[
+                0x008000ef, // call f
+                0x0000006f, // j 0
+                0x01000093, // f: li ra, 0x10
+                0x00008067, // ret
+                0x0000006f, // j 0
+            ],
+        );
+        let log = std::cell::RefCell::new(Vec::new());
+        let stop = mcu.run(|s| log.borrow_mut().push(String::from(s)));
+        assert_eq!(StopReason::SelfLoop(0x10), stop);
+        assert_eq!(
+            vec!["[0x0000000C] Warning: Return to 0x00000010, which is not on the call stack. Expected 0x00000004."],
+            *log.borrow()
+        );
+    }
 }
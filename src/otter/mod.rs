@@ -1,7 +1,29 @@
+pub mod breakpoint;
+pub mod callstack;
+pub mod cosim;
+pub mod definedness;
 mod devices;
+#[cfg(test)]
+mod difftest;
+pub mod expr;
+#[cfg(test)]
+mod harness;
+pub mod history;
 mod mcu;
+pub mod predictor;
+pub mod profile;
+pub mod riscv_tests;
 pub mod rv32i;
+pub mod snapshot;
+mod syscall;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod trace;
+pub mod trap;
+pub mod vcd;
 
-pub use devices::mem::Size;
+pub use devices::cache::CacheConfig;
+pub use devices::mem::{Size, WatchKind, Watchpoint};
 pub use mcu::*;
-pub use rv32i::decode::{reg_name, Instruction, Operation};
+pub use predictor::PredictorConfig;
+pub use rv32i::decode::{reg_index, reg_name, Instruction, Operation};
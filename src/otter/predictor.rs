@@ -0,0 +1,440 @@
+// Models of branch predictors, which watch each branch and jump retire and
+// count how often they would have guessed its outcome. They only keep
+// statistics; execution is the same with or without them.
+//
+// A branch is predicted by the direction predictor and, when it predicts
+// taken and there is a branch target buffer, by the BTB's target as well.
+// Jumps are always taken, so only their targets are predicted: by the BTB
+// when there is one, and otherwise a jal's target is known from the
+// instruction and a jalr's never is.
+
+use super::super::util::parse::parse_int;
+use super::callstack::describe;
+use super::rv32i::decode::{Instruction, OpClass, Operation};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Scheme {
+    // every branch falls through
+    NotTaken,
+    // each entry remembers the last outcome
+    OneBit,
+    // 2-bit saturating counters
+    TwoBit,
+    // 2-bit counters indexed by the PC XORed with the global history
+    Gshare,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PredictorConfig {
+    pub scheme: Scheme,
+    // entries in the table of counters
+    pub table: u32,
+    // bits of global history, for gshare
+    pub history: u32,
+    // entries in the branch target buffer, 0 for none
+    pub btb: u32,
+    // cycles lost to each misprediction
+    pub penalty: u64,
+}
+
+impl PredictorConfig {
+    // a scheme, 'not-taken', '1bit', '2bit' or 'gshare', then any of
+    // 'table=N,history=N,btb=N,penalty=N', e.g. 'gshare,table=4096,btb=64'
+    pub fn parse(s: &str) -> Result<PredictorConfig, String> {
+        let mut settings = s.split(',').map(|s| s.trim());
+        let scheme = match settings.next().unwrap() {
+            "not-taken" => Scheme::NotTaken,
+            "1bit" => Scheme::OneBit,
+            "2bit" => Scheme::TwoBit,
+            "gshare" => Scheme::Gshare,
+            name => return Err(format!("unknown predictor '{}'", name)),
+        };
+        let mut c = PredictorConfig {
+            scheme,
+            table: 1024,
+            history: 0,
+            btb: 0,
+            penalty: 0,
+        };
+        let mut history = None;
+        for setting in settings.filter(|s| !s.is_empty()) {
+            let (key, value) = match setting.find('=') {
+                Some(i) => (&setting[..i], &setting[i + 1..]),
+                None => return Err(format!("expected KEY=VALUE, found '{}'", setting)),
+            };
+            let n = parse_int(value).map_err(|e| format!("{}: {}", setting, e))?;
+            match key {
+                "table" => c.table = n,
+                "history" => history = Some(n),
+                "btb" => c.btb = n,
+                "penalty" => c.penalty = n as u64,
+                _ => return Err(format!("unknown setting '{}'", key)),
+            }
+        }
+        if !c.table.is_power_of_two() {
+            return Err(format!("table must be a power of two, not {}", c.table));
+        }
+        if c.btb != 0 && !c.btb.is_power_of_two() {
+            return Err(format!("btb must be a power of two, not {}", c.btb));
+        }
+        // by default, as much history as the table has index bits
+        c.history = history.unwrap_or_else(|| c.table.trailing_zeros());
+        if c.history > 32 {
+            return Err(format!(
+                "history must be at most 32 bits, not {}",
+                c.history
+            ));
+        }
+        Ok(c)
+    }
+}
+
+impl fmt::Display for PredictorConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.scheme {
+            Scheme::NotTaken => write!(f, "static not-taken")?,
+            Scheme::OneBit => write!(f, "1-bit, {} entries", self.table)?,
+            Scheme::TwoBit => write!(f, "2-bit, {} entries", self.table)?,
+            Scheme::Gshare => write!(
+                f,
+                "gshare, {} entries, {} bits of history",
+                self.table, self.history
+            )?,
+        }
+        if self.btb != 0 {
+            write!(f, ", {}-entry BTB", self.btb)?;
+        }
+        write!(f, ", {}-cycle penalty", self.penalty)
+    }
+}
+
+// predictions of one branch or jump, or of all of them
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Stats {
+    pub executed: u64,
+    pub mispredicted: u64,
+}
+
+impl Stats {
+    fn add(&mut self, right: bool) {
+        self.executed += 1;
+        self.mispredicted += !right as u64;
+    }
+
+    fn accuracy(&self) -> f64 {
+        100.0 * (self.executed - self.mispredicted) as f64 / self.executed.max(1) as f64
+    }
+}
+
+pub struct Predictor {
+    config: PredictorConfig,
+    // a counter for each entry, taken when its top bit is set
+    counters: Vec<u8>,
+    // outcomes of the latest branches, newest in bit 0
+    history: u32,
+    // the PC of a taken branch or jump and its target
+    btb: Vec<Option<(u32, u32)>>,
+    branches: Stats,
+    jumps: Stats,
+    // by PC, with whether it is a jump
+    pcs: HashMap<u32, (Stats, bool)>,
+}
+
+impl Predictor {
+    pub fn new(config: PredictorConfig) -> Predictor {
+        // counters start weakly not-taken
+        let counter = match config.scheme {
+            Scheme::TwoBit | Scheme::Gshare => 1,
+            Scheme::NotTaken | Scheme::OneBit => 0,
+        };
+        Predictor {
+            config,
+            counters: vec![counter; config.table as usize],
+            history: 0,
+            btb: vec![None; config.btb as usize],
+            branches: Stats::default(),
+            jumps: Stats::default(),
+            pcs: HashMap::new(),
+        }
+    }
+
+    // forget what it learned and its counts
+    pub fn reset(&mut self) {
+        *self = Predictor::new(self.config);
+    }
+
+    #[allow(dead_code)]
+    pub fn branches(&self) -> Stats {
+        self.branches
+    }
+
+    #[allow(dead_code)]
+    pub fn jumps(&self) -> Stats {
+        self.jumps
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        let mut i = pc >> 2;
+        if self.config.scheme == Scheme::Gshare {
+            i ^= self.history;
+        }
+        (i & (self.config.table - 1)) as usize
+    }
+
+    fn predict_taken(&self, pc: u32) -> bool {
+        let counter = self.counters[self.index(pc)];
+        match self.config.scheme {
+            Scheme::NotTaken => false,
+            Scheme::OneBit => counter != 0,
+            Scheme::TwoBit | Scheme::Gshare => counter >= 2,
+        }
+    }
+
+    fn train(&mut self, pc: u32, taken: bool) {
+        let i = self.index(pc);
+        let counter = &mut self.counters[i];
+        *counter = match self.config.scheme {
+            Scheme::NotTaken => 0,
+            Scheme::OneBit => taken as u8,
+            Scheme::TwoBit | Scheme::Gshare if taken => (*counter + 1).min(3),
+            Scheme::TwoBit | Scheme::Gshare => counter.saturating_sub(1),
+        };
+        if self.config.history > 0 {
+            let mask = u32::MAX >> (32 - self.config.history);
+            self.history = ((self.history << 1) | taken as u32) & mask;
+        }
+    }
+
+    fn btb_entry(&self, pc: u32) -> usize {
+        ((pc >> 2) & (self.config.btb - 1)) as usize
+    }
+
+    // the target the BTB predicts for the branch or jump at 'pc'
+    fn btb_target(&self, pc: u32) -> Option<u32> {
+        match self.btb[self.btb_entry(pc)] {
+            Some((tag, target)) if tag == pc => Some(target),
+            _ => None,
+        }
+    }
+
+    // watch the instruction at 'pc' retire, leaving the PC at 'next';
+    // returns the cycles of misprediction penalty it costs
+    pub fn observe(&mut self, pc: u32, ir: &Instruction, next: u32) -> u64 {
+        let btb = self.config.btb != 0;
+        let jump = ir.op.class() == OpClass::Jump;
+        let taken = jump || next != pc.wrapping_add(4);
+        let right = match ir.op.class() {
+            OpClass::Branch => {
+                let predicted = self.predict_taken(pc);
+                self.train(pc, taken);
+                predicted == taken && (!taken || !btb || self.btb_target(pc) == Some(next))
+            }
+            OpClass::Jump if btb => self.btb_target(pc) == Some(next),
+            OpClass::Jump => ir.op == Operation::JAL,
+            _ => return 0,
+        };
+        if btb && taken {
+            let entry = self.btb_entry(pc);
+            self.btb[entry] = Some((pc, next));
+        }
+        if jump {
+            self.jumps.add(right);
+        } else {
+            self.branches.add(right);
+        }
+        self.pcs
+            .entry(pc)
+            .or_insert((Stats::default(), jump))
+            .0
+            .add(right);
+        if right {
+            0
+        } else {
+            self.config.penalty
+        }
+    }
+
+    // accuracy overall, then for the 'top' most mispredicted PCs
+    pub fn report(&self, symbols: &HashMap<String, u32>, top: usize) -> String {
+        let mut out = format!("Branch predictor: {}\n", self.config);
+        let mut all = self.branches;
+        all.executed += self.jumps.executed;
+        all.mispredicted += self.jumps.mispredicted;
+        for (what, s) in [
+            ("branches", self.branches),
+            ("jumps", self.jumps),
+            ("all", all),
+        ]
+        .iter()
+        {
+            writeln!(
+                out,
+                "    {:<8} {:>10}  mispredicted {:>10}  {:>5.1}% accuracy",
+                what,
+                s.executed,
+                s.mispredicted,
+                s.accuracy()
+            )
+            .unwrap();
+        }
+
+        let mut pcs: Vec<(u32, Stats, bool)> = self
+            .pcs
+            .iter()
+            .filter(|(_, (s, _))| s.mispredicted > 0)
+            .map(|(pc, (s, jump))| (*pc, *s, *jump))
+            .collect();
+        if pcs.is_empty() {
+            return out;
+        }
+        pcs.sort_by(|a, b| b.1.mispredicted.cmp(&a.1.mispredicted).then(a.0.cmp(&b.0)));
+        writeln!(out, "\nMost mispredicted").unwrap();
+        writeln!(
+            out,
+            "{:>10} {:>12} {:>9}  {:<6} pc",
+            "executed", "mispredicted", "accuracy", "kind"
+        )
+        .unwrap();
+        for (pc, s, jump) in pcs.iter().take(top) {
+            writeln!(
+                out,
+                "{:>10} {:>12} {:>8.1}%  {:<6} {}",
+                s.executed,
+                s.mispredicted,
+                s.accuracy(),
+                if *jump { "jump" } else { "branch" },
+                describe(*pc, symbols)
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rv32i::decode::decode;
+    use super::*;
+
+    // bne a0, zero, -4
+    const BNE: u32 = 0xfe051ee3;
+    // jalr zero, 0(ra)
+    const RET: u32 = 0x00008067;
+    // jal ra, 8
+    const JAL: u32 = 0x008000ef;
+
+    fn predictor(s: &str) -> Predictor {
+        Predictor::new(PredictorConfig::parse(s).unwrap())
+    }
+
+    // a loop branch at 0x10 taken 'n' times and then not taken, 'runs' times
+    fn loops(p: &mut Predictor, n: usize, runs: usize) {
+        let ir = decode(BNE);
+        for _ in 0..runs {
+            for _ in 0..n {
+                p.observe(0x10, &ir, 0xC);
+            }
+            p.observe(0x10, &ir, 0x14);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let c = PredictorConfig::parse("gshare, table=256, btb=16, penalty=3").unwrap();
+        assert_eq!(Scheme::Gshare, c.scheme);
+        assert_eq!((256, 8, 16, 3), (c.table, c.history, c.btb, c.penalty));
+        assert_eq!(
+            "gshare, 256 entries, 8 bits of history, 16-entry BTB, 3-cycle penalty",
+            c.to_string()
+        );
+        assert_eq!(
+            "static not-taken, 0-cycle penalty",
+            PredictorConfig::parse("not-taken").unwrap().to_string()
+        );
+        assert_eq!(
+            Err(String::from("unknown predictor 'perceptron'")),
+            PredictorConfig::parse("perceptron")
+        );
+        assert!(PredictorConfig::parse("2bit,table=1000").is_err());
+        assert!(PredictorConfig::parse("2bit,btb=3").is_err());
+        assert!(PredictorConfig::parse("2bit,ways=2").is_err());
+        assert!(PredictorConfig::parse("gshare,history=33").is_err());
+    }
+
+    #[test]
+    fn schemes() {
+        let accuracy = |s: &str| {
+            let mut p = predictor(s);
+            loops(&mut p, 3, 10);
+            p.branches().mispredicted
+        };
+        // every taken branch
+        assert_eq!(30, accuracy("not-taken"));
+        // the first taken and the not taken of each run
+        assert_eq!(20, accuracy("1bit"));
+        // the first two taken, then only the not taken of each run
+        assert_eq!(11, accuracy("2bit"));
+        // history tells the last iteration apart once it has been learned
+        assert!(accuracy("gshare,table=64") < 11);
+    }
+
+    #[test]
+    fn jumps_and_btb() {
+        let (jal, ret) = (decode(JAL), decode(RET));
+        let mut p = predictor("2bit,penalty=3");
+        assert_eq!(0, p.observe(0x4, &jal, 0xC));
+        assert_eq!(3, p.observe(0x14, &ret, 0x8));
+        // not a branch or jump
+        assert_eq!(0, p.observe(0x0, &decode(0x00300513), 0x4));
+        assert_eq!(
+            Stats {
+                executed: 2,
+                mispredicted: 1
+            },
+            p.jumps()
+        );
+
+        let mut p = predictor("2bit,btb=4,penalty=3");
+        assert_eq!(3, p.observe(0x4, &jal, 0xC));
+        assert_eq!(0, p.observe(0x4, &jal, 0xC));
+        assert_eq!(3, p.observe(0x14, &ret, 0x8));
+        assert_eq!(0, p.observe(0x14, &ret, 0x8));
+        // a return elsewhere
+        assert_eq!(3, p.observe(0x14, &ret, 0x28));
+        // 0x24 shares an entry with 0x14
+        assert_eq!(3, p.observe(0x24, &ret, 0x28));
+        assert_eq!(3, p.observe(0x14, &ret, 0x28));
+
+        // a branch predicted taken still needs its target in the BTB;
+        // 0x10 and 0x20 have their own counters but share an entry
+        let mut p = predictor("2bit,btb=4,penalty=3");
+        let bne = decode(BNE);
+        for (pc, penalty) in [(0x10, 3), (0x10, 0), (0x20, 3), (0x20, 0), (0x10, 3)].iter() {
+            assert_eq!(*penalty, p.observe(*pc, &bne, *pc - 4));
+        }
+        assert_eq!(3, p.branches().mispredicted);
+        p.reset();
+        assert_eq!(Stats::default(), p.branches());
+    }
+
+    #[test]
+    fn report() {
+        let mut p = predictor("2bit");
+        loops(&mut p, 3, 2);
+        p.observe(0x4, &decode(JAL), 0xC);
+        assert_eq!(
+            "Branch predictor: 2-bit, 1024 entries, 0-cycle penalty
+    branches          8  mispredicted          3   62.5% accuracy
+    jumps             1  mispredicted          0  100.0% accuracy
+    all               9  mispredicted          3   66.7% accuracy
+
+Most mispredicted
+  executed mispredicted  accuracy  kind   pc
+         8            3     62.5%  branch 0x00000010
+",
+            p.report(&HashMap::new(), 10)
+        );
+    }
+}
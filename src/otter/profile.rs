@@ -0,0 +1,272 @@
+// An instruction-level profiler: how often each instruction, function and
+// operation ran and how often each branch was taken, reported as tables,
+// hottest first, and as collapsed stacks ('main;f;g 42' lines) for
+// flamegraph tools.
+//
+// Functions come from the shadow call stack, named by the ELF's symbols
+// when there are any. Code outside every call is the 'program'.
+
+use super::callstack::{symbolize, Frame};
+use super::rv32i::decode::{Instruction, OpClass, Operation};
+use super::rv32i::disasm;
+use super::trace::Record;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+#[derive(Default)]
+pub struct Profiler {
+    total: u64,
+    // executions of each instruction, by PC
+    pcs: HashMap<u32, (u64, Instruction)>,
+    ops: HashMap<Operation, u64>,
+    // (taken, not taken) of each branch, by PC
+    branches: HashMap<u32, (u64, u64)>,
+    // executions in each call stack: an address in the outermost function,
+    // then the address of each function called
+    stacks: HashMap<Vec<u32>, u64>,
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    100.0 * n as f64 / total.max(1) as f64
+}
+
+// the name of the function at 'addr', or the outermost code at 'addr'
+fn function(addr: u32, outermost: bool, symbols: &HashMap<String, u32>) -> String {
+    match symbolize(addr, symbols) {
+        Some((name, _)) => String::from(name),
+        None if outermost => String::from("program"),
+        None => format!("{:#010X}", addr),
+    }
+}
+
+// most first, then by key
+fn sorted<K: Ord + Copy>(counts: &HashMap<K, u64>) -> Vec<(K, u64)> {
+    let mut v: Vec<(K, u64)> = counts.iter().map(|(k, n)| (*k, *n)).collect();
+    v.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    v
+}
+
+impl Profiler {
+    // count the instruction just retired, which left the PC at 'next' and
+    // ran inside the calls in 'calls'
+    pub fn record(&mut self, r: &Record, next: u32, calls: &[Frame]) {
+        self.total += 1;
+        self.pcs.entry(r.pc).or_insert((0, r.ir)).0 += 1;
+        *self.ops.entry(r.ir.op).or_insert(0) += 1;
+        if r.ir.op.class() == OpClass::Branch && r.trap.is_none() {
+            let b = self.branches.entry(r.pc).or_insert((0, 0));
+            if next != r.pc.wrapping_add(4) {
+                b.0 += 1;
+            } else {
+                b.1 += 1;
+            }
+        }
+        let mut stack = Vec::with_capacity(calls.len() + 1);
+        stack.push(calls.first().map(|f| f.call).unwrap_or(r.pc));
+        stack.extend(calls.iter().map(|f| f.target));
+        *self.stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    // executions in each stack of function names, outermost first
+    fn stacks(&self, symbols: &HashMap<String, u32>) -> BTreeMap<Vec<String>, u64> {
+        let mut named = BTreeMap::new();
+        for (stack, n) in self.stacks.iter() {
+            let names = stack
+                .iter()
+                .enumerate()
+                .map(|(i, addr)| function(*addr, i == 0, symbols))
+                .collect();
+            *named.entry(names).or_insert(0) += n;
+        }
+        named
+    }
+
+    // executions in each function, not counting the functions it calls
+    pub fn functions(&self, symbols: &HashMap<String, u32>) -> Vec<(String, u64)> {
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for (mut stack, n) in self.stacks(symbols) {
+            *counts.entry(stack.pop().unwrap()).or_insert(0) += n;
+        }
+        let mut v: Vec<(String, u64)> = counts.into_iter().collect();
+        v.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        v
+    }
+
+    // one line for each call stack, e.g. 'main;f;g 42', as flamegraph.pl
+    // and speedscope read them
+    pub fn collapsed(&self, symbols: &HashMap<String, u32>) -> String {
+        let mut out = String::new();
+        for (stack, n) in self.stacks(symbols) {
+            writeln!(out, "{} {}", stack.join(";"), n).unwrap();
+        }
+        out
+    }
+
+    // tables of the 'top' hottest instructions, then every function,
+    // operation and branch
+    pub fn report(&self, symbols: &HashMap<String, u32>, top: usize) -> String {
+        let mut out = String::new();
+        let total = self.total;
+        writeln!(out, "Profile of {} instructions", total).unwrap();
+
+        writeln!(out, "\nHottest instructions").unwrap();
+        writeln!(out, "{:>10} {:>6}  {:<30} instruction", "count", "%", "pc").unwrap();
+        let mut pcs: Vec<(u32, u64, Instruction)> = self
+            .pcs
+            .iter()
+            .map(|(pc, (n, ir))| (*pc, *n, *ir))
+            .collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (pc, n, ir) in pcs.iter().take(top) {
+            writeln!(
+                out,
+                "{:>10} {:>5.1}%  {:<30} {}",
+                n,
+                percent(*n, total),
+                super::callstack::describe(*pc, symbols),
+                disasm::disassemble(ir, *pc)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nFunctions").unwrap();
+        writeln!(out, "{:>10} {:>6}  function", "count", "%").unwrap();
+        for (name, n) in self.functions(symbols) {
+            writeln!(out, "{:>10} {:>5.1}%  {}", n, percent(n, total), name).unwrap();
+        }
+
+        writeln!(out, "\nOperations").unwrap();
+        writeln!(out, "{:>10} {:>6}  {:<8} operation", "count", "%", "class").unwrap();
+        let mut classes: HashMap<OpClass, u64> = HashMap::new();
+        for (op, n) in self.ops.iter() {
+            *classes.entry(op.class()).or_insert(0) += n;
+        }
+        let mut classes: Vec<(OpClass, u64)> = classes.into_iter().collect();
+        classes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.name().cmp(b.0.name())));
+        for (class, n) in classes {
+            writeln!(
+                out,
+                "{:>10} {:>5.1}%  {:<8} all",
+                n,
+                percent(n, total),
+                class.name()
+            )
+            .unwrap();
+            let mut ops: Vec<(String, u64)> = self
+                .ops
+                .iter()
+                .filter(|(op, _)| op.class() == class)
+                .map(|(op, n)| (disasm::mnemonic(*op), *n))
+                .collect();
+            ops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            for (op, n) in ops {
+                writeln!(
+                    out,
+                    "{:>10} {:>5.1}%  {:<8} {}",
+                    n,
+                    percent(n, total),
+                    "",
+                    op
+                )
+                .unwrap();
+            }
+        }
+
+        writeln!(out, "\nBranches").unwrap();
+        writeln!(
+            out,
+            "{:>10} {:>10} {:>6}  pc",
+            "taken", "not taken", "taken"
+        )
+        .unwrap();
+        let executed: HashMap<u32, u64> = self
+            .branches
+            .iter()
+            .map(|(pc, (t, nt))| (*pc, t + nt))
+            .collect();
+        for (pc, _) in sorted(&executed) {
+            let (taken, not_taken) = self.branches[&pc];
+            writeln!(
+                out,
+                "{:>10} {:>10} {:>5.1}%  {}",
+                taken,
+                not_taken,
+                percent(taken, taken + not_taken),
+                super::callstack::describe(pc, symbols)
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rv32i::decode::decode;
+    use super::*;
+
+    fn record(pc: u32, raw: u32) -> Record {
+        Record {
+            count: 0,
+            pc,
+            raw,
+            ir: decode(raw),
+            rd: None,
+            access: None,
+            trap: None,
+        }
+    }
+
+    fn call(call: u32, target: u32) -> Frame {
+        Frame {
+            call,
+            target,
+            ret: call + 4,
+            sp: 0,
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let mut p = Profiler::default();
+        // addi a0, a0, -1; bne a0, zero, -4, looping twice in a call
+        let calls = [call(0x4, 0xC)];
+        for next in [0xC, 0xC, 0x14].iter() {
+            p.record(&record(0xC, 0xfff50513), 0x10, &calls);
+            p.record(&record(0x10, 0xfe051ee3), *next, &calls);
+        }
+        // addi a0, zero, 3
+        p.record(&record(0x0, 0x00300513), 0x4, &[]);
+        assert_eq!(7, p.total);
+        assert_eq!((2, 1), p.branches[&0x10]);
+        assert_eq!(3, p.pcs[&0xC].0);
+        assert_eq!(4, p.ops[&Operation::ADDI]);
+
+        let mut symbols = HashMap::new();
+        assert_eq!(
+            vec![
+                (String::from("0x0000000C"), 6),
+                (String::from("program"), 1)
+            ],
+            p.functions(&symbols)
+        );
+        symbols.insert(String::from("main"), 0x0);
+        symbols.insert(String::from("count"), 0xC);
+        assert_eq!("main 1\nmain;count 6\n", p.collapsed(&symbols));
+    }
+
+    #[test]
+    fn report() {
+        let mut p = Profiler::default();
+        p.record(&record(0x0, 0x00300513), 0x4, &[]);
+        p.record(&record(0x4, 0xfe051ee3), 0x8, &[]);
+        let report = p.report(&HashMap::new(), 1);
+        assert!(report.starts_with("Profile of 2 instructions\n"));
+        // only the hottest instruction, the first of those tied
+        assert!(report.contains(" 50.0%  0x00000000                     addi a0, zero, 3\n"));
+        assert!(!report.contains("bne a0, zero"));
+        assert!(report.contains("         2 100.0%  program\n"));
+        assert!(report.contains("         1  50.0%  branch   all\n"));
+        assert!(report.contains("         0          1   0.0%  0x00000004\n"));
+    }
+}
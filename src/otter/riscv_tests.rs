@@ -0,0 +1,125 @@
+// Runs riscv-tests programs. Each reports through a write to 'tohost':
+// 1 for a pass, or (test number << 1) | 1 for the first test that failed.
+
+use super::super::util::elf;
+use super::super::util::parse::parse_int;
+use super::mcu::{HaltConfig, StopReason, MCU};
+use std::fs;
+use std::path::PathBuf;
+
+// the vendored rv32ui-p suite, see res/riscv-tests/README.md
+pub const RV32UI_DIR: &str = "res/riscv-tests/rv32ui";
+
+// the longest rv32ui test runs well under a thousand instructions
+const BUDGET: u64 = 1_000_000;
+
+// load a test and watch 'tohost', a symbol name or an address as linked
+pub fn load(elf: &elf::Elf, tohost: &str) -> Result<MCU, String> {
+    let addr = match parse_int(tohost) {
+        Ok(addr) => addr,
+        Err(_) => elf
+            .symbol(tohost)
+            .ok_or(format!("no symbol named {}", tohost))?,
+    };
+
+    let mut mcu = MCU::new();
+    // fence_i runs code it writes to .data
+    mcu.set_protection(false);
+    let base = mcu.load_elf(elf)?;
+    mcu.set_halt_config(HaltConfig {
+        tohost: Some(addr.wrapping_sub(base)),
+        budget: Some(BUDGET),
+        ..HaltConfig::default()
+    });
+    Ok(mcu)
+}
+
+// run the test at 'path' until it stops
+pub fn run(path: &str, tohost: &str) -> Result<StopReason, String> {
+    let elf = elf::read(path)?;
+    let mut mcu = load(&elf, tohost).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(mcu.run(|_s| {}))
+}
+
+pub fn passed(stop: &StopReason) -> bool {
+    *stop == StopReason::ToHost(1)
+}
+
+// expand directories into the files directly inside them, sorted by name
+pub fn collect(paths: &[&str]) -> Result<Vec<PathBuf>, String> {
+    let mut tests = Vec::new();
+    for path in paths {
+        let path = PathBuf::from(path);
+        if !path.is_dir() {
+            tests.push(path);
+            continue;
+        }
+        let entries = fs::read_dir(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_file())
+            .collect();
+        files.sort();
+        tests.append(&mut files);
+    }
+    Ok(tests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    #[test]
+    fn rv32ui() {
+        if !Path::new(RV32UI_DIR).is_dir() {
+            eprintln!("{} is missing, see res/riscv-tests/README.md", RV32UI_DIR);
+            return;
+        }
+        let tests = collect(&[RV32UI_DIR]).unwrap();
+        assert!(!tests.is_empty());
+
+        let mut failures = String::new();
+        for test in tests.iter() {
+            let stop = run(test.to_str().unwrap(), "tohost").unwrap();
+            if !passed(&stop) {
+                failures.push_str(&format!("{}: {}\n", test.display(), stop));
+            }
+        }
+        assert!(failures.is_empty(), "\n{}", failures);
+    }
+
+    #[test]
+    fn reports_test_number() {
+        // fails test 3 the way RVTEST_FAIL does, linked like riscv-tests
+        let program = [
+            0x00300193, // addi gp, zero, 3
+            0x00119193, // slli gp, gp, 1
+            0x0011e193, // ori gp, gp, 1
+            0x00000297, // auipc t0, 0
+            0x0632aa23, // sw gp, 0x74(t0)
+        ];
+        let mut data: Vec<u8> = program.iter().flat_map(|w: &u32| w.to_le_bytes()).collect();
+        data.resize(0x100, 0);
+        let mut symbols = HashMap::new();
+        symbols.insert(String::from("tohost"), 0x80000080);
+        let elf = elf::Elf {
+            entry: 0x80000000,
+            segments: vec![elf::Segment {
+                addr: 0x80000000,
+                data,
+                flags: elf::PF_R | elf::PF_W | elf::PF_X,
+            }],
+            symbols,
+        };
+
+        for tohost in ["tohost", "0x80000080"].iter() {
+            let stop = load(&elf, tohost).unwrap().run(|_s| {});
+            assert_eq!(StopReason::ToHost((3 << 1) | 1), stop);
+            assert_eq!("test 3 failed", stop.to_string());
+        }
+
+        assert!(load(&elf, "nosuchsymbol").is_err());
+    }
+}
@@ -1,6 +1,6 @@
 use super::bitwise;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Operation {
     // load upper
     LUI,
@@ -46,10 +46,140 @@ pub enum Operation {
     SRA,
     OR,
     AND,
+    // memory ordering
+    FENCE,
+    FENCEI,
+    // system
+    ECALL,
+    EBREAK,
+    MRET,
+    CSRRW,
+    CSRRS,
+    CSRRC,
+    CSRRWI,
+    CSRRSI,
+    CSRRCI,
     // no match
     Invalid,
 }
 
+// broad groups of operations
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OpClass {
+    // register-register and register-immediate arithmetic, lui, auipc
+    Alu,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    // fences, CSR accesses, ecall, ebreak, mret and invalid instructions
+    System,
+}
+
+impl OpClass {
+    // as --trace-class takes it
+    pub fn name(self) -> &'static str {
+        match self {
+            OpClass::Alu => "alu",
+            OpClass::Load => "load",
+            OpClass::Store => "store",
+            OpClass::Branch => "branch",
+            OpClass::Jump => "jump",
+            OpClass::System => "system",
+        }
+    }
+}
+
+impl Operation {
+    pub fn class(&self) -> OpClass {
+        match self {
+            Operation::LB | Operation::LH | Operation::LW | Operation::LBU | Operation::LHU => {
+                OpClass::Load
+            }
+            Operation::SB | Operation::SH | Operation::SW => OpClass::Store,
+            Operation::BEQ
+            | Operation::BNE
+            | Operation::BLT
+            | Operation::BGE
+            | Operation::BLTU
+            | Operation::BGEU => OpClass::Branch,
+            Operation::JAL | Operation::JALR => OpClass::Jump,
+            Operation::FENCE
+            | Operation::FENCEI
+            | Operation::ECALL
+            | Operation::EBREAK
+            | Operation::MRET
+            | Operation::CSRRW
+            | Operation::CSRRS
+            | Operation::CSRRC
+            | Operation::CSRRWI
+            | Operation::CSRRSI
+            | Operation::CSRRCI
+            | Operation::Invalid => OpClass::System,
+            _ => OpClass::Alu,
+        }
+    }
+
+    // whether the operation writes its result to rd
+    pub fn writes_rd(&self) -> bool {
+        match self.class() {
+            OpClass::Alu | OpClass::Load | OpClass::Jump => true,
+            OpClass::Store | OpClass::Branch => false,
+            OpClass::System => matches!(
+                self,
+                Operation::CSRRW
+                    | Operation::CSRRS
+                    | Operation::CSRRC
+                    | Operation::CSRRWI
+                    | Operation::CSRRSI
+                    | Operation::CSRRCI
+            ),
+        }
+    }
+
+    // whether the operation reads rs1
+    pub fn reads_rs1(&self) -> bool {
+        !matches!(
+            self,
+            Operation::LUI
+                | Operation::AUIPC
+                | Operation::JAL
+                | Operation::FENCE
+                | Operation::FENCEI
+                | Operation::ECALL
+                | Operation::EBREAK
+                | Operation::MRET
+                | Operation::CSRRWI
+                | Operation::CSRRSI
+                | Operation::CSRRCI
+                | Operation::Invalid
+        )
+    }
+
+    // whether the operation reads rs2: register-register arithmetic,
+    // branches and stores
+    pub fn reads_rs2(&self) -> bool {
+        match self.class() {
+            OpClass::Branch | OpClass::Store => true,
+            OpClass::Alu => matches!(
+                self,
+                Operation::ADD
+                    | Operation::SUB
+                    | Operation::SLL
+                    | Operation::SLT
+                    | Operation::SLTU
+                    | Operation::XOR
+                    | Operation::SRL
+                    | Operation::SRA
+                    | Operation::OR
+                    | Operation::AND
+            ),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Instruction {
     pub op: Operation,
     pub rs1: u32,
@@ -58,6 +188,23 @@ pub struct Instruction {
     pub imm: u32,
 }
 
+// ra and t0, which the calling convention uses to hold return addresses
+fn is_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+impl Instruction {
+    // a jump that saves its return address, e.g. 'call' or 'jalr ra'
+    pub fn is_call(&self) -> bool {
+        matches!(self.op, Operation::JAL | Operation::JALR) && is_link(self.rd)
+    }
+
+    // a jump back to a saved return address, e.g. 'ret'
+    pub fn is_return(&self) -> bool {
+        self.op == Operation::JALR && !is_link(self.rd) && is_link(self.rs1)
+    }
+}
+
 fn decode_j_imm(ir_bits: &[bool]) -> u32 {
     let j_imm_vec = bitwise::vec_concat(&vec![ir_bits[31]; 12][..], &ir_bits[12..=19]);
     let j_imm_vec = bitwise::vec_concat(&j_imm_vec[..], &vec![ir_bits[20]][..]);
@@ -84,11 +231,16 @@ fn decode_i_imm(ir_bits: &[bool]) -> u32 {
 }
 
 fn decode_s_imm(ir_bits: &[bool]) -> u32 {
-    let s_imm_vec = bitwise::vec_concat(&vec![ir_bits[31]; 20][..], &ir_bits[25..=30]);
+    let s_imm_vec = bitwise::vec_concat(&vec![ir_bits[31]; 21][..], &ir_bits[25..=30]);
     let s_imm_vec = bitwise::vec_concat(&s_imm_vec[..], &ir_bits[7..=11]);
     bitwise::vec_to_u32(&s_imm_vec[..])
 }
 
+// CSR addresses are unsigned, unlike the I-type immediate
+fn decode_csr(ir_bits: &[bool]) -> u32 {
+    bitwise::vec_to_u32(&ir_bits[20..=31])
+}
+
 fn decode_funct3(ir_bits: &[bool]) -> u32 {
     bitwise::vec_to_u32(&ir_bits[12..=14])
 }
@@ -233,6 +385,41 @@ pub fn decode(ir: u32) -> Instruction {
                 _ => (),
             }
         }
+        // fence
+        // the ordering bits are kept in imm, but have no effect
+        0b0001111 => {
+            imm = decode_i_imm(&ir_bits);
+            rs1 = decode_rs1(&ir_bits);
+            rd = decode_rd(&ir_bits);
+            match decode_funct3(&ir_bits) {
+                0b000 => op_type = Operation::FENCE,
+                0b001 => op_type = Operation::FENCEI,
+                _ => (),
+            }
+        }
+        // system
+        // CSR instructions keep the CSR address in imm;
+        // the immediate variants keep their 5-bit zimm in rs1
+        0b1110011 => {
+            rs1 = decode_rs1(&ir_bits);
+            rd = decode_rd(&ir_bits);
+            imm = decode_csr(&ir_bits);
+            match decode_funct3(&ir_bits) {
+                0b000 if rs1 == 0 && rd == 0 => match imm {
+                    0x000 => op_type = Operation::ECALL,
+                    0x001 => op_type = Operation::EBREAK,
+                    0x302 => op_type = Operation::MRET,
+                    _ => (),
+                },
+                0b001 => op_type = Operation::CSRRW,
+                0b010 => op_type = Operation::CSRRS,
+                0b011 => op_type = Operation::CSRRC,
+                0b101 => op_type = Operation::CSRRWI,
+                0b110 => op_type = Operation::CSRRSI,
+                0b111 => op_type = Operation::CSRRCI,
+                _ => (),
+            }
+        }
         _ => (),
     }
 
@@ -283,6 +470,21 @@ pub fn reg_name(index: u32) -> String {
     })
 }
 
+// inverse of reg_name, also accepts 'xN' and 'fp'
+#[allow(dead_code)]
+pub fn reg_index(name: &str) -> Option<u32> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(n) = name.strip_prefix('x') {
+        return match n.parse::<u32>() {
+            Ok(i) if i < 32 => Some(i),
+            _ => None,
+        };
+    }
+    (0..32).find(|i| reg_name(*i) == name)
+}
+
 // TODO: this needs more testing; ideally 1-2 tests per instruction
 #[cfg(test)]
 mod tests {
@@ -317,6 +519,21 @@ mod tests {
         assert_eq!(-732, ir.imm as i32);
     }
 
+    #[test]
+    fn calls() {
+        // call, jalr ra, jalr t0, ret, jr a0, j
+        let ir: Vec<Instruction> = [
+            0x008000ef, 0x000500e7, 0x000502e7, 0x00008067, 0x00050067, 0x0080006f,
+        ]
+        .iter()
+        .map(|w| decode(*w))
+        .collect();
+        let calls: Vec<bool> = ir.iter().map(|i| i.is_call()).collect();
+        let returns: Vec<bool> = ir.iter().map(|i| i.is_return()).collect();
+        assert_eq!(vec![true, true, true, false, false, false], calls);
+        assert_eq!(vec![false, false, false, true, false, false], returns);
+    }
+
     #[test]
     fn lui1() {
         // lui x1, 15
@@ -346,6 +563,16 @@ mod tests {
         assert_eq!(ir.imm, 12);
     }
 
+    #[test]
+    fn sw2() {
+        // sw sp, -12(ra)
+        let ir_bytes: u32 = 0xfe20aa23;
+        let ir = decode(ir_bytes);
+        assert_eq!(ir.rs1, 1);
+        assert_eq!(ir.rs2, 2);
+        assert_eq!(-12, ir.imm as i32);
+    }
+
     #[test]
     fn add1() {
         // add x13, x14, x15
@@ -365,4 +592,60 @@ mod tests {
         println!("exp: {:#034b}", -5728);
         assert_eq!(-5728, ir.imm as i32);
     }
+
+    #[test]
+    fn reg_names() {
+        for i in 0..32 {
+            assert_eq!(Some(i), reg_index(&reg_name(i)));
+            assert_eq!(Some(i), reg_index(&format!("x{}", i)));
+        }
+        assert_eq!(Some(8), reg_index("fp"));
+        assert_eq!(None, reg_index("x32"));
+        assert_eq!(None, reg_index("None"));
+    }
+
+    #[test]
+    fn system1() {
+        // ecall
+        let ir = decode(0x00000073);
+        assert!(matches!(ir.op, Operation::ECALL));
+        // ebreak
+        let ir = decode(0x00100073);
+        assert!(matches!(ir.op, Operation::EBREAK));
+        // mret
+        let ir = decode(0x30200073);
+        assert!(matches!(ir.op, Operation::MRET));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(OpClass::Alu, decode(0x0000f0b7).op.class()); // lui
+        assert_eq!(OpClass::Store, decode(0x00112623).op.class()); // sw
+        assert_eq!(OpClass::Branch, decode(0x01001663).op.class()); // bne
+        assert_eq!(OpClass::Jump, decode(0xd24080e7).op.class()); // jalr
+        assert!(decode(0x34329373).op.writes_rd()); // csrrw
+        assert!(!decode(0x00000073).op.writes_rd()); // ecall
+        assert!(!decode(0x00112623).op.writes_rd());
+    }
+
+    #[test]
+    fn fence1() {
+        // fence
+        let ir = decode(0x0ff0000f);
+        assert!(matches!(ir.op, Operation::FENCE));
+        // fence.i
+        let ir = decode(0x0000100f);
+        assert!(matches!(ir.op, Operation::FENCEI));
+    }
+
+    #[test]
+    fn csrrw1() {
+        // csrrw x6, mtval, x5
+        let ir_bytes: u32 = 0x34329373;
+        let ir = decode(ir_bytes);
+        assert!(matches!(ir.op, Operation::CSRRW));
+        assert_eq!(ir.rd, 6);
+        assert_eq!(ir.rs1, 5);
+        assert_eq!(ir.imm, 0x343);
+    }
 }
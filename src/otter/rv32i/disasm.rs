@@ -0,0 +1,92 @@
+use super::super::devices::csr;
+use super::decode::{reg_name, Instruction, OpClass, Operation};
+
+// Renders instructions in the usual assembler syntax, e.g.
+// "addi t0, zero, 1", "lw a0, 8(sp)" or "beq a0, a1, 0x000001C0".
+// Branch and jump targets are absolute, so the instruction's PC is needed.
+
+pub fn mnemonic(op: Operation) -> String {
+    match op {
+        Operation::FENCEI => String::from("fence.i"),
+        Operation::Invalid => String::from("invalid"),
+        op => format!("{:?}", op).to_lowercase(),
+    }
+}
+
+fn csr_name(addr: u32) -> String {
+    match csr::name(addr) {
+        Some(name) => String::from(name),
+        None => format!("{:#05X}", addr),
+    }
+}
+
+pub fn disassemble(ir: &Instruction, pc: u32) -> String {
+    let m = mnemonic(ir.op);
+    let rd = reg_name(ir.rd);
+    let rs1 = reg_name(ir.rs1);
+    let rs2 = reg_name(ir.rs2);
+    let imm = ir.imm as i32;
+    let target = pc.wrapping_add(ir.imm);
+
+    match ir.op {
+        Operation::LUI | Operation::AUIPC => format!("{} {}, {:#X}", m, rd, ir.imm >> 12),
+        Operation::JAL => format!("{} {}, {:#010X}", m, rd, target),
+        Operation::JALR => format!("{} {}, {}({})", m, rd, imm, rs1),
+        Operation::ADDI
+        | Operation::SLTI
+        | Operation::SLTIU
+        | Operation::XORI
+        | Operation::ORI
+        | Operation::ANDI => format!("{} {}, {}, {}", m, rd, rs1, imm),
+        Operation::SLLI | Operation::SRLI | Operation::SRAI => {
+            format!("{} {}, {}, {}", m, rd, rs1, ir.imm & 0x1F)
+        }
+        Operation::CSRRW | Operation::CSRRS | Operation::CSRRC => {
+            format!("{} {}, {}, {}", m, rd, csr_name(ir.imm), rs1)
+        }
+        Operation::CSRRWI | Operation::CSRRSI | Operation::CSRRCI => {
+            format!("{} {}, {}, {}", m, rd, csr_name(ir.imm), ir.rs1)
+        }
+        Operation::FENCE
+        | Operation::FENCEI
+        | Operation::ECALL
+        | Operation::EBREAK
+        | Operation::MRET
+        | Operation::Invalid => m,
+        op => match op.class() {
+            OpClass::Load => format!("{} {}, {}({})", m, rd, imm, rs1),
+            OpClass::Store => format!("{} {}, {}({})", m, rs2, imm, rs1),
+            OpClass::Branch => format!("{} {}, {}, {:#010X}", m, rs1, rs2, target),
+            _ => format!("{} {}, {}, {}", m, rd, rs1, rs2),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decode::decode;
+    use super::*;
+
+    #[test]
+    fn formats() {
+        let cases: [(u32, &str); 14] = [
+            (0x00100293, "addi t0, zero, 1"),
+            (0xfff00293, "addi t0, zero, -1"),
+            (0x00f706b3, "add a3, a4, a5"),
+            (0x40a1d093, "srai ra, gp, 10"),
+            (0x0000f0b7, "lui ra, 0xF"),
+            (0x00812503, "lw a0, 8(sp)"),
+            (0xfe20aa23, "sw sp, -12(ra)"),
+            (0x01001663, "bne zero, a6, 0x0000010C"),
+            (0xd24080e7, "jalr ra, -732(ra)"),
+            (0x9a1fe06f, "jal zero, 0xFFFFEAA0"),
+            (0x34329373, "csrrw t1, mtval, t0"),
+            (0x3052d073, "csrrwi zero, mtvec, 5"),
+            (0x0000100f, "fence.i"),
+            (0x00000000, "invalid"),
+        ];
+        for (raw, text) in cases.iter() {
+            assert_eq!(*text, disassemble(&decode(*raw), 0x100));
+        }
+    }
+}
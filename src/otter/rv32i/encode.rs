@@ -0,0 +1,120 @@
+use super::decode::{Instruction, Operation};
+
+// The inverse of decode::decode. Immediates are taken exactly as decode
+// produces them, e.g. SRAI carries its funct7 bit in imm and CSR
+// instructions carry the CSR address in imm and any zimm in rs1.
+
+fn r_type(funct7: u32, funct3: u32, ir: &Instruction) -> u32 {
+    (funct7 << 25) | (ir.rs2 << 20) | (ir.rs1 << 15) | (funct3 << 12) | (ir.rd << 7) | 0b0110011
+}
+
+fn i_type(opcode: u32, funct3: u32, ir: &Instruction) -> u32 {
+    ((ir.imm & 0xFFF) << 20) | (ir.rs1 << 15) | (funct3 << 12) | (ir.rd << 7) | opcode
+}
+
+fn s_type(funct3: u32, ir: &Instruction) -> u32 {
+    (((ir.imm >> 5) & 0x7F) << 25)
+        | (ir.rs2 << 20)
+        | (ir.rs1 << 15)
+        | (funct3 << 12)
+        | ((ir.imm & 0x1F) << 7)
+        | 0b0100011
+}
+
+fn b_type(funct3: u32, ir: &Instruction) -> u32 {
+    (((ir.imm >> 12) & 0b1) << 31)
+        | (((ir.imm >> 5) & 0x3F) << 25)
+        | (ir.rs2 << 20)
+        | (ir.rs1 << 15)
+        | (funct3 << 12)
+        | (((ir.imm >> 1) & 0xF) << 8)
+        | (((ir.imm >> 11) & 0b1) << 7)
+        | 0b1100011
+}
+
+fn u_type(opcode: u32, ir: &Instruction) -> u32 {
+    (ir.imm & 0xFFFFF000) | (ir.rd << 7) | opcode
+}
+
+fn j_type(ir: &Instruction) -> u32 {
+    (((ir.imm >> 20) & 0b1) << 31)
+        | (((ir.imm >> 1) & 0x3FF) << 21)
+        | (((ir.imm >> 11) & 0b1) << 20)
+        | (((ir.imm >> 12) & 0xFF) << 12)
+        | (ir.rd << 7)
+        | 0b1101111
+}
+
+// returns None for Operation::Invalid
+pub fn encode(ir: &Instruction) -> Option<u32> {
+    Some(match ir.op {
+        Operation::LUI => u_type(0b0110111, ir),
+        Operation::AUIPC => u_type(0b0010111, ir),
+        Operation::JAL => j_type(ir),
+        Operation::JALR => i_type(0b1100111, 0b000, ir),
+        Operation::BEQ => b_type(0b000, ir),
+        Operation::BNE => b_type(0b001, ir),
+        Operation::BLT => b_type(0b100, ir),
+        Operation::BGE => b_type(0b101, ir),
+        Operation::BLTU => b_type(0b110, ir),
+        Operation::BGEU => b_type(0b111, ir),
+        Operation::LB => i_type(0b0000011, 0b000, ir),
+        Operation::LH => i_type(0b0000011, 0b001, ir),
+        Operation::LW => i_type(0b0000011, 0b010, ir),
+        Operation::LBU => i_type(0b0000011, 0b100, ir),
+        Operation::LHU => i_type(0b0000011, 0b101, ir),
+        Operation::SB => s_type(0b000, ir),
+        Operation::SH => s_type(0b001, ir),
+        Operation::SW => s_type(0b010, ir),
+        Operation::ADDI => i_type(0b0010011, 0b000, ir),
+        Operation::SLTI => i_type(0b0010011, 0b010, ir),
+        Operation::SLTIU => i_type(0b0010011, 0b011, ir),
+        Operation::XORI => i_type(0b0010011, 0b100, ir),
+        Operation::ORI => i_type(0b0010011, 0b110, ir),
+        Operation::ANDI => i_type(0b0010011, 0b111, ir),
+        Operation::SLLI => i_type(0b0010011, 0b001, ir),
+        Operation::SRLI => i_type(0b0010011, 0b101, ir),
+        Operation::SRAI => i_type(0b0010011, 0b101, ir),
+        Operation::ADD => r_type(0b0000000, 0b000, ir),
+        Operation::SUB => r_type(0b0100000, 0b000, ir),
+        Operation::SLL => r_type(0b0000000, 0b001, ir),
+        Operation::SLT => r_type(0b0000000, 0b010, ir),
+        Operation::SLTU => r_type(0b0000000, 0b011, ir),
+        Operation::XOR => r_type(0b0000000, 0b100, ir),
+        Operation::SRL => r_type(0b0000000, 0b101, ir),
+        Operation::SRA => r_type(0b0100000, 0b101, ir),
+        Operation::OR => r_type(0b0000000, 0b110, ir),
+        Operation::AND => r_type(0b0000000, 0b111, ir),
+        Operation::FENCE => i_type(0b0001111, 0b000, ir),
+        Operation::FENCEI => i_type(0b0001111, 0b001, ir),
+        Operation::ECALL => 0x00000073,
+        Operation::EBREAK => 0x00100073,
+        Operation::MRET => 0x30200073,
+        Operation::CSRRW => i_type(0b1110011, 0b001, ir),
+        Operation::CSRRS => i_type(0b1110011, 0b010, ir),
+        Operation::CSRRC => i_type(0b1110011, 0b011, ir),
+        Operation::CSRRWI => i_type(0b1110011, 0b101, ir),
+        Operation::CSRRSI => i_type(0b1110011, 0b110, ir),
+        Operation::CSRRCI => i_type(0b1110011, 0b111, ir),
+        Operation::Invalid => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::decode::decode;
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // one of each format, see decode tests
+        for ir_bytes in [
+            0x01001663, 0xfe000ae3, 0xd24080e7, 0x0000f0b7, 0x1d800093, 0x00112623, 0xfe20aa23,
+            0x00f706b3, 0x9a1fe06f, 0x34329373, 0x40a1d093, 0x00000073, 0x0ff0000f,
+        ]
+        .iter()
+        {
+            assert_eq!(Some(*ir_bytes), encode(&decode(*ir_bytes)));
+        }
+    }
+}
@@ -1,2 +1,5 @@
 mod bitwise;
 pub mod decode;
+pub mod disasm;
+#[cfg(test)]
+pub mod encode;
@@ -0,0 +1,164 @@
+// Snapshots of the whole machine, as written by MCU::snapshot.
+//
+// A snapshot starts with the magic bytes "OEMUSNAP" and a format version,
+// then each part of the machine in order, little-endian:
+//
+//     pc, entry, end of program    u32 x 3
+//     semihosting program break    u32
+//     instructions executed        u64
+//     cycles counted               u64
+//     register file                u32 x 32
+//     CSRs                         count u32, then (address u32, value u32)
+//     RAM                          size u32, a bitmap of initialized bytes
+//                                  (size / 8 bytes), then every byte
+//     MMIO devices                 count u32, then (address u32, size u32,
+//                                  contents)
+//     UART and timer addresses     u32 x 2
+//     memory regions               count u32, then (begin u32, end u32,
+//                                  permissions u32 as ELF flags)
+//     breakpoints                  count u32, then (enabled u32, hits u64,
+//                                  spec as written by Display, a string)
+//
+// Strings are a length u32 then UTF-8 bytes.
+//
+// Restoring checks the version, so old snapshots keep working or are
+// refused with a reason, never misread.
+
+use std::fs;
+
+pub const MAGIC: &[u8; 8] = b"OEMUSNAP";
+pub const VERSION: u32 = 5;
+
+pub struct Writer {
+    pub buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        let mut w = Writer { buf: Vec::new() };
+        w.bytes(MAGIC);
+        w.u32(VERSION);
+        w
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.buf.extend_from_slice(b);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.bytes(s.as_bytes());
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    // check the header and start reading after it
+    pub fn new(data: &'a [u8]) -> Result<Reader<'a>, String> {
+        let mut r = Reader { data, pos: 0 };
+        if r.bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(String::from("not an oemu snapshot"));
+        }
+        let version = r.u32()?;
+        if version != VERSION {
+            return Err(format!(
+                "snapshot is version {}, but this oemu reads version {}",
+                version, VERSION
+            ));
+        }
+        Ok(r)
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err(String::from("snapshot is truncated"));
+        }
+        let b = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let b = self.bytes(len)?;
+        String::from_utf8(b.to_vec()).map_err(|_| String::from("snapshot has a bad string"))
+    }
+
+    // error on anything left over
+    pub fn finish(&self) -> Result<(), String> {
+        if self.pos != self.data.len() {
+            return Err(format!(
+                "{} unexpected bytes at the end of the snapshot",
+                self.data.len() - self.pos
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn save(path: &str, snapshot: &[u8]) -> Result<(), String> {
+    fs::write(path, snapshot).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn load(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header() {
+        let mut w = Writer::new();
+        w.u32(0xDEADBEEF);
+        w.u64(1 << 40);
+        w.str("0x40 if a0 == 5");
+        let mut r = Reader::new(&w.buf).unwrap();
+        assert_eq!(Ok(0xDEADBEEF), r.u32());
+        assert!(r.finish().is_err());
+        assert_eq!(Ok(1 << 40), r.u64());
+        assert_eq!(Ok(String::from("0x40 if a0 == 5")), r.str());
+        assert_eq!(Ok(()), r.finish());
+        assert_eq!(Err(String::from("snapshot is truncated")), r.u32());
+
+        assert_eq!(
+            Some(String::from("not an oemu snapshot")),
+            Reader::new(b"OEMUDUMP\x01\x00\x00\x00").err()
+        );
+        let mut newer = w.buf.clone();
+        newer[MAGIC.len()] = VERSION as u8 + 1;
+        assert_eq!(
+            Some(format!(
+                "snapshot is version {}, but this oemu reads version {}",
+                VERSION + 1,
+                VERSION
+            )),
+            Reader::new(&newer).err()
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::Read;
+
+// newlib/Linux syscall numbers, passed in a7
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_BRK: u32 = 214;
+
+// errno values returned (negated) in a0
+const EBADF: i32 = 9;
+const EIO: i32 = 5;
+pub const ENOSYS: i32 = 38;
+
+// state for servicing ecalls on behalf of the program
+pub struct Semihosting {
+    // source for reads from fd 0
+    input: Option<Box<dyn Read + Send>>,
+    // bytes written to fd 1/2 that the frontend has not printed yet
+    output: Vec<u8>,
+    // current program break, and the lowest it may go
+    brk: u32,
+    brk_min: u32,
+    brk_max: u32,
+}
+
+impl Semihosting {
+    // 'input' feeds reads from fd 0, None reads as end-of-file
+    pub fn new(input: Option<Box<dyn Read + Send>>) -> Semihosting {
+        Semihosting {
+            input,
+            output: Vec::new(),
+            brk: 0,
+            brk_min: 0,
+            brk_max: 0,
+        }
+    }
+
+    // set the heap bounds, e.g. after loading a program
+    pub fn set_heap(&mut self, begin: u32, end: u32) {
+        self.brk = begin;
+        self.brk_min = begin;
+        self.brk_max = end;
+    }
+
+    // write to a file descriptor, returns bytes written or -errno
+    pub fn write(&mut self, fd: u32, data: &[u8]) -> i32 {
+        match fd {
+            1 | 2 => {
+                self.output.extend_from_slice(data);
+                data.len() as i32
+            }
+            _ => -EBADF,
+        }
+    }
+
+    // read at most 'len' bytes from a file descriptor; the buffer is 'len'
+    // bytes, so the caller bounds it
+    pub fn read(&mut self, fd: u32, len: u32) -> Result<Vec<u8>, i32> {
+        if fd != 0 {
+            return Err(-EBADF);
+        }
+        let input = match &mut self.input {
+            None => return Ok(Vec::new()),
+            Some(i) => i,
+        };
+        let mut buf = vec![0; len as usize];
+        match input.read(&mut buf) {
+            Err(_) => Err(-EIO),
+            Ok(n) => {
+                buf.truncate(n);
+                Ok(buf)
+            }
+        }
+    }
+
+    // move the program break, returns the new break
+    // a request outside the heap leaves it unchanged, as Linux does
+    pub fn brk(&mut self, addr: u32) -> u32 {
+        if addr >= self.brk_min && addr <= self.brk_max {
+            self.brk = addr;
+        }
+        self.brk
+    }
+
+    // the current program break
+    pub fn program_break(&self) -> u32 {
+        self.brk
+    }
+
+    // take everything written to the console since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let mut sh = Semihosting::new(Some(Box::new(&b"abc"[..])));
+        assert_eq!(2, sh.write(1, b"hi"));
+        assert_eq!(-EBADF, sh.write(3, b"hi"));
+        assert_eq!(b"hi".to_vec(), sh.take_output());
+        assert!(sh.take_output().is_empty());
+        assert_eq!(Ok(b"ab".to_vec()), sh.read(0, 2));
+        assert_eq!(Ok(b"c".to_vec()), sh.read(0, 2));
+        assert_eq!(Ok(Vec::new()), sh.read(0, 2));
+    }
+
+    #[test]
+    fn brk() {
+        let mut sh = Semihosting::new(None);
+        sh.set_heap(0x1000, 0x2000);
+        assert_eq!(0x1000, sh.brk(0));
+        assert_eq!(0x1800, sh.brk(0x1800));
+        assert_eq!(0x1800, sh.brk(0x3000));
+        assert_eq!(0x1800, sh.program_break());
+    }
+}
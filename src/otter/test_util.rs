@@ -0,0 +1,8 @@
+// Helpers shared by the tests of several modules.
+
+use super::mcu::MCU;
+
+// load instruction words as a raw binary
+pub fn load_words(mcu: &mut MCU, words: &[u32]) {
+    mcu.load(words.iter().map(|w| w.to_le_bytes().to_vec()).collect());
+}
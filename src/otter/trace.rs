@@ -0,0 +1,342 @@
+// Instruction traces: one record per retired instruction, written as
+// human-readable text or as JSON lines, optionally filtered by PC range
+// and operation class.
+
+use super::super::util::parse::parse_int;
+use super::rv32i::decode::{reg_name, Instruction, OpClass};
+use super::rv32i::disasm;
+use super::trap::Exception;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    // Spike's --log-commits lines
+    Spike,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "spike" => Some(Format::Spike),
+            _ => None,
+        }
+    }
+}
+
+// a load or store made by an instruction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Access {
+    pub addr: u32,
+    pub bytes: u32,
+    pub data: u32,
+    pub write: bool,
+}
+
+// everything one instruction did
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Record {
+    // instructions retired since reset, including this one
+    pub count: u64,
+    pub pc: u32,
+    pub raw: u32,
+    pub ir: Instruction,
+    // register written and its new value
+    pub rd: Option<(u32, u32)>,
+    pub access: Option<Access>,
+    pub trap: Option<Exception>,
+}
+
+// escape a string for a JSON string literal
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Record {
+    // e.g. "       3 0x00000008: 00812503  lw a0, 8(sp)    a0 = 0x00000001  mem[0x0000FFF8] -> 0x00000001"
+    pub fn text(&self) -> String {
+        let mut line = format!(
+            "{:>8} {:#010X}: {:08x}  {:<28}",
+            self.count,
+            self.pc,
+            self.raw,
+            disasm::disassemble(&self.ir, self.pc)
+        );
+        if let Some((rd, value)) = self.rd {
+            line.push_str(&format!("  {} = {:#010X}", reg_name(rd), value));
+        }
+        if let Some(a) = self.access {
+            let dir = if a.write { "<-" } else { "->" };
+            line.push_str(&format!("  mem[{:#010X}] {} {:#010X}", a.addr, dir, a.data));
+        }
+        if let Some(e) = self.trap {
+            line.push_str(&format!("  trap: {}", e));
+        }
+        line.trim_end().to_string()
+    }
+
+    // one JSON object, numbers in decimal
+    pub fn json(&self) -> String {
+        let mut line = format!(
+            "{{\"n\":{},\"pc\":{},\"raw\":{},\"asm\":{}",
+            self.count,
+            self.pc,
+            self.raw,
+            json_str(&disasm::disassemble(&self.ir, self.pc))
+        );
+        if let Some((rd, value)) = self.rd {
+            line.push_str(&format!(",\"rd\":{},\"value\":{}", rd, value));
+        }
+        if let Some(a) = self.access {
+            line.push_str(&format!(
+                ",\"mem\":{{\"addr\":{},\"bytes\":{},\"data\":{},\"write\":{}}}",
+                a.addr, a.bytes, a.data, a.write
+            ));
+        }
+        if let Some(e) = self.trap {
+            line.push_str(&format!(
+                ",\"trap\":{{\"cause\":{},\"name\":{}}}",
+                e.cause(),
+                json_str(&e.to_string())
+            ));
+        }
+        line.push('}');
+        line
+    }
+
+    // Spike's commit log, e.g. "core   0: 3 0x00000000 (0x11000837) x16 0x11000000":
+    // hart, privilege (always machine), PC and encoding, then the register
+    // written, the address read or the address and data written, with values
+    // as wide as the access. Instructions that trap never commit, so they
+    // have no line.
+    pub fn commit(&self) -> Option<String> {
+        if self.trap.is_some() {
+            return None;
+        }
+        let mut line = format!("core   0: 3 0x{:08x} (0x{:08x})", self.pc, self.raw);
+        if let Some((rd, value)) = self.rd {
+            line.push_str(&format!(" x{:<2} 0x{:08x}", rd, value));
+        }
+        if let Some(a) = self.access {
+            line.push_str(&format!(" mem 0x{:08x}", a.addr));
+            if a.write {
+                let digits = 2 * a.bytes as usize;
+                line.push_str(&format!(" 0x{:0width$x}", a.data, width = digits));
+            }
+        }
+        Some(line)
+    }
+}
+
+// which instructions to record; an empty filter records everything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    // PCs from the first address up to, not including, the second
+    pub range: Option<(u32, u32)>,
+    // record only these classes, or every class if empty
+    pub classes: Vec<OpClass>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        if let Some((begin, end)) = self.range {
+            if record.pc < begin || record.pc >= end {
+                return false;
+            }
+        }
+        self.classes.is_empty() || self.classes.contains(&record.ir.op.class())
+    }
+
+    // parse 'BEGIN:END', e.g. '0x100:0x200'
+    pub fn parse_range(s: &str) -> Result<(u32, u32), String> {
+        let mut parts = s.splitn(2, ':');
+        let begin = parts.next().unwrap();
+        let end = parts
+            .next()
+            .ok_or(format!("expected BEGIN:END, got {}", s))?;
+        let begin = parse_int(begin).map_err(|e| format!("{}: {}", begin, e))?;
+        let end = parse_int(end).map_err(|e| format!("{}: {}", end, e))?;
+        Ok((begin, end))
+    }
+
+    // parse a comma-separated list, e.g. 'load,store'
+    pub fn parse_classes(s: &str) -> Result<Vec<OpClass>, String> {
+        s.split(',')
+            .map(|name| match name.trim() {
+                "alu" => Ok(OpClass::Alu),
+                "load" => Ok(OpClass::Load),
+                "store" => Ok(OpClass::Store),
+                "branch" => Ok(OpClass::Branch),
+                "jump" => Ok(OpClass::Jump),
+                "system" => Ok(OpClass::System),
+                other => Err(format!("unknown instruction class {}", other)),
+            })
+            .collect()
+    }
+}
+
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: Format,
+    filter: Filter,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, format: Format, filter: Filter) -> Tracer {
+        Tracer {
+            out,
+            format,
+            filter,
+        }
+    }
+
+    // trace to a new file at 'path'
+    pub fn create(path: &str, format: Format, filter: Filter) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(file)), format, filter))
+    }
+
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        if !self.filter.matches(record) {
+            return Ok(());
+        }
+        let line = match self.format {
+            Format::Text => record.text(),
+            Format::Json => record.json(),
+            Format::Spike => match record.commit() {
+                Some(line) => line,
+                None => return Ok(()),
+            },
+        };
+        writeln!(self.out, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rv32i::decode::decode;
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // collects output for inspection
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn load_record() -> Record {
+        Record {
+            count: 3,
+            pc: 0x8,
+            raw: 0x00812503,
+            ir: decode(0x00812503),
+            rd: Some((10, 1)),
+            access: Some(Access {
+                addr: 0xFFF8,
+                bytes: 4,
+                data: 1,
+                write: false,
+            }),
+            trap: None,
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let r = load_record();
+        assert_eq!(
+            "       3 0x00000008: 00812503  lw a0, 8(sp)                  a0 = 0x00000001  mem[0x0000FFF8] -> 0x00000001",
+            r.text()
+        );
+        assert_eq!(
+            "{\"n\":3,\"pc\":8,\"raw\":8463619,\"asm\":\"lw a0, 8(sp)\",\"rd\":10,\"value\":1,\"mem\":{\"addr\":65528,\"bytes\":4,\"data\":1,\"write\":false}}",
+            r.json()
+        );
+        assert_eq!("\"a\\\"b\"", json_str("a\"b"));
+    }
+
+    #[test]
+    fn spike() {
+        let mut r = load_record();
+        assert_eq!(
+            "core   0: 3 0x00000008 (0x00812503) x10 0x00000001 mem 0x0000fff8",
+            r.commit().unwrap()
+        );
+
+        // sb t0, 0x100(zero)
+        r.raw = 0x10500023;
+        r.ir = decode(r.raw);
+        r.rd = None;
+        r.access = Some(Access {
+            addr: 0x100,
+            bytes: 1,
+            data: 0x5,
+            write: true,
+        });
+        assert_eq!(
+            "core   0: 3 0x00000008 (0x10500023) mem 0x00000100 0x05",
+            r.commit().unwrap()
+        );
+
+        // lui a6, 0x11000
+        r.raw = 0x11000837;
+        r.ir = decode(r.raw);
+        r.pc = 0;
+        r.rd = Some((16, 0x11000000));
+        r.access = None;
+        assert_eq!(
+            "core   0: 3 0x00000000 (0x11000837) x16 0x11000000",
+            r.commit().unwrap()
+        );
+
+        r.trap = Some(Exception::EcallM);
+        assert_eq!(None, r.commit());
+    }
+
+    #[test]
+    fn filters() {
+        let buf = Shared(Arc::new(Mutex::new(Vec::new())));
+        let filter = Filter {
+            range: Some(Filter::parse_range("0x4:0x10").unwrap()),
+            classes: Filter::parse_classes("load, store").unwrap(),
+        };
+        let mut tracer = Tracer::new(Box::new(buf.clone()), Format::Json, filter);
+
+        let mut r = load_record();
+        tracer.record(&r).unwrap();
+        // out of range
+        r.pc = 0x10;
+        tracer.record(&r).unwrap();
+        // wrong class
+        r.pc = 0x8;
+        r.ir = decode(0x00100293);
+        tracer.record(&r).unwrap();
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(1, out.lines().count());
+
+        assert!(Filter::parse_range("0x4").is_err());
+        assert!(Filter::parse_classes("alu,bogus").is_err());
+    }
+}
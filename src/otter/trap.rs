@@ -0,0 +1,80 @@
+use std::fmt;
+
+// synchronous exceptions, see the privileged spec for cause codes
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Exception {
+    InstrAddrMisaligned,
+    InstrAccessFault,
+    IllegalInstr,
+    Breakpoint,
+    LoadAccessFault,
+    StoreAccessFault,
+    EcallM,
+}
+
+impl Exception {
+    // value written to mcause
+    pub fn cause(self) -> u32 {
+        match self {
+            Exception::InstrAddrMisaligned => 0,
+            Exception::InstrAccessFault => 1,
+            Exception::IllegalInstr => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAccessFault => 5,
+            Exception::StoreAccessFault => 7,
+            Exception::EcallM => 11,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Exception::InstrAddrMisaligned => "instruction address misaligned",
+            Exception::InstrAccessFault => "instruction access fault",
+            Exception::IllegalInstr => "illegal instruction",
+            Exception::Breakpoint => "breakpoint",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreAccessFault => "store access fault",
+            Exception::EcallM => "environment call",
+        };
+        write!(f, "{} (mcause {})", name, self.cause())
+    }
+}
+
+// asynchronous interrupts, by the bit they set in mip and mie
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interrupt {
+    Timer,
+    External,
+}
+
+impl Interrupt {
+    // the exception code, without the interrupt bit
+    pub fn code(self) -> u32 {
+        match self {
+            Interrupt::Timer => 7,
+            Interrupt::External => 11,
+        }
+    }
+
+    // value written to mcause
+    pub fn cause(self) -> u32 {
+        0b1 << 31 | self.code()
+    }
+
+    // the pending and enable bit in mip and mie
+    pub fn bit(self) -> u32 {
+        0b1 << self.code()
+    }
+}
+
+// what to do when an exception is raised but mtvec is zero,
+// i.e. the program never installed a trap handler
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TrapFallback {
+    // stop the MCU and report the exception
+    Halt,
+    // log the exception and continue with the next instruction
+    Skip,
+}
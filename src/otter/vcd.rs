@@ -0,0 +1,268 @@
+// Value change dumps of architectural state for waveform viewers such as
+// GTKWave. Each retired instruction is one time unit: its PC, encoding and
+// memory bus activity, with the registers and IO as it left them.
+
+use super::devices::rf::RF_SIZE;
+use super::rv32i::decode::reg_name;
+use super::trace::Access;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// what the MCU looked like after one instruction
+pub struct State {
+    pub pc: u32,
+    pub ir: u32,
+    pub rf: Vec<u32>,
+    pub leds: u32,
+    pub sseg: u32,
+    pub switches: u32,
+    pub access: Option<Access>,
+}
+
+struct Signal {
+    name: String,
+    width: u32,
+}
+
+pub struct Vcd {
+    out: Box<dyn Write + Send>,
+    bus: bool,
+    signals: Vec<Signal>,
+    // values as last written, or None before the first sample
+    last: Option<Vec<u32>>,
+    time: u64,
+}
+
+// short identifier for the signal at 'index', from the printable characters
+fn ident(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+fn value(v: u32, width: u32, id: &str) -> String {
+    if width == 1 {
+        format!("{}{}", v & 1, id)
+    } else {
+        format!("b{:b} {}", v, id)
+    }
+}
+
+impl Vcd {
+    // 'bus' adds the memory address, data and enables
+    pub fn new(out: Box<dyn Write + Send>, bus: bool) -> io::Result<Vcd> {
+        let mut signals = vec![
+            Signal {
+                name: String::from("pc"),
+                width: 32,
+            },
+            Signal {
+                name: String::from("ir"),
+                width: 32,
+            },
+        ];
+        for i in 0..RF_SIZE {
+            signals.push(Signal {
+                name: format!("x{}_{}", i, reg_name(i)),
+                width: 32,
+            });
+        }
+        for name in ["leds", "sseg", "switches"].iter() {
+            signals.push(Signal {
+                name: String::from(*name),
+                width: 16,
+            });
+        }
+        if bus {
+            for (name, width) in [("addr", 32), ("data", 32), ("we", 1), ("re", 1)].iter() {
+                signals.push(Signal {
+                    name: format!("mem_{}", name),
+                    width: *width,
+                });
+            }
+        }
+
+        let mut vcd = Vcd {
+            out,
+            bus,
+            signals,
+            last: None,
+            time: 0,
+        };
+        vcd.header()?;
+        Ok(vcd)
+    }
+
+    // dump to a new file at 'path'
+    pub fn create(path: &str, bus: bool) -> io::Result<Vcd> {
+        let file = File::create(path)?;
+        Vcd::new(Box::new(BufWriter::new(file)), bus)
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "$version oemu $end")?;
+        writeln!(self.out, "$timescale 1 ns $end")?;
+        writeln!(self.out, "$scope module otter $end")?;
+        let rf = 2..2 + RF_SIZE as usize;
+        let io = rf.end..rf.end + 3;
+        for (i, s) in self.signals.iter().enumerate() {
+            if i == rf.start {
+                writeln!(self.out, "$scope module rf $end")?;
+            } else if i == io.start {
+                writeln!(self.out, "$scope module io $end")?;
+            } else if i == io.end {
+                writeln!(self.out, "$scope module bus $end")?;
+            }
+            writeln!(
+                self.out,
+                "$var wire {} {} {} $end",
+                s.width,
+                ident(i),
+                s.name
+            )?;
+            if i + 1 == rf.end || i + 1 == io.end || (self.bus && i + 1 == self.signals.len()) {
+                writeln!(self.out, "$upscope $end")?;
+            }
+        }
+        writeln!(self.out, "$upscope $end")?;
+        writeln!(self.out, "$enddefinitions $end")
+    }
+
+    // write the values that changed since the last sample at 'time'
+    pub fn sample(&mut self, time: u64, state: &State) -> io::Result<()> {
+        let mut values = vec![state.pc, state.ir];
+        values.extend_from_slice(&state.rf);
+        values.extend_from_slice(&[state.leds, state.sseg, state.switches]);
+        if self.bus {
+            // address and data hold their last values while the bus is idle
+            let last = self.last.as_ref();
+            let hold = |i: usize| last.map_or(0, |l| l[i]);
+            let at = values.len();
+            match state.access {
+                Some(a) => {
+                    values.extend_from_slice(&[a.addr, a.data, a.write as u32, !a.write as u32])
+                }
+                None => values.extend_from_slice(&[hold(at), hold(at + 1), 0, 0]),
+            }
+        }
+
+        writeln!(self.out, "#{}", time)?;
+        match &self.last {
+            None => {
+                writeln!(self.out, "$dumpvars")?;
+                for (i, v) in values.iter().enumerate() {
+                    writeln!(self.out, "{}", value(*v, self.signals[i].width, &ident(i)))?;
+                }
+                writeln!(self.out, "$end")?;
+            }
+            Some(last) => {
+                for (i, v) in values.iter().enumerate() {
+                    if *v != last[i] {
+                        writeln!(self.out, "{}", value(*v, self.signals[i].width, &ident(i)))?;
+                    }
+                }
+            }
+        }
+        self.last = Some(values);
+        self.time = time;
+        Ok(())
+    }
+}
+
+impl Drop for Vcd {
+    // end the last sample so viewers show it for a full time unit
+    fn drop(&mut self) {
+        if self.last.is_some() {
+            let _ = writeln!(self.out, "#{}", self.time + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // collects output for inspection
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn idents() {
+        assert_eq!("!", ident(0));
+        assert_eq!("~", ident(93));
+        assert_eq!("!!", ident(94));
+        assert_eq!("\"!", ident(95));
+        assert_eq!("!\"", ident(94 * 2));
+    }
+
+    #[test]
+    fn changes() {
+        let buf = Shared(Arc::new(Mutex::new(Vec::new())));
+        let mut vcd = Vcd::new(Box::new(buf.clone()), true).unwrap();
+        let mut state = State {
+            pc: 0,
+            ir: 0x00500293,
+            rf: vec![0; RF_SIZE as usize],
+            leds: 0,
+            sseg: 0,
+            switches: 0,
+            access: None,
+        };
+        state.rf[5] = 5;
+        vcd.sample(0, &state).unwrap();
+
+        // sw t0, 0(ra) to the LEDs
+        state.pc = 4;
+        state.ir = 0x0050a023;
+        state.leds = 5;
+        state.access = Some(Access {
+            addr: 0x11080000,
+            bytes: 4,
+            data: 5,
+            write: true,
+        });
+        vcd.sample(1, &state).unwrap();
+        drop(vcd);
+
+        let out = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let (header, body) = out.split_at(out.find("#0").unwrap());
+        assert!(header.contains("$var wire 32 ( x5_t0 $end"));
+        assert!(header.contains("$var wire 1 H mem_we $end"));
+        assert_eq!(
+            header.matches("$scope").count(),
+            header.matches("$upscope").count()
+        );
+
+        let lines: Vec<&str> = body.lines().collect();
+        // time, $dumpvars, every signal, $end
+        assert_eq!(2 + 41 + 1, lines.iter().position(|l| *l == "#1").unwrap());
+        assert!(lines.contains(&"b101 ("));
+        let changes = vec![
+            String::from("#1"),
+            String::from("b100 !"),
+            format!("b{:b} \"", 0x0050a023),
+            String::from("b101 C"),
+            format!("b{:b} F", 0x11080000),
+            String::from("b101 G"),
+            String::from("1H"),
+            String::from("#2"),
+        ];
+        assert_eq!(changes, lines[44..].to_vec());
+    }
+}
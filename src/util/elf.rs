@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::fs;
+
+// A minimal reader for statically linked, little-endian ELF32 executables:
+// just enough to load the program and look up its symbols.
+
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const CLASS_32: u8 = 1;
+const DATA_LE: u8 = 1;
+const MACHINE_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+const SHT_SYMTAB: u32 = 2;
+
+// bytes to place in memory at 'addr', zero-filled past the file contents
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+    // PF_R, PF_W and PF_X
+    pub flags: u32,
+}
+
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: HashMap<String, u32>,
+}
+
+impl Elf {
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    match bytes.get(offset..offset + 2) {
+        None => Err(format!("truncated at offset {:#X}", offset)),
+        Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+    }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    match bytes.get(offset..offset + 4) {
+        None => Err(format!("truncated at offset {:#X}", offset)),
+        Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+    }
+}
+
+fn slice(bytes: &[u8], offset: u32, size: u32) -> Result<&[u8], String> {
+    bytes
+        .get(offset as usize..(offset as usize).saturating_add(size as usize))
+        .ok_or(format!("truncated at offset {:#X}", offset))
+}
+
+// read a NUL-terminated string from a string table
+fn string_at(table: &[u8], offset: u32) -> String {
+    let bytes = table.get(offset as usize..).unwrap_or(&[]);
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Elf, String> {
+    if !is_elf(bytes) {
+        return Err(String::from("not an ELF file"));
+    }
+    if bytes.get(4) != Some(&CLASS_32) || bytes.get(5) != Some(&DATA_LE) {
+        return Err(String::from("not a little-endian 32-bit ELF file"));
+    }
+    if u16_at(bytes, 18)? != MACHINE_RISCV {
+        return Err(String::from("not a RISC-V ELF file"));
+    }
+
+    let entry = u32_at(bytes, 24)?;
+    let phoff = u32_at(bytes, 28)? as usize;
+    let shoff = u32_at(bytes, 32)? as usize;
+    let phentsize = u16_at(bytes, 42)? as usize;
+    let phnum = u16_at(bytes, 44)? as usize;
+    let shentsize = u16_at(bytes, 46)? as usize;
+    let shnum = u16_at(bytes, 48)? as usize;
+
+    // loadable segments, by physical address
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if u32_at(bytes, ph)? != PT_LOAD {
+            continue;
+        }
+        let offset = u32_at(bytes, ph + 4)?;
+        let addr = u32_at(bytes, ph + 12)?;
+        let filesz = u32_at(bytes, ph + 16)?;
+        let memsz = u32_at(bytes, ph + 20)?;
+        let flags = u32_at(bytes, ph + 24)?;
+        if memsz == 0 {
+            continue;
+        }
+        let mut data = slice(bytes, offset, filesz)?.to_vec();
+        data.resize(memsz.max(filesz) as usize, 0);
+        segments.push(Segment { addr, data, flags });
+    }
+
+    // symbols, if the file was not stripped
+    let mut symbols = HashMap::new();
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        if u32_at(bytes, sh + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let table = slice(bytes, u32_at(bytes, sh + 16)?, u32_at(bytes, sh + 20)?)?;
+        let strtab_sh = shoff + u32_at(bytes, sh + 24)? as usize * shentsize;
+        let strtab = slice(
+            bytes,
+            u32_at(bytes, strtab_sh + 16)?,
+            u32_at(bytes, strtab_sh + 20)?,
+        )?;
+        let entsize = (u32_at(bytes, sh + 36)? as usize).max(16);
+        for sym in table.chunks_exact(entsize) {
+            let name = string_at(strtab, u32_at(sym, 0)?);
+            if !name.is_empty() {
+                symbols.insert(name, u32_at(sym, 4)?);
+            }
+        }
+    }
+
+    Ok(Elf {
+        entry,
+        segments,
+        symbols,
+    })
+}
+
+pub fn read(path: &str) -> Result<Elf, String> {
+    let bytes = fs::read(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    parse(&bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an executable with one segment and a symbol table
+    fn build(entry: u32, addr: u32, data: &[u8], symbols: &[(&str, u32)]) -> Vec<u8> {
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 16];
+        for (name, value) in symbols.iter() {
+            symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+            symtab.extend_from_slice(&value.to_le_bytes());
+            symtab.extend_from_slice(&[0; 8]);
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let data_off = 52 + 32;
+        let symtab_off = data_off + data.len();
+        let strtab_off = symtab_off + symtab.len();
+        let shoff = strtab_off + strtab.len();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[CLASS_32, DATA_LE, 1]);
+        bytes.resize(16, 0);
+        for half in [2, MACHINE_RISCV].iter() {
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1, entry, 52, shoff as u32, 0].iter() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [52u16, 32, 1, 40, 3, 0].iter() {
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+
+        let (size, flags) = (data.len() as u32, PF_R | PF_X);
+        for word in [PT_LOAD, data_off as u32, addr, addr, size, size, flags, 4].iter() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&symtab);
+        bytes.extend_from_slice(&strtab);
+
+        // null, .symtab linked to .strtab, .strtab
+        bytes.extend_from_slice(&[0; 40]);
+        let sections = [
+            (SHT_SYMTAB, symtab_off, symtab.len(), 2, 16),
+            (3, strtab_off, strtab.len(), 0, 0),
+        ];
+        for (kind, offset, size, link, entsize) in sections.iter() {
+            let words = [
+                0,
+                *kind,
+                0,
+                0,
+                *offset as u32,
+                *size as u32,
+                *link,
+                0,
+                4,
+                *entsize,
+            ];
+            for word in words.iter() {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn segments_and_symbols() {
+        let data = [0x13, 0x00, 0x00, 0x00];
+        let bytes = build(0x80000000, 0x80000000, &data, &[("tohost", 0x80001000)]);
+        let elf = parse(&bytes).unwrap();
+        assert_eq!(0x80000000, elf.entry);
+        assert_eq!(1, elf.segments.len());
+        assert_eq!(0x80000000, elf.segments[0].addr);
+        assert_eq!(data.to_vec(), elf.segments[0].data);
+        assert_eq!(PF_R | PF_X, elf.segments[0].flags);
+        assert_eq!(Some(0x80001000), elf.symbol("tohost"));
+        assert_eq!(None, elf.symbol("fromhost"));
+
+        assert!(parse(&bytes[..60]).is_err());
+    }
+
+    #[test]
+    fn not_elf() {
+        assert!(parse(b"\x37\x08\x00\x11").is_err());
+        assert!(parse(&MAGIC).is_err());
+    }
+}
@@ -1,2 +1,3 @@
+pub mod elf;
 pub mod io;
 pub mod parse;